@@ -0,0 +1,67 @@
+use std::env;
+use std::fs;
+use std::path;
+use std::process as proc;
+
+fn main() {
+    println!(
+        "cargo:rustc-env=RIPPIFY_GIT_COMMIT={}",
+        git_commit().unwrap_or("unknown".to_owned())
+    );
+    println!(
+        "cargo:rustc-env=RIPPIFY_LIBRESPOT_VERSION={}",
+        librespot_version().unwrap_or("unknown".to_owned())
+    );
+
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+}
+
+fn git_commit() -> Option<String> {
+    let output = proc::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    let commit = String::from_utf8(output.stdout).ok()?.trim().to_owned();
+
+    let dirty = proc::Command::new("git")
+        .args(["status", "--porcelain", "--untracked-files=no"])
+        .output()
+        .map_or(false, |output| !output.stdout.is_empty());
+
+    Some(if dirty { format!("{}-dirty", commit) } else { commit })
+}
+
+// Cargo.lock is the only place that knows which librespot revision was actually resolved
+fn librespot_version() -> Option<String> {
+    let lock_path = path::Path::new(&env::var("CARGO_MANIFEST_DIR").ok()?).join("Cargo.lock");
+    let lock = fs::read_to_string(lock_path).ok()?;
+
+    let package = lock
+        .split("[[package]]")
+        .find(|package| package.contains("name = \"librespot-core\""))?;
+
+    let field = |name: &str| {
+        package
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("{} = \"", name)))
+            .map(|value| value.trim_end_matches('"').to_owned())
+    };
+
+    let version = field("version")?;
+
+    match field("source").and_then(|source| {
+        source
+            .rsplit_once('#')
+            .and_then(|(_, rev)| rev.get(..7))
+            .map(str::to_owned)
+    }) {
+        Some(rev) => Some(format!("{} ({})", version, rev)),
+        None => Some(version),
+    }
+}
@@ -0,0 +1,7622 @@
+//! Everything the rippify binary does, for embedding it into other programs. Build a [`UserParams`] and hand it to
+//! [`Downloader::run`], which downloads the inputs exactly like the command line would.
+
+use async_recursion::async_recursion;
+use base64::Engine;
+use colored::Colorize;
+use lewton::header as lhr;
+use librespot_audio as lsa;
+use librespot_core as lsc;
+use librespot_core::authentication as lsc_auth;
+use librespot_metadata as lsm;
+use librespot_metadata::audio as lsm_audio;
+use lsm::Metadata;
+use sha2::Digest;
+use std::cell;
+use std::collections as coll;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::IsTerminal;
+use std::io::Read;
+use std::io::Seek;
+use std::io::Write;
+use std::panic;
+use std::path;
+use std::process as proc;
+use std::sync;
+use std::sync::atomic;
+use std::time as stdtime;
+use tokio::task;
+use tokio::time;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+use unicode_normalization::UnicodeNormalization;
+
+static AUDIO_KEY_TIMEOUT: stdtime::Duration = stdtime::Duration::from_secs(10);
+
+// Delay before the first login retry, doubled after every further one
+static CONNECT_BACKOFF: stdtime::Duration = stdtime::Duration::from_secs(2);
+
+// Vorbis comments holding the base62 track and album ids, used to find the track again from an existing file
+static TAG_TRACK_ID: &str = "SPOTIFY_TRACK_ID";
+static TAG_ALBUM_ID: &str = "SPOTIFY_ALBUM_ID";
+
+// Vorbis comment holding the base62 id of a podcast episode, written instead of the track and album ids
+static TAG_EPISODE_ID: &str = "SPOTIFY_EPISODE_ID";
+
+// Vorbis comment holding the bitrate in kbps the track was downloaded at, used to find files worth upgrading
+static TAG_BITRATE: &str = "SPOTIFY_BITRATE";
+
+// Quiet is used when the audio stream itself is written to stdout, so nothing else can end up in it
+const VERBOSITY_QUIET: u8 = 0;
+const VERBOSITY_SUMMARY: u8 = 1;
+const VERBOSITY_NORMAL: u8 = 2;
+const VERBOSITY_DEBUG: u8 = 3;
+// Also shows the log of librespot itself on stderr
+const VERBOSITY_TRACE: u8 = 4;
+
+static VERBOSITY: atomic::AtomicU8 = atomic::AtomicU8::new(VERBOSITY_NORMAL);
+
+// Whether messages are mirrored to a --log-file, which saves stripping their colors when there's none
+static LOG_FILE: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+static ANSI_ESCAPE: sync::OnceLock<regex::Regex> = sync::OnceLock::new();
+
+tokio::task_local! {
+    // Output of a track processed concurrently with others, printed in one go once the track is done so the output
+    // of different tracks doesn't interleave. Entries are marked with whether they go to stderr
+    static TRACK_LOG: cell::RefCell<Vec<(bool, String)>>;
+}
+
+// Messages go to the log file as soon as they're emitted, only the console output of a track is held back
+fn emit(level: Option<tracing::Level>, shown: bool, to_stderr: bool, text: String) {
+    if let Some(level) = level {
+        record(level, &text);
+    }
+
+    if !shown {
+        return;
+    }
+
+    let mut text = Some(text);
+
+    // Outside of a track task there's no log to collect into, so it's written right away
+    let _ = TRACK_LOG.try_with(|log| log.borrow_mut().push((to_stderr, text.take().unwrap())));
+
+    if let Some(text) = text {
+        write_log(vec![(to_stderr, text)]);
+    }
+}
+
+// The file layer adds the timestamp and level, so lines are written without their colors and indentation
+fn record(level: tracing::Level, text: &str) {
+    if !LOG_FILE.load(atomic::Ordering::Relaxed) {
+        return;
+    }
+
+    let ansi_escape = ANSI_ESCAPE.get_or_init(|| regex::Regex::new("\x1b\\[[0-9;]*[A-Za-z]").unwrap());
+    let plain = ansi_escape.replace_all(text, "");
+
+    for line in plain.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if level == tracing::Level::WARN {
+            tracing::warn!("{}", line);
+        } else if level == tracing::Level::DEBUG {
+            tracing::debug!("{}", line);
+        } else {
+            tracing::info!("{}", line);
+        }
+    }
+}
+
+fn write_log(log: Vec<(bool, String)>) {
+    let write = || {
+        let mut stdout = io::stdout().lock();
+        let mut stderr = io::stderr().lock();
+
+        for (to_stderr, text) in log {
+            if to_stderr {
+                stderr.write_all(text.as_bytes()).ok();
+            } else {
+                stdout.write_all(text.as_bytes()).ok();
+            }
+        }
+
+        stdout.flush().ok();
+    };
+
+    // Lines written while progress bars are drawn would end up in between them, so the bars are hidden meanwhile
+    match PROGRESS.lock().unwrap().as_ref() {
+        Some(progress) => progress.bars.suspend(write),
+        None => write(),
+    }
+}
+
+// Gives a track processed concurrently with others a log of its own, returned with its result to be written at once
+async fn collect_track_log<R>(work: impl std::future::Future<Output = R>) -> (R, Vec<(bool, String)>) {
+    TRACK_LOG
+        .scope(cell::RefCell::default(), async move {
+            let result = work.await;
+            (result, TRACK_LOG.with(|log| log.take()))
+        })
+        .await
+}
+
+// Runs blocking work, like writing files or waiting on the audio file, on the blocking pool. Unlike block_in_place
+// that works on any runtime an embedding program might use. What the work prints goes to the log of its track
+async fn run_blocking<R: Send + 'static>(work: impl FnOnce() -> R + Send + 'static) -> R {
+    let in_track = TRACK_LOG.try_with(|_| ()).is_ok();
+
+    let (result, log) = task::spawn_blocking(move || {
+        if in_track {
+            TRACK_LOG.sync_scope(cell::RefCell::default(), || {
+                let result = work();
+                (result, TRACK_LOG.with(|log| log.take()))
+            })
+        } else {
+            (work(), Vec::new())
+        }
+    })
+    .await
+    .unwrap_or_else(|err| panic::resume_unwind(err.into_panic()));
+
+    let _ = TRACK_LOG.try_with(|current| current.borrow_mut().extend(log));
+
+    result
+}
+
+// Bars drawn with --progress, one for the whole queue and one for every download in flight
+struct ProgressBars {
+    bars: indicatif::MultiProgress,
+    overall: indicatif::ProgressBar,
+}
+
+static PROGRESS: sync::Mutex<Option<ProgressBars>> = sync::Mutex::new(None);
+
+impl ProgressBars {
+    fn start() {
+        let bars = indicatif::MultiProgress::new();
+        let overall = bars.add(
+            indicatif::ProgressBar::new(0)
+                .with_style(
+                    indicatif::ProgressStyle::with_template(" {prefix} [{bar:40}] {pos}/{len} tracks")
+                        .unwrap()
+                        .progress_chars("#>-"),
+                )
+                .with_prefix("->".yellow().bold().to_string()),
+        );
+
+        *PROGRESS.lock().unwrap() = Some(ProgressBars { bars, overall });
+    }
+
+    fn finish() {
+        if let Some(progress) = PROGRESS.lock().unwrap().take() {
+            progress.overall.finish_and_clear();
+        }
+    }
+}
+
+// Bar of a single download, removed again once the download is done or given up on
+struct TransferBar(Option<indicatif::ProgressBar>);
+
+impl TransferBar {
+    fn new(len: u64) -> Self {
+        TransferBar(PROGRESS.lock().unwrap().as_ref().map(|progress| {
+            progress.bars.add(
+                indicatif::ProgressBar::new(len).with_style(
+                    indicatif::ProgressStyle::with_template("    [{bar:40}] {bytes}/{total_bytes} {bytes_per_sec}")
+                        .unwrap()
+                        .progress_chars("#>-"),
+                ),
+            )
+        }))
+    }
+
+    fn set_position(&self, position: u64) {
+        if let Some(bar) = &self.0 {
+            bar.set_position(position);
+        }
+    }
+}
+
+impl Drop for TransferBar {
+    fn drop(&mut self) {
+        if let Some(bar) = &self.0 {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+// Details of how a track is downloaded, only shown with -v
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        emit(
+            Some(tracing::Level::DEBUG),
+            VERBOSITY.load(atomic::Ordering::Relaxed) >= VERBOSITY_DEBUG,
+            false,
+            format!("{}\n", format_args!($($arg)*)),
+        )
+    };
+}
+
+// Per track and per file progress
+macro_rules! info {
+    ($($arg:tt)*) => {
+        emit(
+            Some(tracing::Level::INFO),
+            VERBOSITY.load(atomic::Ordering::Relaxed) >= VERBOSITY_NORMAL,
+            false,
+            format!("{}\n", format_args!($($arg)*)),
+        )
+    };
+}
+
+// Start of a line finished by a later info!, which is all the log file gets of it
+macro_rules! info_part {
+    ($($arg:tt)*) => {
+        emit(None, VERBOSITY.load(atomic::Ordering::Relaxed) >= VERBOSITY_NORMAL, false, format!($($arg)*))
+    };
+}
+
+// Headers and final counts, still shown with --summary-only
+macro_rules! summary {
+    ($($arg:tt)*) => {{
+        let text = format!("{}\n", format_args!($($arg)*));
+        record(tracing::Level::INFO, &text);
+
+        if VERBOSITY.load(atomic::Ordering::Relaxed) >= VERBOSITY_SUMMARY {
+            print!("{}", text);
+        }
+    }};
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        match VERBOSITY.load(atomic::Ordering::Relaxed) {
+            VERBOSITY_QUIET => emit(Some(tracing::Level::WARN), true, true, format!("{}\n", format_args!($($arg)*))),
+            // Clear the progress line so the warning doesn't get appended to it
+            VERBOSITY_SUMMARY => emit(
+                Some(tracing::Level::WARN),
+                true,
+                false,
+                format!("\r\x1b[2K{}\n", format_args!($($arg)*)),
+            ),
+            _ => emit(Some(tracing::Level::WARN), true, false, format!("{}\n", format_args!($($arg)*))),
+        }
+    };
+}
+
+// The console keeps the colored output of the macros above, the subscriber only writes the --log-file and the log of
+// librespot itself with -vv. Embedding programs that already set up their own subscriber keep it
+fn init_logging(opts: &UserParams, verbosity: u8) -> Result<(), Error> {
+    let file_layer = opts.log_file.as_ref().map(|path| -> Result<_, Error> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| {
+                fatal(format!(
+                    "cannot open log file \"{}\": {}",
+                    path.display(),
+                    err.to_string().to_lowercase()
+                ))
+            })?;
+
+        Ok(tracing_subscriber::fmt::layer()
+            .with_writer(sync::Mutex::new(file))
+            .with_ansi(false)
+            .with_target(false)
+            .with_filter(
+                tracing_subscriber::filter::Targets::new()
+                    .with_target("rippify", tracing::Level::DEBUG)
+                    .with_target("librespot", tracing::Level::INFO),
+            ))
+    });
+
+    let file_layer = file_layer.transpose()?;
+
+    let console_layer = (verbosity >= VERBOSITY_TRACE).then(|| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(io::stderr)
+            .with_filter(tracing_subscriber::filter::Targets::new().with_target("librespot", tracing::Level::DEBUG))
+    });
+
+    if file_layer.is_some() {
+        LOG_FILE.store(true, atomic::Ordering::Relaxed);
+    }
+
+    tracing_subscriber::registry()
+        .with(file_layer)
+        .with(console_layer)
+        .try_init()
+        .ok();
+
+    Ok(())
+}
+
+// Why a run ended before it was done. By the time it's returned the error has been printed like everything else the
+// run reports, so embedding programs only have to decide what to do about it
+#[derive(Debug)]
+pub enum Error {
+    // Something the run can't do without, like the credentials, the output directory or enough free space
+    Failed(String),
+    // Ctrl-C or a request to terminate
+    Interrupted,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Failed(message) => write!(f, "{}", message),
+            Error::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Prints an error that ends the run, handing it back to be returned from the run
+fn fatal(message: String) -> Error {
+    warn!("\n{}: {}", "error".red().bold(), message);
+    Error::Failed(message)
+}
+
+// Downloads everything the options ask for, the same way the rippify binary does. Progress and warnings are printed
+// like on the command line, with the verbosity picked from the options
+pub struct Downloader {
+    opts: UserParams,
+}
+
+// A resolved track waiting to be downloaded, with the name of the input it was found through for {source} and its
+// position there for {position}
+#[derive(Clone)]
+pub struct TrackJob {
+    pub id: lsc::SpotifyId,
+    pub source: String,
+    pub position: usize,
+}
+
+impl Downloader {
+    pub fn new(opts: UserParams) -> Self {
+        Downloader { opts }
+    }
+
+    // Logs in, resolves the inputs and processes every track, returning what happened to each of them. Runs that
+    // don't download anything, like rippify list or rippify meta retag, return no reports. Fatal errors, like rejected
+    // credentials or a full disk, end the run with an error instead
+    pub async fn run(self) -> Result<Vec<TrackReport>, Error> {
+        let started = time::Instant::now();
+        let mut opts = self.opts;
+
+        FAILED_FAST.store(false, atomic::Ordering::Relaxed);
+
+        // The metadata export is written to stdout as well, so only warnings are shown next to it
+        let verbosity = if opts.stdout || opts.export_metadata.is_some() || opts.quiet {
+            VERBOSITY_QUIET
+        } else if opts.summary_only {
+            VERBOSITY_SUMMARY
+        } else {
+            match opts.verbose {
+                0 => VERBOSITY_NORMAL,
+                1 => VERBOSITY_DEBUG,
+                _ => VERBOSITY_TRACE,
+            }
+        };
+
+        VERBOSITY.store(verbosity, atomic::Ordering::Relaxed);
+        init_logging(&opts, verbosity)?;
+
+        if opts.codec != Codec::Ogg && !ffmpeg_available() {
+            return Err(fatal(String::from(
+                "transcoding with --codec needs ffmpeg, which cannot be found, aborting...",
+            )));
+        }
+
+        if opts.login {
+            opts.credentials = Some(oauth_credentials().await?);
+        }
+
+        let mut session = connect_session(&opts).await?;
+        summary!(
+            "{} Logged in as: {}",
+            "=>".green().bold(),
+            session.username().bright_blue()
+        );
+
+        if opts.login {
+            summary!(
+                "{} Cached credentials in \"{}\"",
+                "=>".green().bold(),
+                opts.cache_dir.display()
+            );
+            return Ok(Vec::new());
+        }
+
+        if let Some(dir) = &opts.retag_dir {
+            retag_dir(&session, dir, &opts.tags).await?;
+            return Ok(Vec::new());
+        }
+
+        let Some(every) = opts.watch_every else {
+            return process_inputs(session, opts, verbosity, started).await;
+        };
+
+        // Every cycle starts from the sync state the last one wrote, so only tracks added since then are downloaded.
+        // The session is reused between cycles and only connected again once spotify dropped it
+        loop {
+            if session.is_invalid() {
+                debug!(
+                    "\n{} Session was closed by spotify, logging in again",
+                    "=>".green().bold()
+                );
+                session = connect_session(&opts).await?;
+            }
+
+            let mut cycle_opts = opts.clone();
+
+            if let Some(path) = &opts.sync_state {
+                match read_sync_state(path) {
+                    Ok(state) => cycle_opts.resolve.sync_state = Some(state),
+                    Err(err) => warn!(
+                        "\n{}: {}, using the state the watch started with...",
+                        "warning".yellow().bold(),
+                        err
+                    ),
+                }
+            }
+
+            process_inputs(session.clone(), cycle_opts, verbosity, time::Instant::now()).await?;
+
+            summary!(
+                "\n{} Checking the playlists again in {} seconds",
+                "=>".green().bold(),
+                every.as_secs()
+            );
+
+            tokio::select! {
+                _ = time::sleep(every) => {}
+                _ = tokio::signal::ctrl_c() => return Err(Error::Interrupted),
+            }
+        }
+    }
+}
+
+// Resolves the inputs and processes every track, once per run or once per cycle of rippify watch
+async fn process_inputs(
+    session: lsc::Session,
+    opts: UserParams,
+    verbosity: u8,
+    started: time::Instant,
+) -> Result<Vec<TrackReport>, Error> {
+    let max_bitrate = account_max_bitrate(&session).await;
+
+    if let Some(bitrate) = max_bitrate {
+        summary!(
+            "{} {}: free account, audio is downloaded at up to {}kbps",
+            "=>".green().bold(),
+            "note".bright_blue().bold(),
+            bitrate
+        );
+    }
+
+    // An explicit preference list replaces the quality tiers, the account limit still applies to it
+    let selection = if opts.prefer.is_empty() {
+        AudioSelection {
+            bitrate: opts.quality.bitrate(),
+            limit: max_bitrate.unwrap_or(u32::MAX),
+            strict: opts.strict_quality,
+            formats: AUDIO_FORMATS.to_vec(),
+        }
+    } else {
+        AudioSelection {
+            bitrate: u32::MAX,
+            limit: max_bitrate.unwrap_or(u32::MAX),
+            strict: false,
+            formats: opts.prefer.clone(),
+        }
+    };
+
+    // The strict tier is never lowered to the account limit, so none of the tracks can be downloaded at it
+    if selection.strict && selection.bitrate > selection.limit {
+        warn!(
+            "{} {}: free accounts can't download {}kbps audio, every track will be unavailable at --strict-quality",
+            "=>".green().bold(),
+            "warning".yellow().bold(),
+            selection.bitrate
+        );
+    }
+
+    info!("\n{} Input resources:", "=>".green().bold());
+
+    let mut input_lines = Vec::new();
+
+    for line in opts.input.iter().flat_map(|line| expand_input_line(line)) {
+        match search_query(&line) {
+            Some(query) => input_lines.extend(search_input(&session, query, &opts).await?),
+            None => input_lines.push(line),
+        }
+    }
+
+    let input_resources: Vec<_> = input_lines
+        .iter()
+        .map(|line| get_resource_from_line(line))
+        .filter(|x| {
+            if let Err(line) = x {
+                warn!(
+                    "{}: unrecognized input: {}, skipping...",
+                    " -> warning".yellow().bold(),
+                    line.bold()
+                );
+                let _ = fail_fast(&opts);
+                false
+            } else {
+                let res = x.as_ref().unwrap();
+                info!(" {} {}: {}", "->".yellow().bold(), res.kind, res.name());
+                true
+            }
+        })
+        .map(|x| x.unwrap())
+        .chain(opts.radio.iter().filter_map(|line| match get_radio_seed(line) {
+            Some(res) => {
+                info!(
+                    " {} {}: {}",
+                    "->".yellow().bold(),
+                    res.kind,
+                    &res.id.to_base62().unwrap()
+                );
+                Some(res)
+            }
+            None => {
+                warn!(
+                    "{}: radio seeds must be tracks or artists: {}, skipping...",
+                    " -> warning".yellow().bold(),
+                    line.bold()
+                );
+                let _ = fail_fast(&opts);
+                None
+            }
+        }))
+        .collect();
+
+    failed_fast()?;
+
+    let albums = AlbumCache::default();
+
+    // Streamed runs resolve their inputs alongside the downloads, once the track context exists
+    let resolution = if opts.stream {
+        None
+    } else {
+        Some(resolve_inputs(&session, &albums, &input_resources, &opts, None).await?)
+    };
+
+    if let Some(resolution) = &resolution {
+        let input_tracks = &resolution.tracks;
+
+        if opts.list_inputs {
+            summary!("\n{} Resolved inputs:", "=>".green().bold());
+
+            for (input, num_tracks) in &resolution.counts {
+                summary!(" {} {} -> {} tracks", "->".yellow().bold(), input, num_tracks);
+            }
+
+            summary!(
+                " {} {} total unique tracks",
+                "->".yellow().bold(),
+                input_tracks.len().to_string().bold()
+            );
+            return Ok(Vec::new());
+        }
+
+        if input_tracks.is_empty() {
+            match opts.watch_every {
+                Some(_) => summary!("\n{} No new tracks", "=>".green().bold()),
+                None => warn!("\n{}: didn't get any tracks, aborting...", "error".red().bold()),
+            }
+            return Ok(Vec::new());
+        }
+
+        if let Some(path) = &opts.export_beets {
+            export_beets(&session, &albums, input_tracks, &selection, &opts, path).await?;
+            return Ok(Vec::new());
+        }
+
+        if let Some(format) = &opts.export_metadata {
+            export_metadata(&session, &albums, input_tracks, &selection, &opts, format).await?;
+            return Ok(Vec::new());
+        }
+
+        if opts.stdout && input_tracks.len() != 1 {
+            return Err(fatal(format!(
+                "writing to stdout requires exactly one track, but {} were resolved",
+                input_tracks.len()
+            )));
+        }
+
+        if let Some(num_tracks) = opts.benchmark {
+            let track_ids: Vec<_> = input_tracks.keys().take(num_tracks).copied().collect();
+            benchmark(&session, &albums, &track_ids, &selection).await;
+            return Ok(Vec::new());
+        }
+    }
+
+    // The first sync of a directory has to create it before it can be indexed
+    if let Some(dir) = &opts.sync_dir {
+        if let Err(err) = fs::create_dir_all(dir) {
+            return Err(fatal(format!(
+                "cannot create directory \"{}\": {}",
+                dir.display(),
+                err.to_string().to_lowercase()
+            )));
+        }
+    }
+
+    let library = match &opts.skip_duplicates_on_disk {
+        Some(dir) => match index_library(dir) {
+            Ok(library) => {
+                summary!(
+                    "\n{} Indexed {} tracks in \"{}\"",
+                    "=>".green().bold(),
+                    library.len().to_string().bold(),
+                    dir.display()
+                );
+                library
+            }
+            Err(err) => {
+                return Err(fatal(format!(
+                    "cannot read directory \"{}\": {}",
+                    dir.display(),
+                    err.to_string().to_lowercase()
+                )))
+            }
+        },
+        None => coll::HashMap::new(),
+    };
+
+    let archive = opts
+        .download_archive
+        .as_ref()
+        .map(|path| {
+            DownloadArchive::open(path).map_err(|err| {
+                fatal(format!(
+                    "cannot open download archive \"{}\": {}",
+                    path.display(),
+                    err.to_string().to_lowercase()
+                ))
+            })
+        })
+        .transpose()?;
+
+    let db = opts
+        .db
+        .as_ref()
+        .map(|path| {
+            LibraryDb::open(path).map(sync::Arc::new).map_err(|err| {
+                fatal(format!(
+                    "cannot open library database \"{}\": {}",
+                    path.display(),
+                    err.to_string().to_lowercase()
+                ))
+            })
+        })
+        .transpose()?;
+
+    let ctx = sync::Arc::new(TrackContext {
+        session,
+        albums,
+        library,
+        archive,
+        db,
+        rate_limit: opts.limit_rate.map(|rate| sync::Arc::new(RateLimit::new(rate))),
+        recordings: sync::Mutex::default(),
+        cover_folders: sync::Mutex::default(),
+        features: FeaturesCache::default(),
+        selection,
+        jobs: atomic::AtomicUsize::new(opts.jobs),
+        retiring_slots: atomic::AtomicUsize::new(0),
+        track_delay: sync::Mutex::new(stdtime::Duration::ZERO),
+        fetch_slots: tokio::sync::Semaphore::new(opts.jobs),
+        write_slots: tokio::sync::Semaphore::new(opts.write_jobs),
+        opts,
+    });
+
+    // Checked once the archive and the library are open, so tracks that are skipped anyway don't count
+    if let Some(resolution) = &resolution {
+        if !ctx.opts.stdout && !ctx.opts.skip_space_check {
+            check_disk_space(&ctx, resolution.tracks.values()).await?;
+        }
+
+        summary!(
+            "\n{} Parsed {} tracks:",
+            "=>".green().bold(),
+            resolution.tracks.len().to_string().bold()
+        );
+    }
+
+    // Both kinds of runs hand their tracks to the download loop through the same channel, streamed ones just
+    // keep sending while the first tracks are already downloading
+    let (track_tx, mut track_rx) = tokio::sync::mpsc::unbounded_channel::<TrackJob>();
+
+    // Features are requested for many tracks at once, so most tracks find theirs already cached later on
+    if let (true, Some(resolution)) = (ctx.opts.tags.audio_features, &resolution) {
+        let track_ids: Vec<_> = resolution.tracks.keys().copied().collect();
+        ctx.features.prefetch(&ctx.session, &track_ids).await;
+    }
+
+    let resolver = match resolution {
+        Some(resolution) => {
+            for job in resolution.tracks.values() {
+                track_tx.send(job.clone()).unwrap();
+            }
+
+            drop(track_tx);
+            task::spawn(async move { Ok(resolution) })
+        }
+        None => {
+            summary!("\n{} Downloading tracks as they're resolved:", "=>".green().bold());
+
+            let ctx = ctx.clone();
+            let sink = TrackSink::new(track_tx);
+
+            task::spawn(async move {
+                let inputs = input_resources;
+                resolve_inputs(&ctx.session, &ctx.albums, &inputs, &ctx.opts, Some(&sink)).await
+            })
+        }
+    };
+
+    let mut total: usize;
+    let mut reports = Vec::<TrackReport>::new();
+    let mut recent_failures = coll::VecDeque::<bool>::with_capacity(FAILURE_WINDOW);
+
+    // The bars take the place of the progress line of --summary-only
+    let show_progress = verbosity == VERBOSITY_SUMMARY || ctx.opts.progress;
+
+    if ctx.opts.progress {
+        ProgressBars::start();
+    }
+
+    // Tracks in flight are dropped on an interrupt, which only ever cuts a write short before its rename
+    let interrupt = wait_for_stop(ctx.opts.total_timeout.map(|limit| started + limit));
+    tokio::pin!(interrupt);
+    let mut stopped: Option<Stop> = None;
+
+    if ctx.opts.jobs == 1 {
+        // Without concurrent tracks there's nothing to interleave with, so the output is printed as it happens
+        loop {
+            let job = tokio::select! {
+                job = track_rx.recv() => job,
+                stop = &mut interrupt => {
+                    stopped = Some(stop);
+                    break;
+                }
+            };
+
+            let Some(job) = job else {
+                break;
+            };
+
+            // Streamed runs only know about the tracks resolved so far
+            total = reports.len() + 1 + track_rx.len();
+
+            if show_progress {
+                print_progress(reports.len(), total);
+            }
+
+            let report = tokio::select! {
+                report = process_track(&ctx, &job) => report,
+                stop = &mut interrupt => {
+                    stopped = Some(stop);
+                    break;
+                }
+            };
+
+            adapt_concurrency(&ctx, &mut recent_failures, &report.status);
+            reports.push(report);
+
+            if show_progress {
+                print_progress(reports.len(), reports.len() + track_rx.len());
+            }
+        }
+
+        total = reports.len();
+    } else {
+        let (spawned, stop) = join_tasks(
+            &mut track_rx,
+            interrupt.as_mut(),
+            |job| {
+                let ctx = ctx.clone();
+                collect_track_log(async move { process_track(&ctx, &job).await })
+            },
+            |joined, spawned| {
+                // A panicking task already had its message printed by the panic hook
+                let (report, log) = joined.unwrap_or_else(|_| {
+                    (
+                        TrackReport::new(String::new()).failed("track task panicked"),
+                        Vec::new(),
+                    )
+                });
+
+                write_log(log);
+
+                adapt_concurrency(&ctx, &mut recent_failures, &report.status);
+                reports.push(report);
+
+                if show_progress {
+                    print_progress(reports.len(), spawned);
+                }
+            },
+        )
+        .await;
+
+        stopped = stop;
+        total = match stopped {
+            Some(_) => reports.len(),
+            None => spawned,
+        };
+    }
+
+    // Streamed runs would otherwise keep resolving inputs nobody is going to download anymore
+    if stopped.is_some() {
+        resolver.abort();
+    }
+
+    let resolution = match resolver.await {
+        Ok(resolution) => resolution,
+        Err(err) if err.is_cancelled() => Ok(Resolution::default()),
+        Err(err) => panic::resume_unwind(err.into_panic()),
+    };
+
+    ProgressBars::finish();
+
+    // The failure was reported as it happened, none of the remaining tracks or the summary follow it
+    if stopped == Some(Stop::FailedFast) {
+        return Err(Error::Failed(String::from(FAIL_FAST_MESSAGE)));
+    }
+
+    let resolution = resolution?;
+
+    match stopped {
+        Some(Stop::Interrupted) => warn!(
+            "\n{}: interrupted, skipping the remaining tracks...",
+            "warning".yellow().bold()
+        ),
+        Some(Stop::TimedOut) => warn!(
+            "\n{}: reached the total timeout, skipping the remaining tracks...",
+            "warning".yellow().bold()
+        ),
+        Some(Stop::FailedFast) | None => {}
+    }
+
+    if reports.is_empty() {
+        warn!("\n{}: didn't get any tracks, aborting...", "error".red().bold());
+        return Ok(reports);
+    }
+
+    if verbosity == VERBOSITY_SUMMARY && !ctx.opts.progress {
+        println!();
+    }
+
+    if let Some(path) = &ctx.opts.report {
+        match write_report(path, &reports) {
+            Ok(()) => summary!("\n{} Wrote report to \"{}\"", "=>".green().bold(), path.display()),
+            Err(err) => warn!(
+                "\n{}: cannot write report \"{}\": {}",
+                "warning".yellow().bold(),
+                path.display(),
+                err.to_string().to_lowercase()
+            ),
+        }
+    }
+
+    if let (Some(path), Some(state)) = (&ctx.opts.sync_state, &ctx.opts.resolve.sync_state) {
+        // Failed tracks are left out so the next sync tries them again
+        let failed: coll::HashSet<_> = reports
+            .iter()
+            .filter(|report| report.status == TrackOutcome::Failed)
+            .map(|report| report.id.as_str())
+            .collect();
+
+        let mut state = state.clone();
+
+        for (playlist_id, members) in resolution.synced_playlists {
+            let members = members.into_iter().filter(|id| !failed.contains(id.as_str())).collect();
+            state.insert(playlist_id, members);
+        }
+
+        match write_sync_state(path, &state) {
+            Ok(()) => summary!("\n{} Wrote sync state to \"{}\"", "=>".green().bold(), path.display()),
+            Err(err) => warn!(
+                "\n{}: cannot write sync state \"{}\": {}",
+                "warning".yellow().bold(),
+                path.display(),
+                err.to_string().to_lowercase()
+            ),
+        }
+    }
+
+    if let Some(dir) = &ctx.opts.sync_dir {
+        let members: coll::HashSet<_> = resolution
+            .tracks
+            .keys()
+            .map(|track_id| track_id.to_base62().unwrap_or_default())
+            .collect();
+
+        // The resolution of a streamed run stopped early can be incomplete, which must not prune anything
+        update_sync_dir(
+            dir,
+            ctx.opts.prune && stopped.is_none(),
+            &members,
+            &ctx.library,
+            &reports,
+        );
+    }
+
+    if ctx.opts.artist_index {
+        write_artist_indexes(&ctx.opts.format, &reports);
+    }
+
+    if let Some(dir) = &ctx.opts.m3u8_dir {
+        write_playlist_files(dir, &ctx.opts.format, &resolution.playlists, &ctx.library, &reports);
+    }
+
+    let count = |status: TrackOutcome| reports.iter().filter(|report| report.status == status).count();
+
+    let num_completed = count(TrackOutcome::Written);
+    let num_existing = count(TrackOutcome::Existing);
+    let num_unpopular = count(TrackOutcome::Unpopular);
+    let num_below_quality = count(TrackOutcome::BelowQuality);
+    let num_duplicate = count(TrackOutcome::Duplicate);
+
+    summary!("\n{} Processed tracks: ", "=>".green().bold(),);
+
+    summary!(
+        " {} {} error",
+        "->".yellow().bold(),
+        total - num_completed - num_existing - num_unpopular - num_below_quality - num_duplicate
+    );
+
+    summary!(" {} {} already downloaded", "->".yellow().bold(), num_existing);
+
+    summary!(" {} {} below minimum popularity", "->".yellow().bold(), num_unpopular);
+
+    if ctx.selection.strict {
+        summary!(
+            " {} {} unavailable at {}kbps",
+            "->".yellow().bold(),
+            num_below_quality,
+            ctx.selection.bitrate
+        );
+    }
+
+    if ctx.opts.dedupe != Dedupe::None {
+        summary!(" {} {} duplicates", "->".yellow().bold(), num_duplicate);
+    }
+
+    if ctx.opts.resolve.disc.is_some() {
+        summary!(" {} {} on other discs", "->".yellow().bold(), resolution.num_other_disc);
+    }
+
+    if let Some(date) = ctx.opts.resolve.added_after {
+        summary!(
+            " {} {} added to playlists on or before {}",
+            "->".yellow().bold(),
+            resolution.num_added_before,
+            date
+        );
+    }
+
+    if let Some(secs) = ctx.opts.resolve.skip_shorter_than {
+        summary!(
+            " {} {} episodes shorter than {}s",
+            "->".yellow().bold(),
+            resolution.num_teasers,
+            secs
+        );
+    }
+
+    if ctx.opts.sync_state.is_some() {
+        summary!(
+            " {} {} synced before",
+            "->".yellow().bold(),
+            resolution.num_synced_before
+        );
+    }
+
+    summary!(" {} {} new", "->".yellow().bold(), num_completed);
+
+    summary!(" {} {} total processed", "->".yellow().bold(), total);
+
+    if stopped == Some(Stop::Interrupted) {
+        return Err(Error::Interrupted);
+    }
+
+    Ok(reports)
+}
+
+#[derive(PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackOutcome {
+    Written,
+    Existing,
+    Unpopular,
+    BelowQuality,
+    Duplicate,
+    Failed,
+}
+
+impl fmt::Display for TrackOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackOutcome::Written => write!(f, "written"),
+            TrackOutcome::Existing => write!(f, "existing"),
+            TrackOutcome::Unpopular => write!(f, "unpopular"),
+            TrackOutcome::BelowQuality => write!(f, "below_quality"),
+            TrackOutcome::Duplicate => write!(f, "duplicate"),
+            TrackOutcome::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+// Everything the track tasks share. Fetching and writing are bounded separately, so a slow disk doesn't stall the
+// network stage and the other way around
+struct TrackContext {
+    session: lsc::Session,
+    opts: UserParams,
+    albums: AlbumCache,
+    features: FeaturesCache,
+    // Base62 track ids found in the --skip-duplicates-on-disk directory, with the file they were found in
+    library: coll::HashMap<String, path::PathBuf>,
+    archive: Option<DownloadArchive>,
+    db: Option<sync::Arc<LibraryDb>>,
+    rate_limit: Option<sync::Arc<RateLimit>>,
+    // Dedupe keys of the tracks processed so far, with the base62 id of the first track of every recording
+    recordings: sync::Mutex<coll::HashMap<String, String>>,
+    // Folders --save-cover has already handled this run, whether or not the cover could be written
+    cover_folders: sync::Mutex<coll::HashSet<path::PathBuf>>,
+    selection: AudioSelection,
+    jobs: atomic::AtomicUsize,
+    retiring_slots: atomic::AtomicUsize,
+    track_delay: sync::Mutex<stdtime::Duration>,
+    fetch_slots: tokio::sync::Semaphore,
+    write_slots: tokio::sync::Semaphore,
+}
+
+// When most of the recent tracks failed the account is likely being throttled, so instead of burning through the
+// rest of the input the concurrency is halved and every track waits a bit longer before starting
+static FAILURE_WINDOW: usize = 20;
+static FAILURE_THRESHOLD: usize = 10;
+static TRACK_DELAY_STEP: stdtime::Duration = stdtime::Duration::from_secs(2);
+static TRACK_DELAY_MAX: stdtime::Duration = stdtime::Duration::from_secs(30);
+
+fn adapt_concurrency(ctx: &TrackContext, recent_failures: &mut coll::VecDeque<bool>, outcome: &TrackOutcome) {
+    if recent_failures.len() == FAILURE_WINDOW {
+        recent_failures.pop_front();
+    }
+
+    recent_failures.push_back(*outcome == TrackOutcome::Failed);
+
+    let num_failures = recent_failures.iter().filter(|failed| **failed).count();
+
+    if num_failures < FAILURE_THRESHOLD {
+        return;
+    }
+
+    let jobs = ctx.jobs.load(atomic::Ordering::Relaxed);
+    let lowered_jobs = (jobs / 2).max(1);
+
+    let mut track_delay = ctx.track_delay.lock().unwrap();
+    *track_delay = (*track_delay + TRACK_DELAY_STEP).min(TRACK_DELAY_MAX);
+
+    let lowered = if lowered_jobs < jobs {
+        format!("lowering jobs to {} and ", lowered_jobs)
+    } else {
+        String::new()
+    };
+
+    warn!(
+        "{}: {} of the last {} tracks failed, {}waiting {}s before every track...",
+        " -> warning".yellow().bold(),
+        num_failures,
+        recent_failures.len(),
+        lowered,
+        track_delay.as_secs()
+    );
+
+    if lowered_jobs < jobs {
+        ctx.jobs.store(lowered_jobs, atomic::Ordering::Relaxed);
+        ctx.retiring_slots
+            .fetch_add(jobs - lowered_jobs, atomic::Ordering::Relaxed);
+    }
+
+    // The next adjustment should only be based on tracks that ran with the new settings
+    recent_failures.clear();
+}
+
+// Slots that are in use when the concurrency is lowered can't be taken away right away, so they're retired instead of
+// handed back once their track is done with them
+struct FetchSlot<'a> {
+    ctx: &'a TrackContext,
+    permit: Option<tokio::sync::SemaphorePermit<'a>>,
+}
+
+impl Drop for FetchSlot<'_> {
+    fn drop(&mut self) {
+        let retire = self
+            .ctx
+            .retiring_slots
+            .fetch_update(atomic::Ordering::Relaxed, atomic::Ordering::Relaxed, |num| {
+                num.checked_sub(1)
+            })
+            .is_ok();
+
+        if let (true, Some(permit)) = (retire, self.permit.take()) {
+            permit.forget();
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+pub struct TrackReport {
+    pub id: String,
+    pub name: Option<String>,
+    pub status: TrackOutcome,
+    pub path: Option<String>,
+    pub bitrate: Option<u32>,
+    pub bytes: Option<u64>,
+    pub error: Option<String>,
+    // Only used to group the written tracks for --artist-index
+    #[serde(skip)]
+    artist: Option<String>,
+    #[serde(skip)]
+    album: Option<String>,
+}
+
+impl TrackReport {
+    fn new(id: String) -> Self {
+        TrackReport {
+            id,
+            name: None,
+            status: TrackOutcome::Failed,
+            path: None,
+            bitrate: None,
+            bytes: None,
+            error: None,
+            artist: None,
+            album: None,
+        }
+    }
+
+    fn finished(mut self, status: TrackOutcome) -> Self {
+        self.status = status;
+        self
+    }
+
+    fn failed(mut self, err: impl fmt::Display) -> Self {
+        self.status = TrackOutcome::Failed;
+        self.error = Some(err.to_string());
+        self
+    }
+
+    fn to_csv_row(&self) -> String {
+        [
+            Some(self.id.clone()),
+            self.name.clone(),
+            Some(self.status.to_string()),
+            self.path.clone(),
+            self.bitrate.map(|bitrate| bitrate.to_string()),
+            self.bytes.map(|bytes| bytes.to_string()),
+            self.error.clone(),
+        ]
+        .into_iter()
+        .map(|field| csv_field(&field.unwrap_or_default()))
+        .collect::<Vec<_>>()
+        .join(",")
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+// Playlists are keyed by their base62 id and map to the base62 ids of the tracks they had on the last sync
+pub type SyncState = coll::HashMap<String, coll::HashSet<String>>;
+
+// A missing state file is a first sync, so every playlist track is new
+pub fn read_sync_state(path: &path::Path) -> Result<SyncState, String> {
+    match fs::read(path) {
+        Ok(contents) => {
+            serde_json::from_slice(&contents).map_err(|e| format!("invalid sync state \"{}\": {}", path.display(), e))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(SyncState::new()),
+        Err(err) => Err(format!(
+            "cannot read sync state \"{}\": {}",
+            path.display(),
+            err.to_string().to_lowercase()
+        )),
+    }
+}
+
+fn write_sync_state(path: &path::Path, state: &SyncState) -> io::Result<()> {
+    fs::write(path, serde_json::to_string_pretty(state)? + "\n")
+}
+
+// Files a sync directory holds, keyed by the base62 id of their track, so pruning only ever deletes files a sync put
+// there. Paths are relative to the directory
+static SYNC_MANIFEST_FILE: &str = ".rippify-sync.json";
+
+type SyncManifest = coll::BTreeMap<String, path::PathBuf>;
+
+fn update_sync_dir(
+    dir: &path::Path,
+    prune: bool,
+    members: &coll::HashSet<String>,
+    library: &coll::HashMap<String, path::PathBuf>,
+    reports: &[TrackReport],
+) {
+    let manifest_path = dir.join(SYNC_MANIFEST_FILE);
+
+    let previous: SyncManifest = fs::read(&manifest_path)
+        .ok()
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .unwrap_or_default();
+
+    // The embedded track ids of the indexed files still find tracks that were renamed or moved since the last sync
+    let locate = |track_id: &str, path: Option<path::PathBuf>| {
+        path.filter(|path| path.is_file())
+            .or_else(|| library.get(track_id).cloned())
+            .or_else(|| previous.get(track_id).map(|file| dir.join(file)))
+            .filter(|path| path.is_file())
+    };
+
+    let mut manifest = SyncManifest::new();
+
+    for report in reports {
+        if let Some(path) = locate(&report.id, report.path.as_ref().map(path::PathBuf::from)) {
+            let file = path.strip_prefix(dir).map(path::Path::to_path_buf).unwrap_or(path);
+            manifest.insert(report.id.clone(), file);
+        }
+    }
+
+    for (track_id, file) in previous.iter() {
+        if manifest.contains_key(track_id) {
+            continue;
+        }
+
+        // Tracks still in the playlist that failed this time are kept for the next sync, as are removed ones
+        // until they're pruned
+        if members.contains(track_id) || !prune {
+            manifest.insert(track_id.clone(), file.clone());
+            continue;
+        }
+
+        let Some(path) = locate(track_id, None) else {
+            continue;
+        };
+
+        match fs::remove_file(&path) {
+            Ok(()) => summary!(" {} pruned \"{}\"", "->".yellow().bold(), path.display()),
+            Err(err) => {
+                warn!(
+                    " {}: cannot prune \"{}\": {}",
+                    "-> warning".yellow().bold(),
+                    path.display(),
+                    err.to_string().to_lowercase()
+                );
+                manifest.insert(track_id.clone(), file.clone());
+            }
+        }
+    }
+
+    let written = serde_json::to_string_pretty(&manifest)
+        .map_err(io::Error::from)
+        .and_then(|manifest| fs::write(&manifest_path, manifest + "\n"));
+
+    match written {
+        Ok(()) => summary!(
+            "\n{} Wrote sync manifest to \"{}\"",
+            "=>".green().bold(),
+            manifest_path.display()
+        ),
+        Err(err) => warn!(
+            "\n{}: cannot write sync manifest \"{}\": {}",
+            "warning".yellow().bold(),
+            manifest_path.display(),
+            err.to_string().to_lowercase()
+        ),
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ArtistIndex {
+    artist: String,
+    // Album names mapped to how many of their tracks are in the artist folder
+    albums: coll::BTreeMap<String, usize>,
+}
+
+static ARTIST_INDEX_FILE: &str = "artist.json";
+
+// Indexes from earlier runs are merged into, so albums downloaded back then stay listed
+fn write_artist_indexes(format: &OutputFormat, reports: &[TrackReport]) {
+    let mut indexes = coll::BTreeMap::<path::PathBuf, ArtistIndex>::new();
+
+    for report in reports {
+        let (Some(path), Some(artist), Some(album)) = (&report.path, &report.artist, &report.album) else {
+            continue;
+        };
+
+        if !matches!(report.status, TrackOutcome::Written | TrackOutcome::Existing) {
+            continue;
+        }
+
+        let Some(dir) = format.artist_dir(path) else {
+            warn!(
+                "\n{}: the output format has no {{author}} folder, not writing artist indexes...",
+                "warning".yellow().bold()
+            );
+            return;
+        };
+
+        let index = indexes.entry(dir).or_insert_with(|| ArtistIndex {
+            artist: artist.clone(),
+            albums: coll::BTreeMap::new(),
+        });
+
+        *index.albums.entry(album.clone()).or_default() += 1;
+    }
+
+    for (dir, mut index) in indexes {
+        let file = dir.join(ARTIST_INDEX_FILE);
+
+        let previous = fs::read(&file)
+            .ok()
+            .and_then(|contents| serde_json::from_slice::<ArtistIndex>(&contents).ok());
+
+        for (album, num_tracks) in previous.map(|previous| previous.albums).unwrap_or_default() {
+            let count = index.albums.entry(album).or_default();
+            *count = num_tracks.max(*count);
+        }
+
+        let written = serde_json::to_string_pretty(&index)
+            .map_err(io::Error::from)
+            .and_then(|json| fs::write(&file, json + "\n"));
+
+        match written {
+            Ok(()) => info!(" {} wrote \"{}\"", "->".yellow().bold(), file.display()),
+            Err(err) => warn!(
+                " {} {}: cannot write \"{}\": {}",
+                "->".yellow().bold(),
+                "warning".yellow().bold(),
+                file.display(),
+                err.to_string().to_lowercase()
+            ),
+        }
+    }
+}
+
+// Every playlist input gets a <name>.m3u8 in the given folder, listing the tracks that are on disk in playlist order.
+// Tracks skipped by --sync-state are still found through the library when there's one
+fn write_playlist_files(
+    dir: &path::Path,
+    format: &OutputFormat,
+    playlists: &[(String, Vec<String>)],
+    library: &coll::HashMap<String, path::PathBuf>,
+    reports: &[TrackReport],
+) {
+    let reports: coll::HashMap<_, _> = reports
+        .iter()
+        .filter(|report| matches!(report.status, TrackOutcome::Written | TrackOutcome::Existing))
+        .map(|report| (report.id.as_str(), report))
+        .collect();
+
+    if let Err(err) = fs::create_dir_all(dir) {
+        warn!(
+            "\n{}: cannot create playlist folder \"{}\": {}",
+            "warning".yellow().bold(),
+            dir.display(),
+            err.to_string().to_lowercase()
+        );
+        return;
+    }
+
+    let base = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+
+    for (name, items) in playlists {
+        let file = dir.join(format!("{}.m3u8", format.component(name)));
+        let mut lines = vec![String::from("#EXTM3U")];
+
+        for item_id in items {
+            let report = reports.get(item_id.as_str());
+
+            let Some(path) = report
+                .and_then(|report| report.path.as_ref().map(path::PathBuf::from))
+                .or_else(|| library.get(item_id).cloned())
+                .and_then(|path| path.canonicalize().ok())
+            else {
+                continue;
+            };
+
+            if let Some(report) = report {
+                let title = match (&report.artist, &report.name) {
+                    (Some(artist), Some(name)) => format!("{} - {}", artist, name),
+                    (None, Some(name)) => name.clone(),
+                    _ => String::new(),
+                };
+
+                lines.push(format!("#EXTINF:-1,{}", title));
+            }
+
+            lines.push(relative_path(&base, &path).to_string_lossy().into_owned());
+        }
+
+        match fs::write(&file, lines.join("\n") + "\n") {
+            Ok(()) => summary!("\n{} Wrote playlist to \"{}\"", "=>".green().bold(), file.display()),
+            Err(err) => warn!(
+                "\n{}: cannot write playlist \"{}\": {}",
+                "warning".yellow().bold(),
+                file.display(),
+                err.to_string().to_lowercase()
+            ),
+        }
+    }
+}
+
+// Both paths are expected to be absolute, so there's always a common root to go up to
+fn relative_path(from: &path::Path, to: &path::Path) -> path::PathBuf {
+    let common = from
+        .components()
+        .zip(to.components())
+        .take_while(|(from, to)| from == to)
+        .count();
+
+    let mut relative: path::PathBuf = from
+        .components()
+        .skip(common)
+        .map(|_| path::Component::ParentDir)
+        .collect();
+    relative.extend(to.components().skip(common));
+    relative
+}
+
+// Reports ending in .json are written as a json array, anything else as csv
+fn write_report(path: &path::Path, reports: &[TrackReport]) -> io::Result<()> {
+    let contents = if path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("json")) {
+        serde_json::to_string_pretty(reports)?
+    } else {
+        let mut rows = vec![String::from("id,name,status,path,bitrate,bytes,error")];
+        rows.extend(reports.iter().map(TrackReport::to_csv_row));
+        rows.join("\n") + "\n"
+    };
+
+    fs::write(path, contents)
+}
+
+async fn process_track(ctx: &TrackContext, job: &TrackJob) -> TrackReport {
+    let track_id = job.id;
+
+    // Held from the first metadata request until the audio is downloaded, the write stage has its own slots
+    let fetch_slot = FetchSlot {
+        ctx,
+        permit: Some(ctx.fetch_slots.acquire().await.unwrap()),
+    };
+
+    let track_delay = *ctx.track_delay.lock().unwrap();
+
+    if !track_delay.is_zero() {
+        time::sleep(track_delay).await;
+    }
+
+    wait_for_cool_down().await;
+
+    let mut report = TrackReport::new(track_id.to_base62().unwrap_or_default());
+
+    info_part!(" {} ", "->".yellow().bold());
+
+    if track_id.item_type == lsc::spotify_id::SpotifyItemType::Episode {
+        return process_episode(ctx, fetch_slot, job, report).await;
+    }
+
+    let found = with_cool_down(&ctx.opts.retry, || {
+        get_track_from_id(
+            &ctx.session,
+            &ctx.albums,
+            &track_id,
+            ctx.opts.prefer_canonical,
+            &ctx.selection,
+        )
+    })
+    .await;
+
+    let (track, source, relation) = match found {
+        Ok((track, source, relation)) => {
+            match relation {
+                TrackRelation::Requested => {
+                    info!("{} ({})", track.name.bold(), track.id.to_base62().unwrap());
+                }
+                TrackRelation::Alternative | TrackRelation::Canonical => {
+                    info!(
+                        "{} ({} {} {})",
+                        track.name.bold(),
+                        track.id.to_base62().unwrap(),
+                        relation,
+                        track_id.to_base62().unwrap()
+                    );
+                }
+            }
+
+            report.name = Some(track.name.clone());
+            report.bitrate = Some(source.bitrate);
+
+            debug!(
+                "   - audio file {}: {} at {}kbps",
+                source.file_id.to_base16().unwrap_or_default(),
+                source.container.codec(),
+                source.bitrate
+            );
+
+            (track, source, relation)
+        }
+        Err(err) if err.kind == librespot_core::error::ErrorKind::Unavailable => {
+            info!("{} ({})", "??".bold(), track_id.to_base62().unwrap());
+            warn!("   - {}: {}, skipping...", "note".bright_blue().bold(), err.error);
+
+            report.error = Some(err.error.to_string());
+            return report.finished(TrackOutcome::BelowQuality);
+        }
+        Err(err) => {
+            info!("{} ({})", "??".bold(), track_id.to_base62().unwrap());
+            warn!(
+                "   - {}: cannot get track from id: {}, skipping...",
+                "warning".yellow().bold(),
+                err,
+            );
+            let _ = fail_fast(&ctx.opts);
+            return report.failed(err);
+        }
+    };
+
+    if let Some(min_popularity) = ctx.opts.min_popularity {
+        if track.popularity < min_popularity {
+            warn!(
+                "   - {}: popularity {} is below the minimum of {}, skipping...",
+                "note".bright_blue().bold(),
+                track.popularity,
+                min_popularity
+            );
+
+            return report.finished(TrackOutcome::Unpopular);
+        }
+    }
+
+    if ctx
+        .archive
+        .as_ref()
+        .map_or(false, |archive| archive.contains(&[&track_id, &track.id]))
+    {
+        warn!(
+            "   - {}: track is in the download archive, skipping...",
+            "note".bright_blue().bold()
+        );
+
+        return report.finished(TrackOutcome::Existing);
+    }
+
+    if let Some(key) = ctx.opts.dedupe.key(&track) {
+        let first = ctx
+            .recordings
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert_with(|| report.id.clone())
+            .clone();
+
+        if first != report.id {
+            warn!(
+                "   - {}: same recording as track {}, skipping...",
+                "note".bright_blue().bold(),
+                first
+            );
+
+            return report.finished(TrackOutcome::Duplicate);
+        }
+    }
+
+    // The audio always comes from the downloaded track, only its name, path and tags can follow the requested one
+    let requested = match (relation, &ctx.opts.alt_metadata) {
+        (TrackRelation::Alternative, AltMetadata::Requested) => {
+            match ctx.albums.get_track(&ctx.session, &track_id).await {
+                Ok(requested) => {
+                    info!("   - named after the requested track {}", requested.name.bold());
+                    report.name = Some(requested.name.clone());
+                    Some(requested)
+                }
+                Err(err) => {
+                    warn!(
+                        "   - {}: cannot get metadata for the requested track: {}, using the alternative's instead...",
+                        "warning".yellow().bold(),
+                        err
+                    );
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let metadata = requested.as_deref().unwrap_or(&track);
+
+    report.artist = metadata.artists.first().map(|artist| artist.name.clone());
+    report.album = Some(metadata.album.name.clone());
+
+    // Needed before the output path, which only gets disc folders when the album has more than one disc
+    let album = match ctx.albums.get(&ctx.session, &metadata.album.id).await {
+        Ok(album) => Some(album),
+        Err(err) => {
+            warn!(
+                "   - {}: cannot get album metadata: {}, tagging without it...",
+                "warning".yellow().bold(),
+                err
+            );
+            None
+        }
+    };
+
+    let output_file = ctx.opts.format.parse_output_format(
+        metadata,
+        album.as_deref(),
+        ctx.opts.codec.extension(source.container),
+        &job.source,
+        job.position,
+    );
+
+    report.path = Some(output_file.file.clone());
+
+    // Kept files only go on to gather their tags with --refresh-tags, they skip the download either way
+    let mut refresh = None;
+
+    if !ctx.opts.stdout && path::Path::new(&output_file.file).exists() {
+        if keep_existing(ctx, &output_file.file, &source, track.duration).await {
+            refresh = Some(output_file.file.clone());
+        }
+    } else if let Some(existing) = [&track_id, &track.id]
+        .iter()
+        .find_map(|id| ctx.library.get(&id.to_base62().unwrap_or_default()))
+    {
+        warn!(
+            "   - {}: track is already in the library at \"{}\", skipping...",
+            "note".bright_blue().bold(),
+            existing.display()
+        );
+
+        refresh = Some(existing.to_string_lossy().into_owned());
+    }
+
+    if let (Some(existing), Some(album)) = (&refresh, &album) {
+        save_folder_cover(ctx, album, existing).await;
+    }
+
+    if refresh.is_some() && !ctx.opts.refresh_tags {
+        return report.finished(TrackOutcome::Existing);
+    }
+
+    let cover = match &album {
+        Some(album) => match ctx
+            .albums
+            .get_cover(&ctx.session, album, &ctx.opts.tags.cover_size)
+            .await
+        {
+            Ok(cover) => Some(cover),
+            Err(err) => {
+                warn!(
+                    "   - {}: cannot get cover art: {}, tagging without it...",
+                    "warning".yellow().bold(),
+                    err
+                );
+                None
+            }
+        },
+        None => None,
+    };
+
+    let features = if ctx.opts.tags.audio_features {
+        ctx.features
+            .get(&ctx.session, &metadata.id)
+            .await
+            .unwrap_or_else(|err| {
+                warn!(
+                    "   - {}: cannot get audio features: {}, tagging without them...",
+                    "warning".yellow().bold(),
+                    err
+                );
+                None
+            })
+    } else {
+        None
+    };
+
+    // Plenty of tracks have no lyrics at all, which isn't worth a warning
+    let lyrics = if ctx.opts.tags.lyrics {
+        match lsm::Lyrics::get(&ctx.session, &metadata.id).await {
+            Ok(lyrics) => Some(lyrics),
+            Err(err) => {
+                info!("   - no lyrics: {}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let sources = TagSources {
+        item: TaggedItem::Track(metadata),
+        album: album.as_deref(),
+        cover: cover.as_deref(),
+        features: features.as_deref(),
+        lyrics: lyrics.as_ref(),
+        bitrate: Some(source.bitrate),
+        normalization: None,
+    };
+
+    if let Some(existing) = refresh {
+        drop(fetch_slot);
+        let _write_slot = ctx.write_slots.acquire().await.unwrap();
+
+        let refreshed = {
+            let (existing, sources, tags) = (existing.clone(), sources.owned(), ctx.opts.tags.clone());
+            run_blocking(move || track_refresh_tags(&existing, &sources.borrow(), &tags)).await
+        };
+
+        match refreshed {
+            Ok(()) => info!("   - refreshed the tags of \"{}\"", existing),
+            Err(err) => {
+                warn!("   - {}: {}, keeping the old tags...", "warning".yellow().bold(), err);
+                let _ = fail_fast(&ctx.opts);
+            }
+        }
+
+        return report.finished(TrackOutcome::Existing);
+    }
+
+    match fetch_and_write(ctx, fetch_slot, &track.id, &source, &sources, output_file).await {
+        Ok(output) => {
+            info!("   - wrote \"{}\"", output);
+
+            if ctx.opts.save_metadata_json && !ctx.opts.stdout {
+                match track_write_metadata(metadata, &output) {
+                    Ok(sidecar) => info!("   - wrote \"{}\"", sidecar),
+                    Err(err) => {
+                        warn!("   - {}: {}, skipping metadata...", "warning".yellow().bold(), err);
+                        let _ = fail_fast(&ctx.opts);
+                    }
+                }
+            }
+
+            if let (Some(lyrics), false) = (&lyrics, ctx.opts.stdout) {
+                match track_write_lyrics(lyrics, &output) {
+                    Ok(sidecar) => info!("   - wrote \"{}\"", sidecar),
+                    Err(err) => {
+                        warn!("   - {}: {}, skipping lyrics file...", "warning".yellow().bold(), err);
+                        let _ = fail_fast(&ctx.opts);
+                    }
+                }
+            }
+
+            if let (Some(album), false) = (&album, ctx.opts.stdout) {
+                save_folder_cover(ctx, album, &output).await;
+            }
+
+            if let (Some(hook), false) = (&ctx.opts.post_hook, ctx.opts.stdout) {
+                run_post_hook(hook, &output, TaggedItem::Track(metadata)).await;
+            }
+
+            archive_item(ctx, &track_id);
+
+            if let (Some(db), false) = (&ctx.db, ctx.opts.stdout) {
+                db_record_track(db, &track, &output, source.bitrate).await;
+            }
+
+            report.bytes = fs::metadata(&output).ok().map(|metadata| metadata.len());
+            report.path = Some(output);
+            report.finished(TrackOutcome::Written)
+        }
+        Err(err) => {
+            warn!("   - {}: {}, skipping...", "warning".yellow().bold(), err);
+            let _ = fail_fast(&ctx.opts);
+            report.failed(err)
+        }
+    }
+}
+
+// What to do with output files that already exist. Files with an unknown bitrate are never replaced for a quality
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Overwrite {
+    Never,
+    Always,
+    IfLarger,
+    IfDifferentQuality,
+}
+
+// Whether the output file of an item is already there and fine to keep, noting why it's downloaded again otherwise
+async fn keep_existing(ctx: &TrackContext, file: &str, source: &AudioSource, duration: i32) -> bool {
+    // Transcoded files have neither the vorbis comments nor the stream the checks below read
+    let transcoded = ctx.opts.codec != Codec::Ogg;
+
+    if ctx.opts.overwrite == Overwrite::Always {
+        warn!(
+            "   - {}: output file \"{}\" already exists, overwriting it...",
+            "note".bright_blue().bold(),
+            file
+        );
+        return false;
+    }
+
+    let compares_bitrate = matches!(ctx.opts.overwrite, Overwrite::IfLarger | Overwrite::IfDifferentQuality);
+
+    let existing_bitrate = if compares_bitrate && !transcoded {
+        fs::File::open(file)
+            .ok()
+            .and_then(|file| read_bitrate(io::BufReader::new(file)))
+    } else {
+        None
+    };
+
+    let damage = if ctx.opts.verify_existing && !transcoded {
+        let (file, container) = (file.to_owned(), source.container);
+        run_blocking(move || verify_audio_file(&file, container, duration))
+            .await
+            .err()
+    } else {
+        None
+    };
+
+    match (existing_bitrate, damage) {
+        (_, Some(damage)) => {
+            warn!(
+                "   - {}: output file \"{}\" {}, downloading it again...",
+                "note".bright_blue().bold(),
+                file,
+                damage
+            );
+            false
+        }
+        (Some(bitrate), None)
+            if bitrate < source.bitrate
+                || (bitrate != source.bitrate && ctx.opts.overwrite == Overwrite::IfDifferentQuality) =>
+        {
+            warn!(
+                "   - {}: output file \"{}\" is {}kbps, replacing it with {}kbps...",
+                "note".bright_blue().bold(),
+                file,
+                bitrate,
+                source.bitrate
+            );
+            false
+        }
+        _ => {
+            warn!(
+                "   - {}: output file \"{}\" already exists, skipping...",
+                "note".bright_blue().bold(),
+                file
+            );
+            true
+        }
+    }
+}
+
+// Episodes go through the same download and write stages as tracks, they just don't have alternatives, albums or
+// audio features to look up
+async fn process_episode(
+    ctx: &TrackContext,
+    fetch_slot: FetchSlot<'_>,
+    job: &TrackJob,
+    mut report: TrackReport,
+) -> TrackReport {
+    let episode_id = job.id;
+
+    let found = with_cool_down(&ctx.opts.retry, || {
+        get_episode_from_id(&ctx.session, &episode_id, &ctx.selection)
+    })
+    .await;
+
+    let (episode, source) = match found {
+        Ok((episode, source)) => {
+            info!("{} ({})", episode.name.bold(), episode_id.to_base62().unwrap());
+
+            report.name = Some(episode.name.clone());
+            report.bitrate = Some(source.bitrate);
+
+            (episode, source)
+        }
+        Err(err) if err.kind == librespot_core::error::ErrorKind::Unavailable => {
+            info!("{} ({})", "??".bold(), episode_id.to_base62().unwrap());
+            warn!("   - {}: {}, skipping...", "note".bright_blue().bold(), err.error);
+
+            report.error = Some(err.error.to_string());
+            return report.finished(TrackOutcome::BelowQuality);
+        }
+        Err(err) => {
+            info!("{} ({})", "??".bold(), episode_id.to_base62().unwrap());
+            warn!(
+                "   - {}: cannot get episode from id: {}, skipping...",
+                "warning".yellow().bold(),
+                err,
+            );
+            let _ = fail_fast(&ctx.opts);
+            return report.failed(err);
+        }
+    };
+
+    report.artist = Some(episode.show_name.clone());
+    report.album = Some(episode.show_name.clone());
+
+    if ctx
+        .archive
+        .as_ref()
+        .map_or(false, |archive| archive.contains(&[&episode_id]))
+    {
+        warn!(
+            "   - {}: episode is in the download archive, skipping...",
+            "note".bright_blue().bold()
+        );
+
+        return report.finished(TrackOutcome::Existing);
+    }
+
+    let output_file = ctx.opts.format.parse_episode_output_format(
+        &episode,
+        ctx.opts.codec.extension(source.container),
+        &job.source,
+        job.position,
+    );
+
+    report.path = Some(output_file.file.clone());
+
+    if !ctx.opts.stdout
+        && path::Path::new(&output_file.file).exists()
+        && keep_existing(ctx, &output_file.file, &source, episode.duration).await
+    {
+        return report.finished(TrackOutcome::Existing);
+    }
+
+    let cover = match ctx.opts.tags.cover_size.select(&episode.covers) {
+        Some(image) => match download_cover(&ctx.session, image).await {
+            Ok(cover) => Some(cover),
+            Err(err) => {
+                warn!(
+                    "   - {}: cannot get cover art: {}, tagging without it...",
+                    "warning".yellow().bold(),
+                    err
+                );
+                None
+            }
+        },
+        None => None,
+    };
+
+    let sources = TagSources {
+        item: TaggedItem::Episode(&episode),
+        album: None,
+        cover: cover.as_ref(),
+        features: None,
+        lyrics: None,
+        bitrate: Some(source.bitrate),
+        normalization: None,
+    };
+
+    match fetch_and_write(ctx, fetch_slot, &episode_id, &source, &sources, output_file).await {
+        Ok(output) => {
+            info!("   - wrote \"{}\"", output);
+
+            if let (Some(hook), false) = (&ctx.opts.post_hook, ctx.opts.stdout) {
+                run_post_hook(hook, &output, TaggedItem::Episode(&episode)).await;
+            }
+
+            archive_item(ctx, &episode_id);
+
+            report.bytes = fs::metadata(&output).ok().map(|metadata| metadata.len());
+            report.path = Some(output);
+            report.finished(TrackOutcome::Written)
+        }
+        Err(err) => {
+            warn!("   - {}: {}, skipping...", "warning".yellow().bold(), err);
+            let _ = fail_fast(&ctx.opts);
+            report.failed(err)
+        }
+    }
+}
+
+// Downloads the audio of a track or episode and writes it to its output file, tagged from the given sources. Errors
+// are returned as messages, the caller reports them like any other failure of the item
+async fn fetch_and_write(
+    ctx: &TrackContext,
+    fetch_slot: FetchSlot<'_>,
+    item_id: &lsc::SpotifyId,
+    source: &AudioSource,
+    sources: &TagSources<'_>,
+    output_file: OutputFile,
+) -> Result<String, String> {
+    let keep_encrypted = ctx.opts.keep_encrypted.as_deref();
+
+    // Transcoded items are written as downloaded to a temporary file first, which is then encoded to the output path
+    let (output_file, transcode) = match ctx.opts.codec {
+        Codec::Ogg => (output_file, None),
+        _ => {
+            let downloaded = tempfile::Builder::new()
+                .suffix(&format!(".{}", source.container.extension()))
+                .tempfile_in(&ctx.opts.temp_dir)
+                .map_err(|err| format!("cannot create transcoding file: {}", err))?;
+
+            let file = downloaded.path().to_string_lossy().into_owned();
+            (OutputFile { dir: None, file }, Some((downloaded, output_file)))
+        }
+    };
+
+    let written = if ctx.opts.stage_to_disk {
+        let mut staged = match tempfile::tempfile_in(&ctx.opts.temp_dir) {
+            Ok(staged) => staged,
+            Err(err) => return Err(format!("cannot create staging file: {}", err)),
+        };
+
+        let normalization = match track_download(
+            item_id,
+            source,
+            &ctx.session,
+            ctx.rate_limit.as_ref(),
+            keep_encrypted,
+            &ctx.opts,
+            &mut staged,
+        )
+        .await
+        {
+            Ok(normalization) => normalization,
+            Err(err) => return Err(err.to_string()),
+        };
+
+        drop(fetch_slot);
+        let _write_slot = ctx.write_slots.acquire().await.unwrap();
+
+        let (sources, tags, temp_dir) = (sources.owned(), ctx.opts.tags.clone(), ctx.opts.temp_dir.clone());
+        let (container, decrypt_only) = (source.container, ctx.opts.decrypt_only);
+
+        run_blocking(move || {
+            // Untagged audio has no use for its loudness
+            let sources = (!decrypt_only)
+                .then(|| normalized_sources(&sources.borrow(), normalization, &staged, container, &tags));
+
+            track_write_staged(staged, container, sources.as_ref(), &tags, &temp_dir, output_file)
+                .map_err(|err| err.to_string())
+        })
+        .await
+    } else {
+        let mut buffer = Vec::<u8>::new();
+
+        let normalization = match track_download(
+            item_id,
+            source,
+            &ctx.session,
+            ctx.rate_limit.as_ref(),
+            keep_encrypted,
+            &ctx.opts,
+            &mut buffer,
+        )
+        .await
+        {
+            Ok(normalization) => normalization,
+            Err(err) => return Err(err.to_string()),
+        };
+
+        drop(fetch_slot);
+        let _write_slot = ctx.write_slots.acquire().await.unwrap();
+
+        let (sources, tags) = (sources.owned(), ctx.opts.tags.clone());
+        let (container, decrypt_only, stdout) = (source.container, ctx.opts.decrypt_only, ctx.opts.stdout);
+
+        run_blocking(move || {
+            let written = 'written: {
+                if decrypt_only {
+                    break 'written if stdout {
+                        track_write_stdout(buffer)
+                    } else {
+                        track_write(buffer, output_file)
+                    };
+                }
+
+                if container == AudioContainer::Mp4 {
+                    break 'written if stdout {
+                        track_write_stdout(buffer)
+                    } else {
+                        track_write(buffer, output_file)
+                            .inspect(|output| track_tag_mp4_output(output, &sources.borrow(), &tags))
+                    };
+                }
+
+                let sources = &normalized_sources(
+                    &sources.borrow(),
+                    normalization,
+                    io::Cursor::new(&buffer),
+                    container,
+                    &tags,
+                );
+
+                // Untagged audio is still better than no audio, so tagging failures don't skip the track
+                let mut buffer_tags = Vec::<u8>::new();
+
+                let buffer_tags =
+                    match track_add_metadata_tags(io::Cursor::new(&buffer), &mut buffer_tags, sources, &tags) {
+                        Ok(()) => buffer_tags,
+                        Err(err) => {
+                            warn!(
+                                "   - {}: {}, writing untagged audio instead...",
+                                "warning".yellow().bold(),
+                                err
+                            );
+                            buffer
+                        }
+                    };
+
+                if stdout {
+                    track_write_stdout(buffer_tags)
+                } else {
+                    track_write(buffer_tags, output_file)
+                }
+            };
+
+            written.map_err(|err| err.to_string())
+        })
+        .await
+    };
+
+    match transcode {
+        Some((downloaded, output_file)) => {
+            written?;
+            track_transcode(&ctx.opts.codec, downloaded.path(), source, output_file)
+                .await
+                .map_err(|err| err.to_string())
+        }
+        None => written,
+    }
+}
+
+static PROGRESS_WIDTH: usize = 40;
+
+// Why a run stopped before all of its tracks were processed
+#[derive(PartialEq)]
+enum Stop {
+    Interrupted,
+    TimedOut,
+    FailedFast,
+}
+
+// Runs every job received as its own task and hands each result over as soon as it's done, along with the number of
+// jobs spawned so far. Returns once the channel is closed and the last task has finished, or right away on a stop,
+// which aborts the tasks still running
+async fn join_tasks<J, R, F>(
+    jobs: &mut tokio::sync::mpsc::UnboundedReceiver<J>,
+    mut stop: std::pin::Pin<&mut impl std::future::Future<Output = Stop>>,
+    mut spawn: impl FnMut(J) -> F,
+    mut joined: impl FnMut(Result<R, task::JoinError>, usize),
+) -> (usize, Option<Stop>)
+where
+    F: std::future::Future<Output = R> + Send + 'static,
+    R: Send + 'static,
+{
+    let mut tasks = task::JoinSet::new();
+    let mut spawned: usize = 0;
+    let mut receiving = true;
+
+    loop {
+        // The stop never resolves without a signal or a deadline, so it can't be what ends a finished run
+        if !receiving && tasks.is_empty() {
+            return (spawned, None);
+        }
+
+        tokio::select! {
+            received = jobs.recv(), if receiving => match received {
+                Some(job) => {
+                    tasks.spawn(spawn(job));
+                    spawned += 1;
+                }
+                None => receiving = false,
+            },
+            Some(result) = tasks.join_next() => joined(result, spawned),
+            stop = &mut stop => {
+                tasks.abort_all();
+                return (spawned, Some(stop));
+            }
+        }
+    }
+}
+
+// Resolves once the run has to stop early, on an interrupt or at the --total-timeout deadline
+async fn wait_for_stop(deadline: Option<time::Instant>) -> Stop {
+    let timed_out = async {
+        match deadline {
+            Some(deadline) => time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        _ = wait_for_interrupt() => Stop::Interrupted,
+        _ = timed_out => Stop::TimedOut,
+        _ = wait_for_fail_fast() => Stop::FailedFast,
+    }
+}
+
+// Resolves once a failure stopped a --fail-fast run, even one that happened before it was waited for
+async fn wait_for_fail_fast() {
+    let notified = FAIL_FAST_NOTIFY.notified();
+    tokio::pin!(notified);
+    notified.as_mut().enable();
+
+    if !FAILED_FAST.load(atomic::Ordering::Relaxed) {
+        notified.await;
+    }
+}
+
+// Resolves on Ctrl-C, or when the process is asked to terminate
+async fn wait_for_interrupt() {
+    #[cfg(unix)]
+    if let Ok(mut terminate) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+        return;
+    }
+
+    tokio::signal::ctrl_c().await.ok();
+}
+
+// Redraws a single progress line in place, used instead of the per track output with --summary-only
+fn print_progress(done: usize, total: usize) {
+    if let Some(progress) = PROGRESS.lock().unwrap().as_ref() {
+        progress.overall.set_length(total as u64);
+        progress.overall.set_position(done as u64);
+        return;
+    }
+
+    let filled = if total == 0 {
+        PROGRESS_WIDTH
+    } else {
+        done * PROGRESS_WIDTH / total
+    };
+
+    print!(
+        "\r {} [{}{}] {}/{}",
+        "->".yellow().bold(),
+        "#".repeat(filled),
+        "-".repeat(PROGRESS_WIDTH - filled),
+        done,
+        total
+    );
+
+    io::stdout().flush().ok();
+}
+
+static FAIL_FAST_MESSAGE: &str = "stopping at the first failure because of --fail-fast";
+
+// Set by the first failure of a --fail-fast run, which stops the download loop like an interrupt does
+static FAILED_FAST: atomic::AtomicBool = atomic::AtomicBool::new(false);
+static FAIL_FAST_NOTIFY: tokio::sync::Notify = tokio::sync::Notify::const_new();
+
+// Called right after reporting why a resource or track is skipped, turning it into a run-aborting failure if asked to.
+// Tracks can't return the error themselves, they're stopped by the download loop once the failure is flagged
+fn fail_fast(opts: &UserParams) -> Result<(), Error> {
+    if !opts.fail_fast {
+        return Ok(());
+    }
+
+    if !FAILED_FAST.swap(true, atomic::Ordering::Relaxed) {
+        fatal(String::from(FAIL_FAST_MESSAGE));
+
+        if let Ok(log) = TRACK_LOG.try_with(|log| log.take()) {
+            write_log(log);
+        }
+
+        FAIL_FAST_NOTIFY.notify_waiters();
+    }
+
+    Err(Error::Failed(String::from(FAIL_FAST_MESSAGE)))
+}
+
+// For failures reported where the error can't be returned right away, like while going through the inputs
+fn failed_fast() -> Result<(), Error> {
+    if FAILED_FAST.load(atomic::Ordering::Relaxed) {
+        Err(Error::Failed(String::from(FAIL_FAST_MESSAGE)))
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct ResolveOptions {
+    pub disc: Option<i32>,
+    pub artist_albums_limit: Option<usize>,
+    pub artist_include: Vec<ArtistGroup>,
+    pub artist_top_tracks_only: bool,
+    pub added_after: Option<::time::Date>,
+    // Episodes of shows shorter than this many seconds are taken for trailers and teasers and skipped
+    pub skip_shorter_than: Option<u32>,
+    // Playlist ids mapped to the tracks they had on the last sync, loaded from --sync-state
+    pub sync_state: Option<SyncState>,
+    pub interactive: bool,
+    // Take the best search match instead of asking which ones to download
+    pub search_first: bool,
+    pub radio_count: usize,
+}
+
+#[derive(Clone)]
+pub struct TagOptions {
+    pub normalize: Normalize,
+    pub compilation: CompilationTag,
+    pub album_artist: bool,
+    pub cover_size: CoverSize,
+    pub audio_features: bool,
+    pub lyrics: bool,
+    pub strip_featured: bool,
+}
+
+#[derive(Clone)]
+pub struct UserParams {
+    // None logs in with the credentials cached in cache_dir by an earlier run
+    pub credentials: Option<lsc_auth::Credentials>,
+    pub login: bool,
+    pub cache_dir: path::PathBuf,
+    pub cache_credentials: bool,
+    pub format: OutputFormat,
+    pub keep_encrypted: Option<path::PathBuf>,
+    pub stdout: bool,
+    pub retag_dir: Option<path::PathBuf>,
+    pub skip_duplicates_on_disk: Option<path::PathBuf>,
+    pub download_archive: Option<path::PathBuf>,
+    pub db: Option<path::PathBuf>,
+    pub sync_dir: Option<path::PathBuf>,
+    pub prune: bool,
+    pub sync_state: Option<path::PathBuf>,
+    pub min_popularity: Option<i32>,
+    pub prefer_canonical: bool,
+    pub dedupe: Dedupe,
+    pub alt_metadata: AltMetadata,
+    pub quality: Quality,
+    pub strict_quality: bool,
+    pub prefer: Vec<AudioFormat>,
+    pub codec: Codec,
+    pub overwrite: Overwrite,
+    // Rewrite the tags of existing files that are kept with the current metadata
+    pub refresh_tags: bool,
+    pub verify_existing: bool,
+    pub fail_fast: bool,
+    pub jobs: usize,
+    pub write_jobs: usize,
+    pub summary_only: bool,
+    // Number of times -v was given, each showing more details
+    pub verbose: u8,
+    // Only warnings and errors are printed, on stderr
+    pub quiet: bool,
+    // Every message is appended to this file as well, with a timestamp
+    pub log_file: Option<path::PathBuf>,
+    pub progress: bool,
+    pub list_inputs: bool,
+    pub export_beets: Option<path::PathBuf>,
+    pub export_metadata: Option<MetadataFormat>,
+    pub stream: bool,
+    pub radio: Vec<String>,
+    pub benchmark: Option<usize>,
+    pub connect_timeout: stdtime::Duration,
+    // Used for the access point connection as well as every http request, audio included
+    pub proxy: Option<url::Url>,
+    pub connect_retries: u32,
+    pub track_timeout: Option<stdtime::Duration>,
+    // Bytes per second all downloads together are limited to
+    pub limit_rate: Option<u64>,
+    pub total_timeout: Option<stdtime::Duration>,
+    pub retry: RetryPolicy,
+    pub temp_dir: path::PathBuf,
+    pub stage_to_disk: bool,
+    pub decrypt_only: bool,
+    pub skip_space_check: bool,
+    pub resume_partial: bool,
+    pub resolve: ResolveOptions,
+    pub tags: TagOptions,
+    pub save_metadata_json: bool,
+    // File name of the album cover written into every folder tracks are downloaded to
+    pub save_cover: Option<String>,
+    pub save_cover_size: CoverSize,
+    pub artist_index: bool,
+    // Folder the .m3u8 files of playlist inputs are written to
+    pub m3u8_dir: Option<path::PathBuf>,
+    pub report: Option<path::PathBuf>,
+    pub post_hook: Option<String>,
+    // Processes the inputs again after every interval, for rippify watch
+    pub watch_every: Option<stdtime::Duration>,
+    pub input: Vec<String>,
+}
+
+pub enum ResourceKind {
+    Track,
+    Playlist,
+    Album,
+    Artist,
+    Radio,
+    Episode,
+    Show,
+    Collection,
+}
+
+impl fmt::Display for ResourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResourceKind::Track => write!(f, "track"),
+            ResourceKind::Playlist => write!(f, "playlist"),
+            ResourceKind::Album => write!(f, "album"),
+            ResourceKind::Artist => write!(f, "artist"),
+            ResourceKind::Radio => write!(f, "radio"),
+            ResourceKind::Episode => write!(f, "episode"),
+            ResourceKind::Show => write!(f, "show"),
+            ResourceKind::Collection => write!(f, "collection"),
+        }
+    }
+}
+
+impl ResourceKind {
+    fn to_url_regex(&self) -> regex::Regex {
+        regex::Regex::new(&format!(
+            r"^(http(s)?://)?open\.spotify\.com/{}/([[:alnum:]]{{22}})$",
+            self
+        ))
+        .unwrap()
+    }
+
+    fn to_uri_regex(&self) -> regex::Regex {
+        regex::Regex::new(&format!(r"^spotify:{}:([[:alnum:]]{{22}})$", self)).unwrap()
+    }
+}
+
+static VARIOUS_ARTISTS: &str = "Various Artists";
+
+// What {source} evaluates to for tracks given directly rather than through a playlist, album or artist
+static TRACKS_SOURCE: &str = "Tracks";
+
+// Same for podcast episodes given directly rather than through a show or playlist
+static EPISODES_SOURCE: &str = "Episodes";
+
+// And for the liked songs and saved albums of the logged in account
+static COLLECTION_SOURCE: &str = "Liked Songs";
+
+#[derive(Clone, clap::ValueEnum)]
+pub enum AltMetadata {
+    Requested,
+    Alternative,
+}
+
+// Recordings released several times show up under different track ids, so duplicates are told apart by the isrc of
+// the recording or, for fuzzy, by the main artist, title and duration to the nearest two seconds
+#[derive(Clone, PartialEq, clap::ValueEnum)]
+pub enum Dedupe {
+    None,
+    Isrc,
+    Fuzzy,
+}
+
+impl Dedupe {
+    fn key(&self, track: &lsm::Track) -> Option<String> {
+        let normalize = |value: &str| -> String {
+            value
+                .nfc()
+                .flat_map(char::to_lowercase)
+                .filter(|c| c.is_alphanumeric())
+                .collect()
+        };
+
+        match self {
+            Dedupe::None => None,
+            Dedupe::Isrc => track_isrc(track).map(|isrc| isrc.to_uppercase()),
+            Dedupe::Fuzzy => Some(format!(
+                "{}|{}|{}",
+                normalize(track.artists.first().map_or("", |artist| artist.name.as_str())),
+                normalize(&track_title(track, true)),
+                (track.duration + 1000) / 2000
+            )),
+        }
+    }
+}
+
+// Groups spotify sorts the releases of an artist into, appears_on being the releases of others they're featured on
+#[derive(Clone, PartialEq, clap::ValueEnum)]
+pub enum ArtistGroup {
+    Albums,
+    Singles,
+    Compilations,
+    #[value(name = "appears_on", alias = "appears-on")]
+    AppearsOn,
+}
+
+#[derive(Clone, clap::ValueEnum)]
+pub enum CompilationTag {
+    Auto,
+    Always,
+    Never,
+}
+
+impl CompilationTag {
+    fn is_compilation(&self, track: &lsm::Track) -> bool {
+        match self {
+            CompilationTag::Auto => {
+                track.album.album_type == lsm::album::AlbumType::COMPILATION
+                    || track.album.artists.iter().any(|artist| artist.name == VARIOUS_ARTISTS)
+            }
+            CompilationTag::Always => true,
+            CompilationTag::Never => false,
+        }
+    }
+}
+
+#[derive(Clone, clap::ValueEnum)]
+pub enum CoverSize {
+    Small,
+    Medium,
+    Large,
+    Original,
+}
+
+// Orders the image sizes spotify offers, from 64px up to the original upload when it's available
+fn cover_rank(size: &lsm::image::ImageSize) -> u8 {
+    match size {
+        lsm::image::ImageSize::SMALL => 0,
+        lsm::image::ImageSize::DEFAULT => 1,
+        lsm::image::ImageSize::LARGE => 2,
+        lsm::image::ImageSize::XLARGE => 3,
+    }
+}
+
+impl CoverSize {
+    // Falls back to the largest image when the requested size isn't offered for an album
+    fn select<'a>(&self, covers: &'a [lsm::image::Image]) -> Option<&'a lsm::image::Image> {
+        let largest = covers.iter().max_by_key(|cover| (cover_rank(&cover.size), cover.width));
+
+        let rank = match self {
+            CoverSize::Small => 0,
+            CoverSize::Medium => 1,
+            CoverSize::Large => 2,
+            CoverSize::Original => return largest,
+        };
+
+        covers.iter().find(|cover| cover_rank(&cover.size) == rank).or(largest)
+    }
+}
+
+#[derive(Clone)]
+struct Cover {
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+pub struct InputResource {
+    pub kind: ResourceKind,
+    pub id: lsc::SpotifyId,
+}
+
+impl InputResource {
+    // The collection belongs to whoever is logged in and has no id of its own
+    fn name(&self) -> String {
+        match self.kind {
+            ResourceKind::Collection => String::from("liked songs and saved albums"),
+            _ => self.id.to_base62().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Resolution {
+    // Tracks from several inputs are only downloaded once, under the first input they were found in. Kept in input
+    // order, so tracks are downloaded and logged in the order they were given in
+    tracks: indexmap::IndexMap<lsc::SpotifyId, TrackJob>,
+    // Every input that could be resolved, described by its kind and id, with its number of tracks
+    counts: Vec<(String, usize)>,
+    num_other_disc: usize,
+    num_added_before: usize,
+    num_synced_before: usize,
+    num_teasers: usize,
+    synced_playlists: Vec<(String, Vec<String>)>,
+    // Name and items of every playlist input in playlist order, kept for --m3u8
+    playlists: Vec<(String, Vec<String>)>,
+}
+
+// Hands tracks to the download loop as soon as they're resolved for --stream, artists are passed on album by album
+struct TrackSink {
+    tracks: tokio::sync::mpsc::UnboundedSender<TrackJob>,
+    sent: sync::Mutex<coll::HashSet<lsc::SpotifyId>>,
+}
+
+impl TrackSink {
+    fn new(tracks: tokio::sync::mpsc::UnboundedSender<TrackJob>) -> Self {
+        TrackSink {
+            tracks,
+            sent: sync::Mutex::default(),
+        }
+    }
+
+    fn send(&self, tracks: &[(lsc::SpotifyId, usize)], source: &str) {
+        let mut sent = self.sent.lock().unwrap();
+
+        for (track_id, position) in tracks {
+            if sent.insert(*track_id) {
+                // Nothing is listening anymore once the run is aborted, which doesn't need handling here
+                self.tracks
+                    .send(TrackJob {
+                        id: *track_id,
+                        source: source.to_owned(),
+                        position: *position,
+                    })
+                    .ok();
+            }
+        }
+    }
+}
+
+async fn resolve_inputs(
+    session: &lsc::Session,
+    albums: &AlbumCache,
+    inputs: &[InputResource],
+    opts: &UserParams,
+    sink: Option<&TrackSink>,
+) -> Result<Resolution, Error> {
+    let mut resolution = Resolution::default();
+
+    for res in inputs {
+        match res.get_tracks(session, albums, &opts.resolve, sink).await {
+            Ok(resolved) => {
+                let positions = resolved.positions();
+
+                if let Some(sink) = sink {
+                    sink.send(&positions, &resolved.source);
+                }
+
+                resolution
+                    .counts
+                    .push((format!("{} {}", res.kind, res.name()), resolved.tracks.len()));
+
+                for (track_id, position) in positions {
+                    resolution.tracks.entry(track_id).or_insert_with(|| TrackJob {
+                        id: track_id,
+                        source: resolved.source.clone(),
+                        position,
+                    });
+                }
+
+                resolution.num_other_disc += resolved.num_other_disc;
+                resolution.num_added_before += resolved.num_added_before;
+                resolution.num_synced_before += resolved.num_synced_before;
+                resolution.num_teasers += resolved.num_teasers;
+
+                if let Some(items) = resolved.items {
+                    resolution.playlists.push((resolved.source.clone(), items));
+                }
+
+                if let Some(members) = resolved.members {
+                    resolution
+                        .synced_playlists
+                        .push((res.id.to_base62().unwrap_or_default(), members));
+                }
+            }
+            Err(err) if matches!(res.kind, ResourceKind::Playlist) && is_access_denied(&err) => {
+                warn!(
+                    "{}: playlist {} is private or not accessible by this account, skipping...",
+                    "warning".yellow().bold(),
+                    res.id.to_base62().unwrap()
+                );
+                fail_fast(opts)?;
+            }
+            Err(err) => {
+                warn!(
+                    "{}: cannot get metadata for {} {}: {}, skipping...",
+                    "warning".yellow().bold(),
+                    res.kind,
+                    res.name(),
+                    err
+                );
+                fail_fast(opts)?;
+            }
+        }
+    }
+
+    Ok(resolution)
+}
+
+#[derive(Default)]
+struct ResolvedTracks {
+    tracks: Vec<lsc::SpotifyId>,
+    num_other_disc: usize,
+    num_added_before: usize,
+    num_synced_before: usize,
+    num_teasers: usize,
+    // Every track of a playlist when --sync-state is given, remembered for the next sync
+    members: Option<Vec<String>>,
+    // Every item of a playlist in order, including the ones skipped by --added-after or --sync-state
+    items: Option<Vec<String>>,
+    // Name of the input the tracks were resolved from, used for the {source} specifier
+    source: String,
+}
+
+// 1-based positions of tracks that follow the first offset ones of their input
+fn numbered(tracks: &[lsc::SpotifyId], offset: usize) -> Vec<(lsc::SpotifyId, usize)> {
+    tracks
+        .iter()
+        .enumerate()
+        .map(|(index, track_id)| (*track_id, offset + index + 1))
+        .collect()
+}
+
+impl ResolvedTracks {
+    // Playlist tracks keep their position in the playlist, even when the ones before them were skipped
+    fn positions(&self) -> Vec<(lsc::SpotifyId, usize)> {
+        let Some(items) = &self.items else {
+            return numbered(&self.tracks, 0);
+        };
+
+        let mut item_positions = coll::HashMap::<&str, usize>::new();
+
+        for (index, item_id) in items.iter().enumerate() {
+            item_positions.entry(item_id.as_str()).or_insert(index + 1);
+        }
+
+        self.tracks
+            .iter()
+            .map(|track_id| {
+                let item_id = track_id.to_base62().unwrap_or_default();
+                (
+                    *track_id,
+                    item_positions.get(item_id.as_str()).copied().unwrap_or_default(),
+                )
+            })
+            .collect()
+    }
+
+    fn extend(&mut self, other: ResolvedTracks) {
+        self.tracks.extend(other.tracks);
+        self.num_other_disc += other.num_other_disc;
+        self.num_added_before += other.num_added_before;
+        self.num_synced_before += other.num_synced_before;
+        self.num_teasers += other.num_teasers;
+    }
+}
+
+impl InputResource {
+    #[async_recursion]
+    async fn get_tracks(
+        &self,
+        session: &lsc::Session,
+        albums: &AlbumCache,
+        resolve: &ResolveOptions,
+        sink: Option<&TrackSink>,
+    ) -> Result<ResolvedTracks, librespot_core::error::Error> {
+        let mut resolved = ResolvedTracks::default();
+
+        match self.kind {
+            ResourceKind::Track => {
+                resolved.source = String::from(TRACKS_SOURCE);
+                resolved.tracks.push(self.id);
+            }
+            ResourceKind::Playlist => {
+                let playlist = lsm::Playlist::get(session, &self.id).await?;
+                resolved.source = playlist.name().to_owned();
+
+                if playlist.contents.items.is_empty() {
+                    self.report_empty(&resolved.source);
+                }
+
+                let synced = resolve
+                    .sync_state
+                    .as_ref()
+                    .map(|state| state.get(&self.id.to_base62().unwrap_or_default()));
+
+                for item in playlist.contents.items.iter() {
+                    let item_id = item.id.to_base62().unwrap_or_default();
+                    resolved.items.get_or_insert_with(Vec::new).push(item_id.clone());
+
+                    if synced.flatten().map_or(false, |members| members.contains(&item_id)) {
+                        resolved.num_synced_before += 1;
+                    } else if resolve
+                        .added_after
+                        .map_or(true, |date| item.attributes.timestamp.date() > date)
+                    {
+                        resolved.tracks.push(item.id);
+                    } else {
+                        resolved.num_added_before += 1;
+                    }
+
+                    if synced.is_some() {
+                        resolved.members.get_or_insert_with(Vec::new).push(item_id);
+                    }
+                }
+
+                if let Some(Some(_)) = synced {
+                    warn!(
+                        "{}: playlist {} has {} new tracks since last sync",
+                        " -> note".bright_blue().bold(),
+                        resolved.source.bold(),
+                        resolved.tracks.len() + resolved.num_added_before
+                    );
+                }
+            }
+            ResourceKind::Album => {
+                let album = albums.get(session, &self.id).await?;
+                resolved.source = album.name.clone();
+
+                if album.tracks().next().is_none() {
+                    self.report_empty(&resolved.source);
+                }
+
+                for disc in album.discs.iter() {
+                    if resolve.disc.map_or(true, |number| number == disc.number) {
+                        resolved.tracks.extend(disc.tracks.iter());
+                    } else {
+                        resolved.num_other_disc += disc.tracks.len();
+                    }
+                }
+            }
+            ResourceKind::Episode => {
+                resolved.source = String::from(EPISODES_SOURCE);
+                resolved.tracks.push(self.id);
+            }
+            ResourceKind::Show => {
+                let show = lsm::Show::get(session, &self.id).await?;
+                resolved.source = show.name.clone();
+
+                if show.episodes.is_empty() {
+                    self.report_empty(&resolved.source);
+                }
+
+                for episode_id in show.episodes.iter() {
+                    let episode_id = lsc::SpotifyId {
+                        item_type: lsc::spotify_id::SpotifyItemType::Episode,
+                        ..*episode_id
+                    };
+
+                    // Shows only list their episode ids, so the duration takes the metadata of every episode. Episodes
+                    // without metadata are kept, to fail with a proper error once they're downloaded
+                    if let Some(secs) = resolve.skip_shorter_than {
+                        if let Ok(episode) = lsm::Episode::get(session, &episode_id).await {
+                            if i64::from(episode.duration) < i64::from(secs) * 1000 {
+                                resolved.num_teasers += 1;
+                                continue;
+                            }
+                        }
+                    }
+
+                    resolved.tracks.push(episode_id);
+                }
+            }
+            ResourceKind::Radio => {
+                let response = session.spclient().get_radio_for_track(&self.id).await?;
+                let radio: serde_json::Value =
+                    serde_json::from_slice(&response).map_err(librespot_core::error::Error::failed_precondition)?;
+
+                let playlist_id = radio["mediaItems"][0]["uri"]
+                    .as_str()
+                    .ok_or_else(|| librespot_core::error::Error::not_found("spotify has no radio for this seed"))?;
+                let playlist = lsm::Playlist::get(session, &lsc::SpotifyId::from_uri(playlist_id)?).await?;
+                resolved.source = playlist.name().to_owned();
+
+                if playlist.contents.items.is_empty() {
+                    self.report_empty(&resolved.source);
+                }
+
+                resolved.tracks.extend(
+                    playlist
+                        .contents
+                        .items
+                        .iter()
+                        .take(resolve.radio_count)
+                        .map(|item| item.id),
+                );
+            }
+            ResourceKind::Collection => {
+                resolved.source = String::from(COLLECTION_SOURCE);
+                resolved.tracks = get_saved_items(session, "track").await?;
+
+                let saved_albums = get_saved_items(session, "album").await?;
+
+                if resolved.tracks.is_empty() && saved_albums.is_empty() {
+                    self.report_empty(&resolved.source);
+                }
+
+                if let Some(sink) = sink {
+                    sink.send(&numbered(&resolved.tracks, 0), &resolved.source);
+                }
+
+                for album in saved_albums {
+                    let album_resolved = InputResource {
+                        kind: ResourceKind::Album,
+                        id: album,
+                    }
+                    .get_tracks(session, albums, resolve, sink)
+                    .await?;
+
+                    if let Some(sink) = sink {
+                        sink.send(
+                            &numbered(&album_resolved.tracks, resolved.tracks.len()),
+                            &resolved.source,
+                        );
+                    }
+
+                    resolved.extend(album_resolved);
+                }
+            }
+            ResourceKind::Artist => {
+                let artist = lsm::Artist::get(session, &self.id).await?;
+                resolved.source = artist.name.clone();
+
+                if resolve.artist_top_tracks_only {
+                    resolved
+                        .tracks
+                        .extend(artist.top_tracks.for_country(&session.country()).iter());
+
+                    if resolved.tracks.is_empty() {
+                        self.report_empty(&resolved.source);
+                    }
+
+                    return Ok(resolved);
+                }
+
+                let limit = resolve.artist_albums_limit.unwrap_or(usize::MAX);
+
+                // Album groups are already sorted by spotify from newest to oldest
+                let mut artist_albums: Vec<lsc::SpotifyId> = [
+                    (ArtistGroup::Albums, artist.albums),
+                    (ArtistGroup::Singles, artist.singles),
+                    (ArtistGroup::Compilations, artist.compilations),
+                    (ArtistGroup::AppearsOn, artist.appears_on),
+                ]
+                .into_iter()
+                .filter(|(group, _)| resolve.artist_include.contains(group))
+                .flat_map(|(_, album_groups)| album_groups.0.into_iter().take(limit))
+                .flat_map(|album_group| album_group.0 .0)
+                .collect();
+
+                // There's nobody to answer the prompt when input is piped in, so everything is downloaded then
+                if artist_albums.is_empty() {
+                    self.report_empty(&resolved.source);
+                } else if resolve.interactive && io::stdin().is_terminal() {
+                    artist_albums = select_albums(session, albums, artist_albums).await?;
+                }
+
+                for album in artist_albums {
+                    let album_resolved = InputResource {
+                        kind: ResourceKind::Album,
+                        id: album,
+                    }
+                    .get_tracks(session, albums, resolve, sink)
+                    .await?;
+
+                    if let Some(sink) = sink {
+                        sink.send(
+                            &numbered(&album_resolved.tracks, resolved.tracks.len()),
+                            &resolved.source,
+                        );
+                    }
+
+                    resolved.extend(album_resolved);
+                }
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    // Empty inputs aren't errors, but without a note they'd just silently contribute nothing
+    fn report_empty(&self, name: &str) {
+        warn!(
+            "{}: {} {} ({}) is empty, skipping...",
+            " -> note".bright_blue().bold(),
+            self.kind,
+            name.bold(),
+            self.name()
+        );
+    }
+}
+
+// Liked songs and saved albums are only exposed through the web API, which hands them out a page at a time
+async fn get_saved_items(
+    session: &lsc::Session,
+    kind: &str,
+) -> Result<Vec<lsc::SpotifyId>, librespot_core::error::Error> {
+    let token = session.token_provider().get_token("user-library-read").await?;
+
+    let mut items = Vec::new();
+    let mut next = Some(format!("https://api.spotify.com/v1/me/{}s?limit=50", kind));
+
+    while let Some(url) = next {
+        let request = hyper::Request::get(url)
+            .header("Authorization", format!("Bearer {}", token.access_token))
+            .body(hyper::Body::empty())
+            .map_err(librespot_core::error::Error::failed_precondition)?;
+
+        let response = session.http_client().request_body(request).await?;
+        let page: serde_json::Value =
+            serde_json::from_slice(&response).map_err(librespot_core::error::Error::failed_precondition)?;
+
+        // Local files saved to the library have no id, there's nothing to download for them
+        for item in page["items"].as_array().into_iter().flatten() {
+            if let Some(id) = item[kind]["id"].as_str() {
+                items.push(lsc::SpotifyId::from_base62(id)?);
+            }
+        }
+
+        next = page["next"].as_str().map(str::to_owned);
+    }
+
+    Ok(items)
+}
+
+async fn select_albums(
+    session: &lsc::Session,
+    albums: &AlbumCache,
+    artist_albums: Vec<lsc::SpotifyId>,
+) -> Result<Vec<lsc::SpotifyId>, librespot_core::error::Error> {
+    let mut choices = Vec::with_capacity(artist_albums.len());
+
+    for id in &artist_albums {
+        let album = albums.get(session, id).await?;
+
+        choices.push(format!(
+            "{} ({}, {} tracks)",
+            album.name,
+            album.date.year(),
+            album.tracks().count()
+        ));
+    }
+
+    let selected = prompt_selection(
+        "\n",
+        &choices,
+        "albums to download, as numbers or ranges like 1,3-5, or nothing for all of them",
+    );
+
+    Ok(match selected {
+        Some(selected) => selected.into_iter().map(|index| artist_albums[index]).collect(),
+        None => artist_albums,
+    })
+}
+
+// Asks which of the numbered choices to take, None standing for an empty answer. A closed stdin can't answer
+// either, so it's taken the same way. The prompt goes to stderr like the warnings, which keeps it out of what
+// --stdout writes, and the console is held until the answer so concurrent tracks can't write their log in between
+fn prompt_selection(header: &str, choices: &[String], question: &str) -> Option<Vec<usize>> {
+    let mut stdout = io::stdout().lock();
+    let mut stderr = io::stderr().lock();
+    stdout.flush().ok();
+
+    let mut listing = String::from(header);
+
+    for (num_choice, choice) in choices.iter().enumerate() {
+        listing += &format!(
+            " {} {}: {}\n",
+            "->".yellow().bold(),
+            (num_choice + 1).to_string().bold(),
+            choice
+        );
+    }
+
+    record(tracing::Level::INFO, &listing);
+    stderr.write_all(listing.as_bytes()).ok();
+
+    loop {
+        write!(stderr, " {} {}: ", "=>".green().bold(), question).ok();
+        stderr.flush().ok();
+
+        let mut line = String::new();
+
+        if io::stdin().read_line(&mut line).unwrap_or(0) == 0 || line.trim().is_empty() {
+            return None;
+        }
+
+        match parse_selection(line.trim(), choices.len()) {
+            Some(selected) => {
+                record(tracing::Level::INFO, &format!("selected {}", line.trim()));
+                return Some(selected);
+            }
+            None => {
+                let warning = format!(
+                    " {} {}: expected numbers between 1 and {}, try again\n",
+                    "->".yellow().bold(),
+                    "warning".yellow().bold(),
+                    choices.len()
+                );
+
+                record(tracing::Level::WARN, &warning);
+                stderr.write_all(warning.as_bytes()).ok();
+            }
+        }
+    }
+}
+
+// Inputs like search:"daft punk discovery" stand for what spotify finds for the query, with or without the quotes
+fn search_query(line: &str) -> Option<&str> {
+    line.strip_prefix("search:")
+        .map(|query| query.trim().trim_matches('"').trim())
+}
+
+static SEARCH_LIMIT: usize = 5;
+
+#[derive(serde::Deserialize)]
+struct SearchResponse {
+    tracks: Option<SearchPage>,
+    albums: Option<SearchPage>,
+}
+
+#[derive(serde::Deserialize)]
+struct SearchPage {
+    items: Vec<SearchItem>,
+}
+
+#[derive(serde::Deserialize)]
+struct SearchItem {
+    uri: String,
+    name: String,
+    artists: Vec<SearchArtist>,
+}
+
+#[derive(serde::Deserialize)]
+struct SearchArtist {
+    name: String,
+}
+
+// The top tracks and albums for a query as their URIs, with a description to pick them by
+async fn search_matches(
+    session: &lsc::Session,
+    query: &str,
+) -> Result<Vec<(String, String)>, librespot_core::error::Error> {
+    let token = session.token_provider().get_token("user-read-private").await?;
+
+    let limit = SEARCH_LIMIT.to_string();
+    let url = url::Url::parse_with_params(
+        "https://api.spotify.com/v1/search",
+        &[("q", query), ("type", "track,album"), ("limit", &limit)],
+    )
+    .map_err(librespot_core::error::Error::failed_precondition)?;
+
+    let request = hyper::Request::get(url.as_str())
+        .header("Authorization", format!("Bearer {}", token.access_token))
+        .body(hyper::Body::empty())
+        .map_err(librespot_core::error::Error::failed_precondition)?;
+
+    let response = session.http_client().request_body(request).await?;
+    let response: SearchResponse =
+        serde_json::from_slice(&response).map_err(librespot_core::error::Error::failed_precondition)?;
+
+    let matches = [
+        (ResourceKind::Track, response.tracks),
+        (ResourceKind::Album, response.albums),
+    ]
+    .into_iter()
+    .flat_map(|(kind, page)| {
+        page.into_iter().flat_map(|page| page.items).map(move |item| {
+            let artists: Vec<_> = item.artists.iter().map(|artist| artist.name.as_str()).collect();
+            let description = format!("{}: {} by {}", kind, item.name, artists.join(", "));
+            (item.uri, description)
+        })
+    })
+    .collect();
+
+    Ok(matches)
+}
+
+// There's nobody to ask when input is piped in, so the best match is taken then, like with --first
+async fn search_input(session: &lsc::Session, query: &str, opts: &UserParams) -> Result<Vec<String>, Error> {
+    let matches = match search_matches(session, query).await {
+        Ok(matches) => matches,
+        Err(err) => {
+            warn!(
+                "{}: cannot search for {}: {}, skipping...",
+                " -> warning".yellow().bold(),
+                query.bold(),
+                err
+            );
+            fail_fast(opts)?;
+            return Ok(Vec::new());
+        }
+    };
+
+    let Some((first, description)) = matches.first() else {
+        warn!(
+            "{}: nothing found for {}, skipping...",
+            " -> note".bright_blue().bold(),
+            query.bold()
+        );
+        return Ok(Vec::new());
+    };
+
+    if opts.resolve.search_first || !io::stdin().is_terminal() {
+        info!(" {} search {}: {}", "->".yellow().bold(), query.bold(), description);
+        return Ok(vec![first.clone()]);
+    }
+
+    Ok(select_search_matches(query, &matches))
+}
+
+fn select_search_matches(query: &str, matches: &[(String, String)]) -> Vec<String> {
+    let choices: Vec<_> = matches.iter().map(|(_, description)| description.clone()).collect();
+
+    let selected = prompt_selection(
+        &format!("\n {} Matches for {}:\n", "=>".green().bold(), query.bold()),
+        &choices,
+        "matches to download, as numbers or ranges like 1,3-5, or nothing for the first one",
+    );
+
+    match selected {
+        Some(selected) => selected.into_iter().map(|index| matches[index].0.clone()).collect(),
+        None => vec![matches[0].0.clone()],
+    }
+}
+
+// Turns a selection like 1,3-5 into the zero based indices it refers to, keeping the order and skipping repeats
+fn parse_selection(selection: &str, len: usize) -> Option<Vec<usize>> {
+    let mut selected = Vec::<usize>::new();
+
+    for part in selection.split(',').map(str::trim) {
+        let (start, end) = match part.split_once('-') {
+            Some((start, end)) => (start.trim().parse::<usize>().ok()?, end.trim().parse::<usize>().ok()?),
+            None => (part.parse::<usize>().ok()?, part.parse::<usize>().ok()?),
+        };
+
+        if start == 0 || start > end || end > len {
+            return None;
+        }
+
+        for index in start - 1..end {
+            if !selected.contains(&index) {
+                selected.push(index);
+            }
+        }
+    }
+
+    Some(selected)
+}
+
+// Spotify answers requests for private playlists of other users either as unauthorized or as not found, so both
+// are treated the same. Depending on where the request failed the status code is only kept in the wrapped http error
+fn is_access_denied(err: &librespot_core::error::Error) -> bool {
+    use librespot_core::error::ErrorKind;
+    use librespot_core::http_client::HttpClientError;
+
+    match err.kind {
+        ErrorKind::PermissionDenied | ErrorKind::Unauthenticated | ErrorKind::NotFound => true,
+        _ => matches!(
+            err.error.downcast_ref::<HttpClientError>(),
+            Some(HttpClientError::StatusCode(code)) if [401, 403, 404].contains(&code.as_u16())
+        ),
+    }
+}
+
+#[derive(Clone, serde::Deserialize)]
+struct AudioFeatures {
+    id: String,
+    tempo: f64,
+    // Pitch class of the key, -1 when spotify couldn't detect one
+    key: i32,
+    // 1 for major and 0 for minor keys
+    mode: i32,
+}
+
+static KEY_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+impl AudioFeatures {
+    fn bpm(&self) -> u16 {
+        self.tempo.round() as u16
+    }
+
+    // Written the way most dj software does, like C# for major and Am for minor keys
+    fn initial_key(&self) -> Option<String> {
+        let name = KEY_NAMES.get(usize::try_from(self.key).ok()?)?;
+
+        match self.mode {
+            0 => Some(format!("{}m", name)),
+            _ => Some(name.to_string()),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct AudioFeaturesResponse {
+    audio_features: Vec<Option<AudioFeatures>>,
+}
+
+// Most ids the audio features endpoint of the web api accepts in one request
+static AUDIO_FEATURES_BATCH: usize = 100;
+
+// Tracks without features are cached as well, so they're only asked for once
+#[derive(Default)]
+struct FeaturesCache {
+    features: sync::Mutex<coll::HashMap<String, Option<sync::Arc<AudioFeatures>>>>,
+}
+
+impl FeaturesCache {
+    async fn prefetch(&self, session: &lsc::Session, track_ids: &[lsc::SpotifyId]) {
+        let track_ids: Vec<_> = track_ids.iter().filter_map(|id| id.to_base62().ok()).collect();
+
+        for batch in track_ids.chunks(AUDIO_FEATURES_BATCH) {
+            // Tracks missing from the cache are simply asked for again on their own later
+            if let Err(err) = self.fetch(session, batch).await {
+                warn!(
+                    "{}: cannot get audio features: {}, trying again for every track...",
+                    " -> warning".yellow().bold(),
+                    err
+                );
+                return;
+            }
+        }
+    }
+
+    async fn get(
+        &self,
+        session: &lsc::Session,
+        track_id: &lsc::SpotifyId,
+    ) -> Result<Option<sync::Arc<AudioFeatures>>, librespot_core::error::Error> {
+        let track_id = track_id.to_base62()?;
+
+        if let Some(features) = self.features.lock().unwrap().get(&track_id) {
+            return Ok(features.clone());
+        }
+
+        self.fetch(session, &[track_id.clone()]).await?;
+        Ok(self.features.lock().unwrap().get(&track_id).cloned().flatten())
+    }
+
+    async fn fetch(&self, session: &lsc::Session, track_ids: &[String]) -> Result<(), librespot_core::error::Error> {
+        let token = session.token_provider().get_token("user-read-private").await?;
+
+        let request = hyper::Request::get(format!(
+            "https://api.spotify.com/v1/audio-features?ids={}",
+            track_ids.join(",")
+        ))
+        .header("Authorization", format!("Bearer {}", token.access_token))
+        .body(hyper::Body::empty())
+        .map_err(librespot_core::error::Error::failed_precondition)?;
+
+        let response = session.http_client().request_body(request).await?;
+        let response: AudioFeaturesResponse =
+            serde_json::from_slice(&response).map_err(librespot_core::error::Error::failed_precondition)?;
+
+        let mut features = self.features.lock().unwrap();
+
+        for track_id in track_ids {
+            features.entry(track_id.clone()).or_insert(None);
+        }
+
+        for track_features in response.audio_features.into_iter().flatten() {
+            features.insert(track_features.id.clone(), Some(sync::Arc::new(track_features)));
+        }
+
+        Ok(())
+    }
+}
+
+// Album metadata is needed once per track for tagging, so every album is only fetched once per run. Each entry
+// gets its own cell so concurrent requests for the same album wait on a single fetch instead of repeating it.
+// Track metadata is kept the same way, so the downloads reuse what the space estimate already requested
+#[derive(Default)]
+struct AlbumCache {
+    albums: sync::Mutex<coll::HashMap<lsc::SpotifyId, sync::Arc<tokio::sync::OnceCell<sync::Arc<lsm::Album>>>>>,
+    tracks: sync::Mutex<coll::HashMap<lsc::SpotifyId, sync::Arc<tokio::sync::OnceCell<sync::Arc<lsm::Track>>>>>,
+    covers: sync::Mutex<coll::HashMap<lsc::SpotifyId, sync::Arc<tokio::sync::OnceCell<sync::Arc<Cover>>>>>,
+}
+
+impl AlbumCache {
+    async fn get(
+        &self,
+        session: &lsc::Session,
+        id: &lsc::SpotifyId,
+    ) -> Result<sync::Arc<lsm::Album>, librespot_core::error::Error> {
+        let cell = self.albums.lock().unwrap().entry(*id).or_default().clone();
+
+        cell.get_or_try_init(|| async { lsm::Album::get(session, id).await.map(sync::Arc::new) })
+            .await
+            .cloned()
+    }
+
+    async fn get_track(
+        &self,
+        session: &lsc::Session,
+        id: &lsc::SpotifyId,
+    ) -> Result<sync::Arc<lsm::Track>, librespot_core::error::Error> {
+        let cell = self.tracks.lock().unwrap().entry(*id).or_default().clone();
+
+        cell.get_or_try_init(|| async { lsm::Track::get(session, id).await.map(sync::Arc::new) })
+            .await
+            .cloned()
+    }
+
+    // Covers are cached per album as well, so the image is only downloaded once for all of its tracks
+    async fn get_cover(
+        &self,
+        session: &lsc::Session,
+        album: &lsm::Album,
+        size: &CoverSize,
+    ) -> Result<sync::Arc<Cover>, librespot_core::error::Error> {
+        let cell = self.covers.lock().unwrap().entry(album.id).or_default().clone();
+
+        cell.get_or_try_init(|| async {
+            let image = size
+                .select(&album.covers)
+                .ok_or_else(|| librespot_core::error::Error::not_found("album has no cover art"))?;
+
+            download_cover(session, image).await.map(sync::Arc::new)
+        })
+        .await
+        .cloned()
+    }
+}
+
+async fn download_cover(
+    session: &lsc::Session,
+    image: &lsm::image::Image,
+) -> Result<Cover, librespot_core::error::Error> {
+    let data = session.spclient().get_image(&image.id).await?;
+
+    Ok(Cover {
+        data: data.to_vec(),
+        width: image.width as u32,
+        height: image.height as u32,
+    })
+}
+
+// Local m3u playlists are expanded into the spotify entries they contain, everything else is passed through as is
+fn expand_input_line(line: &str) -> Vec<String> {
+    let is_m3u = [".m3u", ".m3u8"].iter().any(|ext| line.to_lowercase().ends_with(ext));
+
+    if !is_m3u || !path::Path::new(line).is_file() {
+        return vec![line.to_owned()];
+    }
+
+    match fs::read_to_string(line) {
+        Ok(playlist) => playlist
+            .lines()
+            .map(|entry| entry.trim())
+            .filter(|entry| !entry.is_empty() && !entry.starts_with('#'))
+            .filter(|entry| get_resource_from_line(entry).is_ok())
+            .map(|entry| entry.to_owned())
+            .collect(),
+        Err(err) => {
+            warn!(
+                "{}: cannot read playlist file {}: {}, skipping...",
+                " -> warning".yellow().bold(),
+                line.bold(),
+                err.to_string().to_lowercase()
+            );
+            Vec::new()
+        }
+    }
+}
+
+pub fn get_resource_from_line(line: &str) -> Result<InputResource, &str> {
+    if let Some(id) = is_resource(line, ResourceKind::Track) {
+        Ok(InputResource {
+            kind: ResourceKind::Track,
+            id,
+        })
+    //
+    } else if let Some(id) = is_resource(line, ResourceKind::Album) {
+        Ok(InputResource {
+            kind: ResourceKind::Album,
+            id,
+        })
+    //
+    } else if let Some(id) = is_resource(line, ResourceKind::Playlist) {
+        Ok(InputResource {
+            kind: ResourceKind::Playlist,
+            id,
+        })
+    //
+    } else if let Some(id) = is_resource(line, ResourceKind::Artist) {
+        Ok(InputResource {
+            kind: ResourceKind::Artist,
+            id,
+        })
+    //
+    } else if let Some(mut id) = is_resource(line, ResourceKind::Episode) {
+        // Episodes are told apart from tracks by their item type once they're resolved
+        id.item_type = lsc::spotify_id::SpotifyItemType::Episode;
+        Ok(InputResource {
+            kind: ResourceKind::Episode,
+            id,
+        })
+    //
+    } else if let Some(id) = is_resource(line, ResourceKind::Show) {
+        Ok(InputResource {
+            kind: ResourceKind::Show,
+            id,
+        })
+    //
+    } else if is_collection(line) {
+        Ok(InputResource {
+            kind: ResourceKind::Collection,
+            id: lsc::SpotifyId {
+                id: 0,
+                item_type: lsc::spotify_id::SpotifyItemType::Unknown,
+            },
+        })
+    //
+    } else {
+        Err(line)
+    }
+}
+
+// Radio seeds keep their item type, spotify needs the full URI of the seed to generate its radio
+fn get_radio_seed(line: &str) -> Option<InputResource> {
+    let (mut id, item_type) = if let Some(id) = is_resource(line, ResourceKind::Track) {
+        (id, lsc::spotify_id::SpotifyItemType::Track)
+    } else if let Some(id) = is_resource(line, ResourceKind::Artist) {
+        (id, lsc::spotify_id::SpotifyItemType::Artist)
+    } else {
+        return None;
+    };
+
+    id.item_type = item_type;
+    Some(InputResource {
+        kind: ResourceKind::Radio,
+        id,
+    })
+}
+
+// Accepts the URI spotify gives the liked songs playlist, with or without the user, and the library's URL
+fn is_collection(line: &str) -> bool {
+    regex::Regex::new(
+        r"^(spotify:(user:[^:]+:)?collection(:tracks)?|(http(s)?://)?open\.spotify\.com/collection/tracks)$",
+    )
+    .unwrap()
+    .is_match(line)
+}
+
+fn is_resource(line: &str, res: ResourceKind) -> Option<lsc::SpotifyId> {
+    if let Some(captures) = res.to_url_regex().captures(line).or(res.to_uri_regex().captures(line)) {
+        let id_str = captures.iter().last().unwrap().unwrap().as_str();
+        let id = lsc::SpotifyId::from_base62(id_str).unwrap();
+
+        Some(id)
+    //
+    } else {
+        None
+    }
+}
+
+enum TrackRelation {
+    Requested,
+    Alternative,
+    Canonical,
+}
+
+impl fmt::Display for TrackRelation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackRelation::Requested => write!(f, "req."),
+            TrackRelation::Alternative => write!(f, "alt."),
+            TrackRelation::Canonical => write!(f, "canon."),
+        }
+    }
+}
+
+// Free accounts can only stream up to 160kbps, asking for more just gets the audio key denied
+static FREE_MAX_BITRATE: u32 = 160;
+
+// The account type only arrives in its own packet shortly after logging in
+static ACCOUNT_TYPE_POLLS: usize = 20;
+static ACCOUNT_TYPE_POLL_INTERVAL: stdtime::Duration = stdtime::Duration::from_millis(100);
+
+// Returns the highest bitrate the account is allowed to download, if it's limited at all. Premium is assumed when
+// the account type can't be found out, as that's what rippify is meant to be used with
+async fn account_max_bitrate(session: &lsc::Session) -> Option<u32> {
+    for _ in 0..ACCOUNT_TYPE_POLLS {
+        match session.get_user_attribute("type").as_deref() {
+            Some("premium") => return None,
+            Some(_) => return Some(FREE_MAX_BITRATE),
+            None => time::sleep(ACCOUNT_TYPE_POLL_INTERVAL).await,
+        }
+    }
+
+    debug!(
+        "{} account type not received after {}ms, assuming premium and not limiting the bitrate",
+        "=>".green().bold(),
+        ACCOUNT_TYPE_POLLS as u128 * ACCOUNT_TYPE_POLL_INTERVAL.as_millis()
+    );
+
+    None
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum AudioContainer {
+    Ogg,
+    Mp4,
+}
+
+impl AudioContainer {
+    fn extension(&self) -> &'static str {
+        match self {
+            AudioContainer::Ogg => "ogg",
+            AudioContainer::Mp4 => "m4a",
+        }
+    }
+
+    // Codec name used for the formats given to --prefer
+    fn codec(&self) -> &'static str {
+        match self {
+            AudioContainer::Ogg => "ogg",
+            AudioContainer::Mp4 => "aac",
+        }
+    }
+
+    // Spotify prepends its own header to ogg files, which has to be skipped for the stream to be playable
+    fn has_header(&self) -> bool {
+        match self {
+            AudioContainer::Ogg => true,
+            AudioContainer::Mp4 => false,
+        }
+    }
+}
+
+static OGG_CAPTURE_PATTERN: &[u8; 4] = b"OggS";
+
+// Length of an ogg page header up to the segment table, the number of segments being its last byte
+static OGG_PAGE_HEADER_LEN: usize = 27;
+
+// The header spotify prepends is an ogg page of its own, so its length is read from the page rather than assumed.
+// The vorbis stream has to start right after it, anything else would only be written out as a broken file. Leaves
+// the input at the first byte of the vorbis stream, malformed headers are reported as invalid data
+fn read_audio_header(input: &mut (impl io::Read + io::Seek)) -> io::Result<Vec<u8>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    let mut header = vec![0u8; OGG_PAGE_HEADER_LEN];
+    input.read_exact(&mut header)?;
+
+    if &header[..4] != OGG_CAPTURE_PATTERN {
+        return Err(invalid(String::from(
+            "no spotify header page at the start of the audio",
+        )));
+    }
+
+    if header[4] != 0 {
+        return Err(invalid(format!(
+            "unsupported ogg version {} in the spotify header",
+            header[4]
+        )));
+    }
+
+    let segments = header[OGG_PAGE_HEADER_LEN - 1] as usize;
+    header.resize(OGG_PAGE_HEADER_LEN + segments, 0);
+    input.read_exact(&mut header[OGG_PAGE_HEADER_LEN..])?;
+
+    let body_len: usize = header[OGG_PAGE_HEADER_LEN..].iter().map(|&len| len as usize).sum();
+    let body_start = header.len();
+    header.resize(body_start + body_len, 0);
+    input.read_exact(&mut header[body_start..])?;
+
+    let mut capture = [0u8; 4];
+
+    match input.read_exact(&mut capture) {
+        Ok(()) if &capture == OGG_CAPTURE_PATTERN => {}
+        Ok(()) => {
+            return Err(invalid(format!(
+                "no ogg page after the spotify header at {} bytes",
+                header.len()
+            )))
+        }
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(invalid(String::from("no audio after the spotify header")))
+        }
+        Err(err) => return Err(err),
+    }
+
+    input.seek(io::SeekFrom::Start(header.len() as u64))?;
+
+    Ok(header)
+}
+
+// A malformed header won't get better by downloading again, unlike a read that failed along the way
+fn header_error(err: io::Error) -> TrackDownloadError {
+    let kind = match err.kind() {
+        io::ErrorKind::InvalidData => TrackDownloadErrorKind::Header,
+        _ => TrackDownloadErrorKind::Decrypt,
+    };
+
+    ProcessError {
+        kind,
+        error: err.into(),
+    }
+}
+
+#[derive(Clone, Copy)]
+struct AudioSource {
+    file_id: lsc::FileId,
+    container: AudioContainer,
+    bitrate: u32,
+}
+
+// Formats in order of preference, with their bitrate in kbps. Some tracks are only available as aac, which is only
+// used when no vorbis file at all is available
+pub type AudioFormat = (lsm_audio::AudioFileFormat, AudioContainer, u32);
+
+static AUDIO_FORMATS: [AudioFormat; 5] = [
+    (lsm_audio::AudioFileFormat::OGG_VORBIS_320, AudioContainer::Ogg, 320),
+    (lsm_audio::AudioFileFormat::OGG_VORBIS_160, AudioContainer::Ogg, 160),
+    (lsm_audio::AudioFileFormat::OGG_VORBIS_96, AudioContainer::Ogg, 96),
+    (lsm_audio::AudioFileFormat::AAC_48, AudioContainer::Mp4, 48),
+    (lsm_audio::AudioFileFormat::AAC_24, AudioContainer::Mp4, 24),
+];
+
+// The bitrates are accepted as well, for users who think of the quality in kbps
+#[derive(Clone, clap::ValueEnum)]
+pub enum Quality {
+    #[value(alias = "320")]
+    High,
+    #[value(alias = "160")]
+    Normal,
+    #[value(alias = "96")]
+    Low,
+}
+
+impl Quality {
+    fn bitrate(&self) -> u32 {
+        match self {
+            Quality::High => 320,
+            Quality::Normal => 160,
+            Quality::Low => 96,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, clap::ValueEnum)]
+pub enum Codec {
+    Ogg,
+    Mp3,
+    Flac,
+    Opus,
+}
+
+impl Codec {
+    // Ogg keeps the audio as spotify serves it, which is aac in an m4a file for the few tracks without vorbis audio
+    fn extension(&self, container: AudioContainer) -> &'static str {
+        match self {
+            Codec::Ogg => container.extension(),
+            Codec::Mp3 => "mp3",
+            Codec::Flac => "flac",
+            Codec::Opus => "opus",
+        }
+    }
+}
+
+#[derive(Clone)]
+struct AudioSelection {
+    // Bitrate of the requested quality tier, which can be above what the account allows
+    bitrate: u32,
+    // Highest bitrate the account is allowed to download
+    limit: u32,
+    // Only accept exactly that bitrate instead of falling back to lower ones
+    strict: bool,
+    // Formats to try in order, all of them from best to worst unless --prefer was given
+    formats: Vec<AudioFormat>,
+}
+
+impl AudioSelection {
+    // Bitrate the selected audio can be downloaded at, at most
+    fn highest_bitrate(&self) -> u32 {
+        self.formats
+            .iter()
+            .map(|(_, _, bitrate)| *bitrate)
+            .filter(|bitrate| *bitrate <= self.bitrate.min(self.limit))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+pub fn parse_audio_format(name: &str) -> Result<AudioFormat, String> {
+    let format_name = |(_, container, bitrate): &AudioFormat| format!("{}{}", container.codec(), bitrate);
+
+    AUDIO_FORMATS
+        .iter()
+        .find(|format| format_name(format).eq_ignore_ascii_case(name.trim()))
+        .copied()
+        .ok_or_else(|| {
+            let names: Vec<_> = AUDIO_FORMATS.iter().map(format_name).collect();
+            format!("expected one of {}", names.join(", "))
+        })
+}
+
+fn select_audio_file(files: &lsm_audio::AudioFiles, selection: &AudioSelection) -> Option<AudioSource> {
+    selection
+        .formats
+        .iter()
+        .filter(|(_, _, bitrate)| {
+            if selection.strict {
+                *bitrate == selection.bitrate
+            } else {
+                *bitrate <= selection.bitrate
+            }
+        })
+        .filter(|(_, _, bitrate)| *bitrate <= selection.limit)
+        .find_map(|(format, container, bitrate)| {
+            files.get(format).map(|file_id| AudioSource {
+                file_id: *file_id,
+                container: *container,
+                bitrate: *bitrate,
+            })
+        })
+}
+
+async fn get_track_from_id(
+    session: &lsc::Session,
+    albums: &AlbumCache,
+    id: &lsc::SpotifyId,
+    prefer_canonical: bool,
+    selection: &AudioSelection,
+) -> Result<(lsm::Track, AudioSource, TrackRelation), librespot_core::error::Error> {
+    let mut track_ids = coll::VecDeque::<lsc::SpotifyId>::new();
+    track_ids.push_back(id.to_owned());
+
+    let lenient = AudioSelection {
+        strict: false,
+        ..selection.clone()
+    };
+
+    let mut has_other_quality = false;
+
+    while let Some(track_id) = track_ids.pop_front() {
+        let track = lsm::Track::clone(&*albums.get_track(session, &track_id).await?);
+
+        match select_audio_file(&track.files, selection) {
+            Some(source) if track_id != *id => return Ok((track, source, TrackRelation::Alternative)),
+            Some(source) if prefer_canonical => {
+                return Ok(get_canonical_track(session, albums, track, source, selection).await)
+            }
+            Some(source) => return Ok((track, source, TrackRelation::Requested)),
+            None => {
+                has_other_quality |= selection.strict && select_audio_file(&track.files, &lenient).is_some();
+
+                debug!(
+                    "   - no suitable audio file for {}, {} alternatives to try",
+                    track_id.to_base62().unwrap_or_default(),
+                    track.alternatives.0.len()
+                );
+
+                track_ids.extend(track.alternatives.0);
+            }
+        };
+    }
+
+    // Reported as unavailable rather than not found, so callers can tell it apart from a missing track
+    if has_other_quality {
+        Err(librespot_core::error::Error::unavailable(format!(
+            "not available at {}kbps",
+            selection.bitrate
+        )))
+    } else {
+        Err(librespot_core::error::Error::not_found("cannot find a suitable track"))
+    }
+}
+
+async fn get_episode_from_id(
+    session: &lsc::Session,
+    id: &lsc::SpotifyId,
+    selection: &AudioSelection,
+) -> Result<(lsm::Episode, AudioSource), librespot_core::error::Error> {
+    let episode = lsm::Episode::get(session, id).await?;
+
+    if let Some(source) = select_audio_file(&episode.audio, selection) {
+        return Ok((episode, source));
+    }
+
+    let lenient = AudioSelection {
+        strict: false,
+        ..selection.clone()
+    };
+
+    // Shows spotify doesn't host itself only come with a link to the publisher's feed, which isn't followed
+    if selection.strict && select_audio_file(&episode.audio, &lenient).is_some() {
+        Err(librespot_core::error::Error::unavailable(format!(
+            "not available at {}kbps",
+            selection.bitrate
+        )))
+    } else if !episode.external_url.is_empty() {
+        Err(librespot_core::error::Error::not_found(format!(
+            "episode is hosted outside of spotify at {}",
+            episode.external_url
+        )))
+    } else {
+        Err(librespot_core::error::Error::not_found(
+            "cannot find a suitable audio file",
+        ))
+    }
+}
+
+// Spotify doesn't expose which of the regional versions of a recording is the original one, so the most popular
+// playable version with the same isrc is assumed to be it, as regional copies rarely get any plays of their own
+async fn get_canonical_track(
+    session: &lsc::Session,
+    albums: &AlbumCache,
+    track: lsm::Track,
+    source: AudioSource,
+    selection: &AudioSelection,
+) -> (lsm::Track, AudioSource, TrackRelation) {
+    let isrc = track_isrc(&track);
+    let alternatives = track.alternatives.0.clone();
+
+    let mut canonical = (track, source, TrackRelation::Requested);
+
+    if isrc.is_none() {
+        return canonical;
+    }
+
+    for alternative_id in alternatives {
+        let Ok(alternative) = albums.get_track(session, &alternative_id).await else {
+            continue;
+        };
+        let alternative = lsm::Track::clone(&alternative);
+
+        if !same_recording(isrc.as_deref(), track_isrc(&alternative).as_deref())
+            || alternative.popularity <= canonical.0.popularity
+        {
+            continue;
+        }
+
+        if let Some(source) = select_audio_file(&alternative.files, selection) {
+            canonical = (alternative, source, TrackRelation::Canonical);
+        }
+    }
+
+    canonical
+}
+
+// Tracks without an isrc can't be told apart from a different recording, so they never match anything
+fn same_recording(isrc: Option<&str>, other: Option<&str>) -> bool {
+    isrc.is_some() && isrc == other
+}
+
+#[derive(Clone)]
+pub struct OutputFormat {
+    pub format_string: String,
+    pub number_width: usize,
+    pub replace_spaces: Option<char>,
+    pub strip_featured: bool,
+    pub sanitize: Sanitize,
+    // Takes the place of every character the file system rules of sanitize don't allow
+    pub sanitize_replacement: char,
+    // Puts the tracks of multi-disc albums into a Disc N folder next to where they'd go otherwise
+    pub disc_folders: bool,
+}
+
+// Value a specifier of the output format expands to
+enum TemplateValue {
+    // Metadata, which gets sanitized and --replace-spaces applies to
+    Text(String),
+    // Track and disc numbers, padded to --track-number-width unless a width is given
+    Number(i32),
+    Raw(String),
+}
+
+#[derive(Debug)]
+struct OutputFile {
+    dir: Option<String>,
+    file: String,
+}
+
+impl OutputFile {
+    fn in_folder(self, folder: &str) -> OutputFile {
+        let dir = self.dir.unwrap_or_default();
+        let name = &self.file[dir.len()..];
+
+        OutputFile {
+            file: format!("{}{}/{}", dir, folder, name),
+            dir: Some(format!("{}{}/", dir, folder)),
+        }
+    }
+}
+
+impl OutputFormat {
+    fn parse_output_format(
+        &self,
+        track: &lsm::Track,
+        album: Option<&lsm::Album>,
+        extension: &str,
+        source: &str,
+        position: usize,
+    ) -> OutputFile {
+        // NOTE: using the first found artist as the "main" artist
+        let author = &track.artists.first().unwrap().name;
+
+        let output_file = self.expand(|specifier| {
+            Some(match specifier {
+                "author" => TemplateValue::Text(author.clone()),
+                "artists" => TemplateValue::Text(
+                    track
+                        .artists
+                        .iter()
+                        .map(|artist| artist.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
+                "album_artist" => TemplateValue::Text(
+                    track
+                        .album
+                        .artists
+                        .first()
+                        .map_or(author, |artist| &artist.name)
+                        .clone(),
+                ),
+                "album" => TemplateValue::Text(track.album.name.clone()),
+                "name" => TemplateValue::Text(track_title(track, self.strip_featured)),
+                "source" | "playlist" => TemplateValue::Text(source.to_owned()),
+                "position" => TemplateValue::Number(position as i32),
+                "track_number" => TemplateValue::Number(track.number),
+                "disc_number" => TemplateValue::Number(track.disc_number),
+                "year" => TemplateValue::Raw(track.album.date.year().to_string()),
+                "date" => TemplateValue::Raw(track_release_date(track)),
+                "id" => TemplateValue::Raw(track.id.to_base62().unwrap_or_default()),
+                "ext" => TemplateValue::Raw(extension.to_owned()),
+                _ => return None,
+            })
+        });
+
+        match album {
+            Some(album) if self.disc_folders && album.discs.len() > 1 => {
+                output_file.in_folder(&self.component(&format!("Disc {}", track.disc_number)))
+            }
+            _ => output_file,
+        }
+    }
+
+    // Episodes have no artist or album of their own, so both {author} and {album} evaluate to the show
+    fn parse_episode_output_format(
+        &self,
+        episode: &lsm::Episode,
+        extension: &str,
+        source: &str,
+        position: usize,
+    ) -> OutputFile {
+        self.expand(|specifier| {
+            Some(match specifier {
+                "author" | "artists" | "album_artist" | "album" => TemplateValue::Text(episode.show_name.clone()),
+                "name" => TemplateValue::Text(episode.name.clone()),
+                "source" | "playlist" => TemplateValue::Text(source.to_owned()),
+                "position" => TemplateValue::Number(position as i32),
+                "track_number" => TemplateValue::Number(episode.number),
+                "disc_number" => TemplateValue::Number(1),
+                "year" => TemplateValue::Raw(episode.publish_time.year().to_string()),
+                "date" => TemplateValue::Raw(episode_release_date(episode)),
+                "id" => TemplateValue::Raw(episode.id.to_base62().unwrap_or_default()),
+                "ext" => TemplateValue::Raw(extension.to_owned()),
+                _ => return None,
+            })
+        })
+    }
+
+    // Replaces every {specifier} of the format string with its value, numbers are zero padded to WIDTH for
+    // {specifier:WIDTH}. Unknown specifiers are kept as they are
+    fn expand(&self, value: impl Fn(&str) -> Option<TemplateValue>) -> OutputFile {
+        let mut parsed = String::with_capacity(self.format_string.len());
+        let mut rest = self.format_string.as_str();
+
+        while let Some(start) = rest.find('{') {
+            parsed.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            let Some(end) = rest.find('}') else {
+                break;
+            };
+
+            let (name, width) = match rest[1..end].split_once(':') {
+                Some((name, width)) => (name, width.parse::<usize>().ok()),
+                None => (&rest[1..end], None),
+            };
+
+            match value(name) {
+                Some(TemplateValue::Text(text)) => parsed.push_str(&self.component(&text)),
+                Some(TemplateValue::Raw(text)) => parsed.push_str(&text),
+                Some(TemplateValue::Number(number)) => parsed.push_str(&format!(
+                    "{:0width$}",
+                    number,
+                    width = width.unwrap_or(self.number_width)
+                )),
+                None => parsed.push_str(&rest[..=end]),
+            }
+
+            rest = &rest[end + 1..];
+        }
+
+        parsed.push_str(rest);
+
+        OutputFile {
+            dir: parsed.rfind('/').map(|split_pos| parsed[..=split_pos].to_owned()),
+            file: parsed,
+        }
+    }
+
+    // Deepest directory every output path is placed under, taken from the format string before its first specifier
+    fn root(&self) -> path::PathBuf {
+        let fixed = &self.format_string[..self.format_string.find('{').unwrap_or(self.format_string.len())];
+
+        match fixed.rfind('/') {
+            Some(split_pos) => path::PathBuf::from(&fixed[..=split_pos]),
+            None => path::PathBuf::from("."),
+        }
+    }
+
+    // Folder the {author} specifier expands to in an output path, None when the artist isn't a folder of its own
+    fn artist_dir(&self, file: &str) -> Option<path::PathBuf> {
+        let components: Vec<_> = self.format_string.split('/').collect();
+        let depth = components.iter().position(|component| component.contains("{author}"))?;
+
+        if depth + 1 == components.len() {
+            return None;
+        }
+
+        Some(path::PathBuf::from(
+            file.split('/').take(depth + 1).collect::<Vec<_>>().join("/"),
+        ))
+    }
+
+    fn component(&self, value: &str) -> String {
+        // Metadata comes in mixed normal forms, composing it keeps the same album mapping to the same folder everywhere
+        let value: String = value.nfc().collect();
+        let value = self.sanitize.apply(&value, self.sanitize_replacement);
+
+        match self.replace_spaces {
+            Some(replacement) => value.replace(' ', &replacement.to_string()),
+            None => value,
+        }
+    }
+}
+
+// Rules for the characters of a single path component. posix only keeps out the separator, windows everything NTFS
+// and explorer reject, and strict additionally anything that isn't plain ascii, for the pickiest file systems and tools
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Sanitize {
+    Posix,
+    Windows,
+    Strict,
+}
+
+static WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+impl Sanitize {
+    // The rules of the platform rippify is running on
+    pub fn native() -> Self {
+        if cfg!(windows) {
+            Sanitize::Windows
+        } else {
+            Sanitize::Posix
+        }
+    }
+
+    pub fn allows(&self, c: char) -> bool {
+        match self {
+            Sanitize::Posix => c != '/' && c != '\0',
+            Sanitize::Windows => !c.is_control() && !"<>:\"/\\|?*".contains(c),
+            Sanitize::Strict => c.is_ascii_alphanumeric() || " -_.,'()[]&+".contains(c),
+        }
+    }
+
+    fn apply(&self, value: &str, replacement: char) -> String {
+        let mut sanitized: String = value
+            .chars()
+            .map(|c| if self.allows(c) { c } else { replacement })
+            .collect();
+
+        if *self != Sanitize::Posix {
+            // Windows silently drops trailing dots and spaces, the file would end up at another path than written
+            sanitized.truncate(sanitized.trim_end_matches(['.', ' ']).len());
+
+            // Reserved device names stay reserved with an extension, as in CON.ogg
+            let stem_len = sanitized.find('.').unwrap_or(sanitized.len());
+
+            if WINDOWS_RESERVED_NAMES
+                .iter()
+                .any(|reserved| reserved.eq_ignore_ascii_case(&sanitized[..stem_len]))
+            {
+                sanitized.insert(stem_len, '_');
+            }
+        }
+
+        // Nothing left, or a name that refers to the folder itself or its parent
+        match sanitized.as_str() {
+            "" | "." | ".." => String::from("_"),
+            _ => sanitized,
+        }
+    }
+}
+
+trait ProcessErrorKind: fmt::Display {}
+
+struct ProcessError<T: ProcessErrorKind> {
+    kind: T,
+    error: Box<dyn std::error::Error>,
+}
+
+impl<T: ProcessErrorKind> fmt::Display for ProcessError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.error)
+    }
+}
+
+enum TrackDownloadErrorKind {
+    AudioKey,
+    AudioFile,
+    TrackFile,
+    Decrypt,
+    Header,
+    Dump,
+    Timeout,
+}
+
+impl fmt::Display for TrackDownloadErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackDownloadErrorKind::AudioKey => write!(f, "cannot get audio key"),
+            TrackDownloadErrorKind::AudioFile => write!(f, "cannot get audio file"),
+            TrackDownloadErrorKind::TrackFile => write!(f, "cannot get track file audio"),
+            TrackDownloadErrorKind::Decrypt => write!(f, "cannot decrypt audio file"),
+            TrackDownloadErrorKind::Header => write!(f, "invalid audio header"),
+            TrackDownloadErrorKind::Dump => write!(f, "cannot dump audio buffers"),
+            TrackDownloadErrorKind::Timeout => write!(f, "download timed out"),
+        }
+    }
+}
+
+impl ProcessErrorKind for TrackDownloadErrorKind {}
+type TrackDownloadError = ProcessError<TrackDownloadErrorKind>;
+
+// How often the audio key and audio file of a track are requested again before the track is given up on
+#[derive(Clone)]
+pub struct RetryPolicy {
+    pub retries: usize,
+    pub delay: stdtime::Duration,
+    // How long every track pauses once spotify rate limits a request. Librespot doesn't hand out the Retry-After
+    // header of its responses, so the advised period is never known
+    pub cool_down: stdtime::Duration,
+}
+
+// End of the current rate limit pause, shared by all tracks so nobody keeps asking spotify while it lasts
+static COOL_DOWN: sync::Mutex<Option<time::Instant>> = sync::Mutex::new(None);
+
+fn is_rate_limited(err: &librespot_core::error::Error) -> bool {
+    use librespot_core::http_client::HttpClientError;
+
+    err.kind == librespot_core::error::ErrorKind::ResourceExhausted
+        || matches!(
+            err.error.downcast_ref::<HttpClientError>(),
+            Some(HttpClientError::StatusCode(code)) if code.as_u16() == 429
+        )
+}
+
+// Tracks starting while the pause lasts wait for it before their first request
+async fn wait_for_cool_down() {
+    let until = *COOL_DOWN.lock().unwrap();
+
+    if let Some(until) = until {
+        time::sleep_until(until).await;
+    }
+}
+
+// Rate limited metadata requests are made again once the pause is over instead of failing the track
+async fn with_cool_down<T, F>(retry: &RetryPolicy, request: impl Fn() -> F) -> Result<T, librespot_core::error::Error>
+where
+    F: std::future::Future<Output = Result<T, librespot_core::error::Error>>,
+{
+    let mut attempt: usize = 1;
+
+    loop {
+        match request().await {
+            Err(err) if is_rate_limited(&err) && attempt <= retry.retries => {
+                retry.cool_down().await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // The delay doubles with every retry, and gets up to half of it added at random so tracks that failed together
+    // don't all ask spotify again at the same moment
+    fn backoff(&self, attempt: usize) -> stdtime::Duration {
+        let delay = self.delay.saturating_mul(1 << (attempt - 1).min(16));
+        let jitter = std::hash::BuildHasher::hash_one(&coll::hash_map::RandomState::new(), attempt) % 1000;
+
+        delay + delay.mul_f64(jitter as f64 / 2000.0)
+    }
+
+    async fn wait(&self, error: &dyn fmt::Display, attempt: usize) {
+        let backoff = self.backoff(attempt);
+
+        warn!(
+            "   - {}: {}, retrying in {:.1}s ({}/{})...",
+            "warning".yellow().bold(),
+            error,
+            backoff.as_secs_f64(),
+            attempt,
+            self.retries
+        );
+
+        time::sleep(backoff).await;
+    }
+
+    // Only the track that starts a pause announces it, the ones running into the limit during it just join in
+    async fn cool_down(&self) {
+        let until = {
+            let mut cool_down = COOL_DOWN.lock().unwrap();
+            let now = time::Instant::now();
+
+            match *cool_down {
+                Some(until) if until > now => until,
+                _ => {
+                    warn!(
+                        "   - {}: rate limited by spotify, pausing all tracks for {}s...",
+                        "warning".yellow().bold(),
+                        self.cool_down.as_secs()
+                    );
+
+                    *cool_down = Some(now + self.cool_down);
+                    now + self.cool_down
+                }
+            }
+        };
+
+        time::sleep_until(until).await;
+    }
+}
+
+// The timeout covers the audio key, opening the file and reading it, retries included. Whatever was already written
+// to the output is discarded along with the track
+async fn track_download(
+    track_id: &lsc::SpotifyId,
+    source: &AudioSource,
+    session: &lsc::Session,
+    rate_limit: Option<&sync::Arc<RateLimit>>,
+    keep_encrypted: Option<&path::Path>,
+    opts: &UserParams,
+    output: &mut impl io::Write,
+) -> Result<Option<Normalization>, TrackDownloadError> {
+    let download = track_download_audio(track_id, source, session, rate_limit, keep_encrypted, opts, output);
+
+    match opts.track_timeout {
+        Some(limit) => time::timeout(limit, download).await.unwrap_or_else(|_| {
+            Err(ProcessError {
+                kind: TrackDownloadErrorKind::Timeout,
+                error: format!("no complete audio after {} seconds", limit.as_secs()).into(),
+            })
+        }),
+        None => download.await,
+    }
+}
+
+async fn track_download_audio(
+    track_id: &lsc::SpotifyId,
+    source: &AudioSource,
+    session: &lsc::Session,
+    rate_limit: Option<&sync::Arc<RateLimit>>,
+    keep_encrypted: Option<&path::Path>,
+    opts: &UserParams,
+    output: &mut impl io::Write,
+) -> Result<Option<Normalization>, TrackDownloadError> {
+    // Raw streams keep the header spotify puts in front of its ogg files
+    let has_header = !opts.decrypt_only && source.container.has_header();
+
+    let resume_dir = opts.resume_partial.then_some(opts.temp_dir.as_path());
+
+    let track_file_key = track_audio_key(session, track_id, source, &opts.retry).await?;
+
+    // Dumping the encrypted audio and resuming from a .part file both need the whole encrypted file at hand
+    if keep_encrypted.is_none() && resume_dir.is_none() {
+        return track_stream(
+            session,
+            rate_limit,
+            &source.file_id,
+            track_file_key,
+            has_header,
+            &opts.retry,
+            output,
+        )
+        .await;
+    }
+
+    let mut track_buffer = Vec::<u8>::new();
+
+    let mut attempt: usize = 1;
+
+    loop {
+        // A failed read can leave part of the file in the buffer, so every attempt has to start from scratch
+        track_buffer.clear();
+
+        match track_fetch(session, rate_limit, &source.file_id, resume_dir, &mut track_buffer).await {
+            Ok(()) => break,
+            Err(err) if attempt <= opts.retry.retries => {
+                opts.retry.wait(&err, attempt).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    let mut track_decrypt = lsa::AudioDecrypt::new(Some(track_file_key), io::Cursor::new(&track_buffer[..]));
+
+    if let Some(dir) = keep_encrypted {
+        let mut track_buffer_decrypted = Vec::<u8>::new();
+
+        track_decrypt
+            .read_to_end(&mut track_buffer_decrypted)
+            .map_err(|e| ProcessError {
+                kind: TrackDownloadErrorKind::Decrypt,
+                error: e.into(),
+            })?;
+
+        track_dump(dir, track_id, &track_buffer, &track_buffer_decrypted).map_err(|e| ProcessError {
+            kind: TrackDownloadErrorKind::Dump,
+            error: e.into(),
+        })?;
+
+        // The dump keeps the header either way, only the written audio goes without it
+        let header = if has_header {
+            read_audio_header(&mut io::Cursor::new(&track_buffer_decrypted)).map_err(header_error)?
+        } else {
+            Vec::new()
+        };
+
+        output
+            .write_all(&track_buffer_decrypted[header.len()..])
+            .map_err(|e| ProcessError {
+                kind: TrackDownloadErrorKind::Decrypt,
+                error: e.into(),
+            })?;
+
+        return Ok(read_spotify_normalization(&header));
+    }
+
+    let header = if has_header {
+        read_audio_header(&mut track_decrypt).map_err(header_error)?
+    } else {
+        Vec::new()
+    };
+
+    io::copy(&mut track_decrypt, output).map_err(|e| ProcessError {
+        kind: TrackDownloadErrorKind::Decrypt,
+        error: e.into(),
+    })?;
+
+    Ok(read_spotify_normalization(&header))
+}
+
+// Network hiccups are retried with a growing delay, but spotify rejecting the credentials ends the run right away
+pub async fn connect_session(opts: &UserParams) -> Result<lsc::Session, Error> {
+    let mut attempt: u32 = 0;
+    let mut backoff = CONNECT_BACKOFF;
+
+    // Every successful login replaces the cached credentials, which are reusable tokens rather than the password
+    let cache = if opts.cache_credentials {
+        match lsc::cache::Cache::new(Some(&opts.cache_dir), None, None, None) {
+            Ok(cache) => Some(cache),
+            Err(err) => {
+                warn!(
+                    "{}: cannot use credentials cache \"{}\": {}, continuing without it...",
+                    "warning".yellow().bold(),
+                    opts.cache_dir.display(),
+                    err.to_string().to_lowercase()
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let Some(credentials) = opts
+        .credentials
+        .clone()
+        .or_else(|| cache.as_ref().and_then(|cache| cache.credentials()))
+    else {
+        return Err(fatal(format!(
+            "no cached credentials in \"{}\", log in with --user and --pass, --token or rippify login first",
+            opts.cache_dir.display()
+        )));
+    };
+
+    loop {
+        let session_config = lsc::SessionConfig {
+            tmp_dir: opts.temp_dir.clone(),
+            proxy: opts.proxy.clone(),
+            ..Default::default()
+        };
+
+        // A session that failed to connect can't be reused, so every attempt starts with a fresh one
+        let session = lsc::Session::new(session_config, cache.clone());
+
+        let error = match time::timeout(opts.connect_timeout, session.connect(credentials.clone(), true)).await {
+            Ok(Ok(_)) => return Ok(session),
+            Ok(Err(err)) if err.kind == librespot_core::error::ErrorKind::PermissionDenied => {
+                return Err(fatal(format!("cannot log in: {}", err.to_string().to_lowercase())));
+            }
+            Ok(Err(err)) => format!("cannot log in: {}", err.to_string().to_lowercase()),
+            Err(_) => format!(
+                "could not reach spotify after {} seconds, check your connection",
+                opts.connect_timeout.as_secs()
+            ),
+        };
+
+        if attempt >= opts.connect_retries {
+            return Err(fatal(error));
+        }
+
+        attempt += 1;
+
+        warn!(
+            "{}: {}, retrying in {}s ({}/{})...",
+            "warning".yellow().bold(),
+            error,
+            backoff.as_secs(),
+            attempt,
+            opts.connect_retries
+        );
+
+        time::sleep(backoff).await;
+        backoff *= 2;
+    }
+}
+
+// Client id and redirect of spotify's own desktop app, which are the ones librespot's login flow is meant to be used with
+static OAUTH_CLIENT_ID: &str = "65b708073fc0480ea92a077233ca87bd";
+static OAUTH_REDIRECT_URI: &str = "http://127.0.0.1:8898/login";
+static OAUTH_SCOPES: [&str; 5] = [
+    "streaming",
+    "user-read-private",
+    "playlist-read-private",
+    "playlist-read-collaborative",
+    "user-library-read",
+];
+
+// Waits for the user to log in on the page printed to the terminal, spotify then redirects back to a local server
+async fn oauth_credentials() -> Result<lsc_auth::Credentials, Error> {
+    info!("\n{} Logging in through the browser:", "=>".green().bold());
+
+    run_blocking(|| librespot_oauth::get_access_token(OAUTH_CLIENT_ID, OAUTH_REDIRECT_URI, OAUTH_SCOPES.to_vec()))
+        .await
+        .map(|token| lsc_auth::Credentials::with_access_token(token.access_token))
+        .map_err(|err| fatal(format!("cannot log in: {}", err.to_string().to_lowercase())))
+}
+
+async fn track_audio_key(
+    session: &lsc::Session,
+    track_id: &lsc::SpotifyId,
+    source: &AudioSource,
+    retry: &RetryPolicy,
+) -> Result<lsc::audio_key::AudioKey, TrackDownloadError> {
+    let mut attempt: usize = 1;
+
+    loop {
+        let error: Box<dyn std::error::Error + Send + Sync> = match time::timeout(
+            AUDIO_KEY_TIMEOUT,
+            session.audio_key().request(*track_id, source.file_id),
+        )
+        .await
+        {
+            Ok(Ok(key)) => return Ok(key),
+            Ok(Err(err)) if is_rate_limited(&err) && attempt <= retry.retries => {
+                retry.cool_down().await;
+                attempt += 1;
+                continue;
+            }
+            // Spotify answering with an error means the key was denied, which asking again won't change
+            Ok(Err(err)) if err.kind == librespot_core::error::ErrorKind::Unavailable => {
+                return Err(ProcessError {
+                    kind: TrackDownloadErrorKind::AudioKey,
+                    error: err.into(),
+                })
+            }
+            Ok(Err(err)) => err.into(),
+            Err(_) => format!("no answer after {} seconds", AUDIO_KEY_TIMEOUT.as_secs()).into(),
+        };
+
+        let error = ProcessError {
+            kind: TrackDownloadErrorKind::AudioKey,
+            error,
+        };
+
+        if attempt > retry.retries {
+            return Err(error);
+        }
+
+        retry.wait(&error, attempt).await;
+        attempt += 1;
+    }
+}
+
+// Size of the chunks the audio is decrypted and written in while it streams in
+static STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+// How many chunks the reader can get ahead of the output
+static STREAM_BUFFERED_CHUNKS: usize = 4;
+
+enum StreamChunk {
+    Header(Vec<u8>),
+    Audio(Vec<u8>),
+}
+
+// Decrypts the audio chunk by chunk while it downloads, so the encrypted file is never held in memory. A failed
+// attempt continues right after the bytes that already made it to the output, as nothing can be taken back from it
+async fn track_stream(
+    session: &lsc::Session,
+    rate_limit: Option<&sync::Arc<RateLimit>>,
+    file_id: &lsc::FileId,
+    key: lsc::audio_key::AudioKey,
+    has_header: bool,
+    retry: &RetryPolicy,
+    output: &mut impl io::Write,
+) -> Result<Option<Normalization>, TrackDownloadError> {
+    let mut written: u64 = 0;
+    let mut attempt: usize = 1;
+    let mut transfer: Option<TransferBar> = None;
+    let mut header_len: u64 = 0;
+    let mut normalization = None;
+
+    loop {
+        let error = match lsa::AudioFile::open(session, *file_id, 40).await {
+            Ok(track_file_audio) => {
+                let len = track_file_audio
+                    .get_stream_loader_controller()
+                    .map_or(0, |controller| controller.len() as u64);
+
+                let mut track_decrypt =
+                    lsa::AudioDecrypt::new(Some(key), Throttled(track_file_audio, rate_limit.cloned()));
+                let read_header = has_header && written == 0;
+                let resume_at = header_len + written;
+                let (chunk_tx, mut chunk_rx) = tokio::sync::mpsc::channel(STREAM_BUFFERED_CHUNKS);
+
+                // Reads of the audio file block until spotify sent the bytes, so they happen on the blocking pool
+                // while the chunks are written out here. The header is only read on its way to the first byte of
+                // audio, later attempts seek past it. Sending only fails once the output did, which is reported below
+                let reader = task::spawn_blocking(move || -> io::Result<()> {
+                    let mut resume_at = resume_at;
+
+                    if read_header {
+                        let header = read_audio_header(&mut track_decrypt)?;
+                        resume_at = header.len() as u64;
+
+                        if chunk_tx.blocking_send(StreamChunk::Header(header)).is_err() {
+                            return Ok(());
+                        }
+                    }
+
+                    track_decrypt.seek(io::SeekFrom::Start(resume_at))?;
+
+                    loop {
+                        let mut chunk = vec![0u8; STREAM_CHUNK_SIZE];
+
+                        match track_decrypt.read(&mut chunk)? {
+                            0 => return Ok(()),
+                            len => {
+                                chunk.truncate(len);
+
+                                if chunk_tx.blocking_send(StreamChunk::Audio(chunk)).is_err() {
+                                    return Ok(());
+                                }
+                            }
+                        }
+                    }
+                });
+
+                let mut header_read = false;
+
+                while let Some(chunk) = chunk_rx.recv().await {
+                    match chunk {
+                        StreamChunk::Header(header) => {
+                            header_len = header.len() as u64;
+                            header_read = true;
+                            normalization = read_spotify_normalization(&header);
+                        }
+                        StreamChunk::Audio(chunk) => {
+                            let bar = transfer.get_or_insert_with(|| TransferBar::new(len.saturating_sub(header_len)));
+
+                            // Failing to write the output won't get better by downloading again
+                            output.write_all(&chunk).map_err(|e| ProcessError {
+                                kind: TrackDownloadErrorKind::Decrypt,
+                                error: e.into(),
+                            })?;
+
+                            written += chunk.len() as u64;
+                            bar.set_position(written);
+                        }
+                    }
+                }
+
+                match reader
+                    .await
+                    .unwrap_or_else(|err| panic::resume_unwind(err.into_panic()))
+                {
+                    Ok(()) => return Ok(normalization),
+                    Err(err) if read_header && !header_read && err.kind() == io::ErrorKind::InvalidData => {
+                        return Err(header_error(err))
+                    }
+                    Err(err) => ProcessError {
+                        kind: TrackDownloadErrorKind::TrackFile,
+                        error: err.into(),
+                    },
+                }
+            }
+            Err(err) => ProcessError {
+                kind: TrackDownloadErrorKind::AudioFile,
+                error: err.into(),
+            },
+        };
+
+        if attempt > retry.retries {
+            return Err(error);
+        }
+
+        retry.wait(&error, attempt).await;
+        attempt += 1;
+    }
+}
+
+// Token bucket shared by all downloads of a run for --limit-rate, holding up to a second worth of bytes. Reads can
+// take more than the bucket holds, the reader then waits until the bucket has refilled the difference
+struct RateLimit {
+    rate: u64,
+    bucket: sync::Mutex<(f64, stdtime::Instant)>,
+}
+
+impl RateLimit {
+    fn new(rate: u64) -> Self {
+        RateLimit {
+            rate,
+            bucket: sync::Mutex::new((rate as f64, stdtime::Instant::now())),
+        }
+    }
+
+    fn take(&self, len: usize) -> stdtime::Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let (tokens, refilled) = &mut *bucket;
+
+        let now = stdtime::Instant::now();
+        let rate = self.rate as f64;
+
+        *tokens = (*tokens + now.duration_since(*refilled).as_secs_f64() * rate).min(rate) - len as f64;
+        *refilled = now;
+
+        if *tokens < 0.0 {
+            stdtime::Duration::from_secs_f64(-*tokens / rate)
+        } else {
+            stdtime::Duration::ZERO
+        }
+    }
+}
+
+// Wraps the audio file readers, which is where the audio is pulled from spotify
+struct Throttled<R>(R, Option<sync::Arc<RateLimit>>);
+
+impl<R: io::Read> io::Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.0.read(buf)?;
+
+        if let Some(delay) = self
+            .1
+            .as_ref()
+            .map(|limit| limit.take(len))
+            .filter(|delay| !delay.is_zero())
+        {
+            // The audio file is only read on the blocking pool, where sleeping holds up nothing but this download
+            std::thread::sleep(delay);
+        }
+
+        Ok(len)
+    }
+}
+
+impl<R: io::Seek> io::Seek for Throttled<R> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+async fn track_fetch(
+    session: &lsc::Session,
+    rate_limit: Option<&sync::Arc<RateLimit>>,
+    file_id: &lsc::FileId,
+    resume_dir: Option<&path::Path>,
+    track_buffer: &mut Vec<u8>,
+) -> Result<(), TrackDownloadError> {
+    let mut track_file_audio = lsa::AudioFile::open(session, *file_id, 40)
+        .await
+        .map(|track_file_audio| Throttled(track_file_audio, rate_limit.cloned()))
+        .map_err(|e| ProcessError {
+            kind: TrackDownloadErrorKind::AudioFile,
+            error: e.into(),
+        })?;
+
+    // The encrypted file is written to a .part file as it arrives, so an interrupted run or failed attempt only
+    // requests the bytes after what it already got. Decryption always starts from the complete file afterwards
+    let part_path = resume_dir.map(|dir| dir.join(format!("{}.part", file_id.to_base16().unwrap_or_default())));
+
+    // Reads of the audio file block until spotify sent the bytes, so they happen on the blocking pool
+    let (buffer, fetched) = {
+        let part_path = part_path.clone();
+
+        run_blocking(move || {
+            let mut buffer = Vec::<u8>::new();
+
+            let Some(part_path) = part_path else {
+                let fetched = track_file_audio.read_to_end(&mut buffer).map(|_| ());
+                return (buffer, fetched);
+            };
+
+            let fetched = fs::OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(&part_path)
+                .and_then(|mut part| resume_part(&mut track_file_audio, &mut part, &mut buffer));
+
+            (buffer, fetched)
+        })
+        .await
+    };
+
+    fetched.map_err(|e| ProcessError {
+        kind: TrackDownloadErrorKind::TrackFile,
+        error: e.into(),
+    })?;
+
+    *track_buffer = buffer;
+
+    if let Some(part_path) = part_path {
+        fs::remove_file(part_path).ok();
+    }
+
+    Ok(())
+}
+
+// Requests only what the audio file has past the end of the .part file, then reads the now complete file back
+fn resume_part(
+    audio: &mut (impl io::Read + io::Seek),
+    part: &mut (impl io::Read + io::Write + io::Seek),
+    buffer: &mut Vec<u8>,
+) -> io::Result<()> {
+    let offset = part.seek(io::SeekFrom::End(0))?;
+
+    if offset > 0 {
+        info!("   - resuming download at {}KB", offset / 1000);
+    }
+
+    audio.seek(io::SeekFrom::Start(offset))?;
+    io::copy(audio, part)?;
+
+    part.seek(io::SeekFrom::Start(0))?;
+    part.read_to_end(buffer).map(|_| ())
+}
+
+static BENCHMARK_JOBS: [usize; 4] = [1, 2, 4, 8];
+
+// Spotify doesn't expose file sizes in its metadata, so the download is estimated from the track durations at the
+// selected bitrate. Tracks in the download archive, in the library or with a kept output file are left out, only
+// files --overwrite might replace are still counted, which errs on the safe side
+async fn check_disk_space<'a>(
+    ctx: &sync::Arc<TrackContext>,
+    jobs: impl Iterator<Item = &'a TrackJob>,
+) -> Result<(), Error> {
+    // Skipping these doesn't need any metadata, so a re-run over a complete library makes no requests at all
+    let jobs: Vec<TrackJob> = jobs
+        .filter(|job| !already_downloaded(ctx.archive.as_ref(), &ctx.library, &job.id))
+        .cloned()
+        .collect();
+
+    if jobs.is_empty() {
+        return Ok(());
+    }
+
+    let root = ctx.opts.format.root();
+
+    // The output directory might not exist yet, in which case the space left on its closest existing parent counts
+    let existing = root
+        .ancestors()
+        .find(|dir| dir.exists())
+        .unwrap_or(path::Path::new("."));
+
+    let available = match fs2::available_space(existing) {
+        Ok(available) => available,
+        Err(err) => {
+            warn!(
+                "\n{}: cannot check free space in \"{}\": {}, continuing...",
+                "warning".yellow().bold(),
+                root.display(),
+                err.to_string().to_lowercase()
+            );
+            return Ok(());
+        }
+    };
+
+    info!("\n{} Estimating download size...", "=>".green().bold());
+
+    let semaphore = sync::Arc::new(tokio::sync::Semaphore::new(ctx.opts.jobs));
+    let mut tasks = task::JoinSet::new();
+
+    for job in jobs {
+        let ctx = ctx.clone();
+        let semaphore = semaphore.clone();
+
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await.unwrap();
+            estimate_download_size(&ctx, &job).await
+        });
+    }
+
+    let mut estimate: u64 = 0;
+
+    while let Some(result) = tasks.join_next().await {
+        estimate += result.unwrap_or(0);
+    }
+
+    if estimate > available {
+        return Err(fatal(format!(
+            "the download needs up to {}MB but only {}MB are free in \"{}\", aborting... (use --skip-space-check to download anyway)",
+            estimate / 1_000_000,
+            available / 1_000_000,
+            root.display()
+        )));
+    }
+
+    Ok(())
+}
+
+// Whether the download archive or the library index already has the item, which takes no request to find out
+fn already_downloaded(
+    archive: Option<&DownloadArchive>,
+    library: &coll::HashMap<String, path::PathBuf>,
+    id: &lsc::SpotifyId,
+) -> bool {
+    archive.map_or(false, |archive| archive.contains(&[id]))
+        || library.contains_key(&id.to_base62().unwrap_or_default())
+}
+
+// Skips a track for the same reasons process_track does, leaving its metadata in the album cache for the download
+async fn estimate_download_size(ctx: &TrackContext, job: &TrackJob) -> u64 {
+    // Items without metadata will fail to download anyway and don't need any space
+    if job.id.item_type == lsc::spotify_id::SpotifyItemType::Episode {
+        return match lsm::Episode::get(&ctx.session, &job.id).await {
+            Ok(episode) => {
+                u64::try_from(episode.duration).unwrap_or(0) * u64::from(ctx.selection.highest_bitrate()) / 8
+            }
+            Err(_) => 0,
+        };
+    }
+
+    let Ok((track, source, relation)) = get_track_from_id(
+        &ctx.session,
+        &ctx.albums,
+        &job.id,
+        ctx.opts.prefer_canonical,
+        &ctx.selection,
+    )
+    .await
+    else {
+        return 0;
+    };
+
+    if already_downloaded(ctx.archive.as_ref(), &ctx.library, &track.id) {
+        return 0;
+    }
+
+    let requested = match (relation, &ctx.opts.alt_metadata) {
+        (TrackRelation::Alternative, AltMetadata::Requested) => ctx.albums.get_track(&ctx.session, &job.id).await.ok(),
+        _ => None,
+    };
+
+    let metadata = requested.as_deref().unwrap_or(&track);
+    let album = ctx.albums.get(&ctx.session, &metadata.album.id).await.ok();
+
+    let output_file = ctx.opts.format.parse_output_format(
+        metadata,
+        album.as_deref(),
+        ctx.opts.codec.extension(source.container),
+        &job.source,
+        job.position,
+    );
+
+    if ctx.opts.overwrite == Overwrite::Never && path::Path::new(&output_file.file).exists() {
+        return 0;
+    }
+
+    u64::try_from(track.duration).unwrap_or(0) * u64::from(source.bitrate) / 8
+}
+
+async fn benchmark(
+    session: &lsc::Session,
+    albums: &AlbumCache,
+    track_ids: &[lsc::SpotifyId],
+    selection: &AudioSelection,
+) {
+    // Metadata is resolved up front so it doesn't count against the measured throughput
+    let mut file_ids = Vec::new();
+
+    for track_id in track_ids {
+        match get_track_from_id(session, albums, track_id, false, selection).await {
+            Ok((_, source, _)) => file_ids.push(source.file_id),
+            Err(err) => {
+                warn!(
+                    "{}: cannot get track from id {}: {}, skipping...",
+                    " -> warning".yellow().bold(),
+                    track_id.to_base62().unwrap(),
+                    err
+                );
+            }
+        }
+    }
+
+    summary!(
+        "\n{} Benchmarking with {} tracks:",
+        "=>".green().bold(),
+        file_ids.len().to_string().bold()
+    );
+
+    for jobs in BENCHMARK_JOBS {
+        let semaphore = sync::Arc::new(tokio::sync::Semaphore::new(jobs));
+        let mut tasks = tokio::task::JoinSet::new();
+
+        let start = stdtime::Instant::now();
+
+        for file_id in &file_ids {
+            let session = session.clone();
+            let semaphore = semaphore.clone();
+            let file_id = *file_id;
+
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await.unwrap();
+                let mut buffer = Vec::<u8>::new();
+
+                track_fetch(&session, None, &file_id, None, &mut buffer)
+                    .await
+                    .map(|_| buffer.len())
+                    .map_err(|_| ())
+            });
+        }
+
+        let mut num_bytes: usize = 0;
+        let mut num_failed: usize = 0;
+
+        while let Some(result) = tasks.join_next().await {
+            match result {
+                Ok(Ok(len)) => num_bytes += len,
+                _ => num_failed += 1,
+            }
+        }
+
+        let elapsed = start.elapsed().as_secs_f64();
+        let megabytes = num_bytes as f64 / 1_000_000.0;
+
+        summary!(
+            " {} {} jobs: {} ({:.1} MB in {:.1}s, {} failed)",
+            "->".yellow().bold(),
+            jobs,
+            format!("{:.2} MB/s", megabytes / elapsed).bold(),
+            megabytes,
+            elapsed,
+            num_failed
+        );
+    }
+}
+
+// Debug only, dumps both buffers before the header offset is stripped so they can be inspected by hand
+fn track_dump(dir: &path::Path, id: &lsc::SpotifyId, encrypted: &[u8], decrypted: &[u8]) -> io::Result<()> {
+    let id = id.to_base62().unwrap();
+
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join(format!("{}.encrypted", id)), encrypted)?;
+    fs::write(dir.join(format!("{}.decrypted", id)), decrypted)?;
+
+    Ok(())
+}
+
+enum TrackWriteErrorKind {
+    FolderCreate,
+    FileCreate,
+    FileWrite,
+    Transcode,
+}
+
+impl fmt::Display for TrackWriteErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackWriteErrorKind::FolderCreate => write!(f, "cannot create output folders"),
+            TrackWriteErrorKind::FileCreate => write!(f, "cannot create output file"),
+            TrackWriteErrorKind::FileWrite => write!(f, "cannot write output file"),
+            TrackWriteErrorKind::Transcode => write!(f, "cannot transcode audio file"),
+        }
+    }
+}
+
+impl ProcessErrorKind for TrackWriteErrorKind {}
+type TrackWriteError = ProcessError<TrackWriteErrorKind>;
+
+// Spotify only serves vorbis and aac, so every other codec is encoded by ffmpeg from the tagged download. ffmpeg carries
+// the tags over on its own, cover art included except for opus, whose ogg muxer can't write it
+async fn track_transcode(
+    codec: &Codec,
+    input: &path::Path,
+    source: &AudioSource,
+    output_file: OutputFile,
+) -> Result<String, TrackWriteError> {
+    if let Some(path) = &output_file.dir {
+        fs::create_dir_all(path).map_err(|e| TrackWriteError {
+            kind: TrackWriteErrorKind::FolderCreate,
+            error: e.into(),
+        })?;
+    }
+
+    // Vorbis comments end up as metadata of the audio stream in ffmpeg, while mp4 tags are global ones
+    let metadata = match source.container {
+        AudioContainer::Ogg => "0:s:a:0",
+        AudioContainer::Mp4 => "0",
+    };
+
+    // The muxer has to be given, ffmpeg can't tell it from the extension of the .part file
+    let muxer = match codec {
+        Codec::Ogg => "ogg",
+        Codec::Mp3 => "mp3",
+        Codec::Flac => "flac",
+        Codec::Opus => "opus",
+    };
+
+    let part = PartFile::new(&output_file.file);
+
+    let mut command = tokio::process::Command::new("ffmpeg");
+    command
+        .kill_on_drop(true)
+        .args(["-v", "error", "-y", "-i"])
+        .arg(input)
+        .args(["-map", "0:a", "-map_metadata", metadata]);
+
+    match codec {
+        Codec::Mp3 => {
+            command
+                .args([
+                    "-map",
+                    "0:v?",
+                    "-c:v",
+                    "copy",
+                    "-c:a",
+                    "libmp3lame",
+                    "-id3v2_version",
+                    "3",
+                    "-b:a",
+                ])
+                .arg(format!("{}k", source.bitrate));
+        }
+        Codec::Flac => {
+            command.args(["-map", "0:v?", "-c:v", "copy", "-c:a", "flac"]);
+        }
+        Codec::Opus => {
+            command
+                .args(["-c:a", "libopus", "-b:a"])
+                .arg(format!("{}k", source.bitrate));
+        }
+        Codec::Ogg => {}
+    }
+
+    let output = command
+        .args(["-f", muxer])
+        .arg(&part.path)
+        .output()
+        .await
+        .map_err(|e| TrackWriteError {
+            kind: TrackWriteErrorKind::Transcode,
+            error: e.into(),
+        })?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        return Err(TrackWriteError {
+            kind: TrackWriteErrorKind::Transcode,
+            error: match stderr.trim().lines().last() {
+                Some(line) => line.to_owned().into(),
+                None => format!("ffmpeg exited with {}", output.status).into(),
+            },
+        });
+    }
+
+    part.finish(&output_file.file)?;
+
+    Ok(output_file.file)
+}
+
+// Checked once before the run, so a missing ffmpeg doesn't fail every single track
+fn ffmpeg_available() -> bool {
+    proc::Command::new("ffmpeg")
+        .arg("-version")
+        .stdout(proc::Stdio::null())
+        .stderr(proc::Stdio::null())
+        .status()
+        .map_or(false, |status| status.success())
+}
+
+// Output is written to a .part file next to its path and only renamed to it once complete, so an interrupted write
+// never leaves a truncated file behind that the next run would take as already downloaded
+struct PartFile {
+    path: String,
+    done: bool,
+}
+
+impl PartFile {
+    fn new(file: &str) -> Self {
+        PartFile {
+            path: format!("{}.part", file),
+            done: false,
+        }
+    }
+
+    fn finish(mut self, file: &str) -> Result<(), TrackWriteError> {
+        fs::rename(&self.path, file).map_err(|e| ProcessError {
+            kind: TrackWriteErrorKind::FileWrite,
+            error: e.into(),
+        })?;
+
+        self.done = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        if !self.done {
+            fs::remove_file(&self.path).ok();
+        }
+    }
+}
+
+fn track_write(track_buffer: Vec<u8>, output_file: OutputFile) -> Result<String, TrackWriteError> {
+    if let Some(path) = output_file.dir {
+        fs::create_dir_all(path).map_err(|e| TrackWriteError {
+            kind: TrackWriteErrorKind::FolderCreate,
+            error: e.into(),
+        })?;
+    }
+
+    let part = PartFile::new(&output_file.file);
+
+    let mut file_write = fs::File::create(&part.path).map_err(|e| ProcessError {
+        kind: TrackWriteErrorKind::FileCreate,
+        error: e.into(),
+    })?;
+
+    io::copy(&mut track_buffer.as_slice(), &mut file_write).map_err(|e| ProcessError {
+        kind: TrackWriteErrorKind::FileWrite,
+        error: e.into(),
+    })?;
+
+    drop(file_write);
+    part.finish(&output_file.file)?;
+
+    Ok(output_file.file)
+}
+
+#[derive(serde::Serialize)]
+struct ArtistMetadata {
+    id: String,
+    name: String,
+}
+
+#[derive(serde::Serialize)]
+struct AlbumMetadata {
+    id: String,
+    name: String,
+    artists: Vec<ArtistMetadata>,
+}
+
+#[derive(serde::Serialize)]
+struct TrackMetadata {
+    id: String,
+    name: String,
+    isrc: Option<String>,
+    artists: Vec<ArtistMetadata>,
+    album: AlbumMetadata,
+    duration_ms: i32,
+    disc_number: i32,
+    track_number: i32,
+    release_date: String,
+    popularity: i32,
+}
+
+impl From<&lsm::Track> for TrackMetadata {
+    fn from(track: &lsm::Track) -> Self {
+        let artists = |artists: &lsm::artist::Artists| {
+            artists
+                .iter()
+                .map(|artist| ArtistMetadata {
+                    id: artist.id.to_base62().unwrap(),
+                    name: artist.name.clone(),
+                })
+                .collect()
+        };
+
+        TrackMetadata {
+            id: track.id.to_base62().unwrap(),
+            name: track.name.clone(),
+            isrc: track_isrc(track),
+            artists: artists(&track.artists),
+            album: AlbumMetadata {
+                id: track.album.id.to_base62().unwrap(),
+                name: track.album.name.clone(),
+                artists: artists(&track.album.artists),
+            },
+            duration_ms: track.duration,
+            disc_number: track.disc_number,
+            track_number: track.number,
+            release_date: track_release_date(track),
+            popularity: track.popularity,
+        }
+    }
+}
+
+fn track_title(track: &lsm::Track, strip_featured: bool) -> String {
+    if !strip_featured {
+        return track.name.clone();
+    }
+
+    let other_artists: Vec<_> = track
+        .artists
+        .iter()
+        .skip(1)
+        .map(|artist| artist.name.as_str())
+        .collect();
+    strip_featured_credit(&track.name, &other_artists)
+}
+
+// Only a trailing (feat. ...), [ft. ...] or (with ...) that credits one of the track's other artists is removed, so
+// parentheses that are part of the actual title are kept
+fn strip_featured_credit(name: &str, other_artists: &[&str]) -> String {
+    let featured = regex::Regex::new(r"(?i)\s*[(\[](?:feat\.?|ft\.?|featuring|with)\s+([^()\[\]]+)[)\]]$").unwrap();
+
+    let Some(captures) = featured.captures(name) else {
+        return name.to_owned();
+    };
+
+    let credited = captures[1].to_lowercase();
+
+    if other_artists
+        .iter()
+        .any(|artist| credited.contains(&artist.to_lowercase()))
+    {
+        name[..captures.get(0).unwrap().start()].to_owned()
+    } else {
+        name.to_owned()
+    }
+}
+
+fn track_isrc(track: &lsm::Track) -> Option<String> {
+    track
+        .external_ids
+        .iter()
+        .find(|external_id| external_id.external_type == "isrc")
+        .map(|external_id| external_id.id.clone())
+}
+
+fn album_upc(album: &lsm::Album) -> Option<String> {
+    album
+        .external_ids
+        .iter()
+        .find(|external_id| external_id.external_type == "upc")
+        .map(|external_id| external_id.id.clone())
+}
+
+fn track_release_date(track: &lsm::Track) -> String {
+    let date = &track.album.date;
+    format!("{:04}-{:02}-{:02}", date.year(), date.month() as u8, date.day())
+}
+
+fn episode_release_date(episode: &lsm::Episode) -> String {
+    let date = &episode.publish_time;
+    format!("{:04}-{:02}-{:02}", date.year(), date.month() as u8, date.day())
+}
+
+// Uses beets' own field names, so every entry can be applied to the file at its path as is
+#[derive(serde::Serialize)]
+struct BeetsItem {
+    path: String,
+    title: String,
+    artist: String,
+    artists: Vec<String>,
+    album: String,
+    albumartist: String,
+    track: i32,
+    tracktotal: Option<usize>,
+    disc: i32,
+    disctotal: Option<usize>,
+    year: i32,
+    month: u8,
+    day: u8,
+    length: f64,
+    isrc: Option<String>,
+    comp: bool,
+    spotify_track_id: String,
+    spotify_album_id: String,
+}
+
+impl BeetsItem {
+    fn new(path: String, track: &lsm::Track, album: Option<&lsm::Album>, tags: &TagOptions) -> Self {
+        let artists: Vec<_> = track.artists.iter().map(|artist| artist.name.clone()).collect();
+        let date = &track.album.date;
+
+        BeetsItem {
+            path,
+            title: track_title(track, tags.strip_featured),
+            artist: artists.join(", "),
+            artists,
+            album: track.album.name.clone(),
+            albumartist: track
+                .album
+                .artists
+                .first()
+                .map(|artist| artist.name.clone())
+                .unwrap_or_default(),
+            track: track.number,
+            tracktotal: album.map(|album| album.tracks().count()),
+            disc: track.disc_number,
+            disctotal: album.map(|album| album.discs.len()),
+            year: date.year(),
+            month: date.month() as u8,
+            day: date.day(),
+            length: f64::from(track.duration) / 1000.0,
+            isrc: track_isrc(track),
+            comp: tags.compilation.is_compilation(track),
+            spotify_track_id: track.id.to_base62().unwrap(),
+            spotify_album_id: track.album.id.to_base62().unwrap(),
+        }
+    }
+}
+
+// Resolves every track like a download would, including the output path, but only writes out the metadata
+async fn export_beets(
+    session: &lsc::Session,
+    albums: &AlbumCache,
+    input_tracks: &indexmap::IndexMap<lsc::SpotifyId, TrackJob>,
+    selection: &AudioSelection,
+    opts: &UserParams,
+    path: &path::Path,
+) -> Result<(), Error> {
+    summary!(
+        "\n{} Exporting {} tracks:",
+        "=>".green().bold(),
+        input_tracks.len().to_string().bold()
+    );
+
+    let mut items = Vec::<BeetsItem>::with_capacity(input_tracks.len());
+
+    for (track_id, job) in input_tracks {
+        let found = get_track_from_id(session, albums, track_id, opts.prefer_canonical, selection).await;
+
+        let (track, source) = match found {
+            Ok((track, source, _)) => (track, source),
+            Err(err) => {
+                warn!(
+                    " {} {}: cannot get track from id {}: {}, skipping...",
+                    "->".yellow().bold(),
+                    "warning".yellow().bold(),
+                    track_id.to_base62().unwrap(),
+                    err
+                );
+                fail_fast(opts)?;
+                continue;
+            }
+        };
+
+        let album = albums.get(session, &track.album.id).await.ok();
+
+        let output_file = opts.format.parse_output_format(
+            &track,
+            album.as_deref(),
+            opts.codec.extension(source.container),
+            &job.source,
+            job.position,
+        );
+
+        info!(" {} {}", "->".yellow().bold(), output_file.file);
+        items.push(BeetsItem::new(output_file.file, &track, album.as_deref(), &opts.tags));
+    }
+
+    let written = serde_json::to_string_pretty(&items)
+        .map_err(io::Error::from)
+        .and_then(|json| fs::write(path, json + "\n"));
+
+    if let Err(err) = written {
+        return Err(fatal(format!(
+            "cannot write \"{}\": {}",
+            path.display(),
+            err.to_string().to_lowercase()
+        )));
+    }
+
+    summary!(
+        "\n{} Exported {} tracks to \"{}\"",
+        "=>".green().bold(),
+        items.len().to_string().bold(),
+        path.display()
+    );
+
+    Ok(())
+}
+
+#[derive(Clone, clap::ValueEnum)]
+pub enum MetadataFormat {
+    Json,
+    Csv,
+}
+
+// Everything known about a track without downloading it, including whether it could be downloaded at the selected
+// quality and the url of its cover art
+#[derive(serde::Serialize)]
+struct CatalogItem {
+    #[serde(flatten)]
+    track: TrackMetadata,
+    source: String,
+    available: bool,
+    bitrate: Option<u32>,
+    cover_url: Option<String>,
+}
+
+impl CatalogItem {
+    fn to_csv_row(&self) -> String {
+        let names = |artists: &[ArtistMetadata]| {
+            artists
+                .iter()
+                .map(|artist| artist.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        [
+            Some(self.track.id.clone()),
+            Some(self.track.name.clone()),
+            Some(names(&self.track.artists)),
+            Some(self.track.album.name.clone()),
+            Some(names(&self.track.album.artists)),
+            Some(self.track.duration_ms.to_string()),
+            Some(self.track.disc_number.to_string()),
+            Some(self.track.track_number.to_string()),
+            self.track.isrc.clone(),
+            Some(self.track.release_date.clone()),
+            Some(self.track.popularity.to_string()),
+            Some(self.source.clone()),
+            Some(self.available.to_string()),
+            self.bitrate.map(|bitrate| bitrate.to_string()),
+            self.cover_url.clone(),
+        ]
+        .into_iter()
+        .map(|field| csv_field(&field.unwrap_or_default()))
+        .collect::<Vec<_>>()
+        .join(",")
+    }
+}
+
+// Unlike a download, tracks that can't be downloaded are listed as well, marked as unavailable
+async fn export_metadata(
+    session: &lsc::Session,
+    albums: &AlbumCache,
+    input_tracks: &indexmap::IndexMap<lsc::SpotifyId, TrackJob>,
+    selection: &AudioSelection,
+    opts: &UserParams,
+    format: &MetadataFormat,
+) -> Result<(), Error> {
+    let mut items = Vec::<CatalogItem>::with_capacity(input_tracks.len());
+
+    for (track_id, job) in input_tracks {
+        if track_id.item_type == lsc::spotify_id::SpotifyItemType::Episode {
+            warn!(
+                " {} {}: episode {} has no track metadata, skipping...",
+                "->".yellow().bold(),
+                "note".bright_blue().bold(),
+                track_id.to_base62().unwrap()
+            );
+            continue;
+        }
+
+        let track = match lsm::Track::get(session, track_id).await {
+            Ok(track) => track,
+            Err(err) => {
+                warn!(
+                    " {} {}: cannot get track metadata for {}: {}, skipping...",
+                    "->".yellow().bold(),
+                    "warning".yellow().bold(),
+                    track_id.to_base62().unwrap(),
+                    err
+                );
+                fail_fast(opts)?;
+                continue;
+            }
+        };
+
+        let source = match get_track_from_id(session, albums, track_id, false, selection).await {
+            Ok((_, source, _)) => Some(source),
+            Err(_) => None,
+        };
+
+        let cover_url = match albums.get(session, &track.album.id).await {
+            Ok(album) => opts
+                .tags
+                .cover_size
+                .select(&album.covers)
+                .map(|cover| format!("https://i.scdn.co/image/{}", cover.id.to_base16().unwrap_or_default())),
+            Err(_) => None,
+        };
+
+        items.push(CatalogItem {
+            track: TrackMetadata::from(&track),
+            source: job.source.clone(),
+            available: source.is_some(),
+            bitrate: source.map(|source| source.bitrate),
+            cover_url,
+        });
+    }
+
+    let contents = match format {
+        MetadataFormat::Json => serde_json::to_string_pretty(&items).unwrap_or_default() + "\n",
+        MetadataFormat::Csv => {
+            let mut rows = vec![String::from(
+                "id,name,artists,album,album_artists,duration_ms,disc_number,track_number,isrc,release_date,\
+                popularity,source,available,bitrate,cover_url",
+            )];
+            rows.extend(items.iter().map(CatalogItem::to_csv_row));
+            rows.join("\n") + "\n"
+        }
+    };
+
+    io::stdout().lock().write_all(contents.as_bytes()).map_err(|err| {
+        fatal(format!(
+            "cannot write metadata to stdout: {}",
+            err.to_string().to_lowercase()
+        ))
+    })
+}
+
+// Sidecar goes next to the audio file, so its folders have already been created
+fn track_write_metadata(track: &lsm::Track, audio_file: &str) -> Result<String, TrackWriteError> {
+    let sidecar = path::Path::new(audio_file).with_extension("json");
+
+    let json = serde_json::to_vec_pretty(&TrackMetadata::from(track)).map_err(|e| ProcessError {
+        kind: TrackWriteErrorKind::FileWrite,
+        error: e.into(),
+    })?;
+
+    fs::write(&sidecar, json).map_err(|e| ProcessError {
+        kind: TrackWriteErrorKind::FileWrite,
+        error: e.into(),
+    })?;
+
+    Ok(sidecar.to_string_lossy().into_owned())
+}
+
+fn lyrics_text(lyrics: &lsm::Lyrics) -> String {
+    lyrics
+        .lyrics
+        .lines
+        .iter()
+        .map(|line| line.words.as_str())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Writes the album cover into the folder of a track for players that look for one there, once per folder. A cover
+// that is already there is left alone, which also covers folders shared by several albums
+async fn save_folder_cover(ctx: &TrackContext, album: &lsm::Album, audio_file: &str) {
+    let Some(name) = &ctx.opts.save_cover else {
+        return;
+    };
+
+    let folder = path::Path::new(audio_file)
+        .parent()
+        .map(|folder| folder.to_path_buf())
+        .unwrap_or_default();
+
+    if !ctx.cover_folders.lock().unwrap().insert(folder.clone()) {
+        return;
+    }
+
+    let cover_file = folder.join(name);
+
+    if cover_file.exists() {
+        return;
+    }
+
+    let Some(image) = ctx.opts.save_cover_size.select(&album.covers) else {
+        return;
+    };
+
+    let cover = match download_cover(&ctx.session, image).await {
+        Ok(cover) => cover,
+        Err(err) => {
+            warn!(
+                "   - {}: cannot get cover art: {}, skipping cover file...",
+                "warning".yellow().bold(),
+                err
+            );
+            return;
+        }
+    };
+
+    match fs::write(&cover_file, cover.data) {
+        Ok(()) => info!("   - wrote \"{}\"", cover_file.display()),
+        Err(err) => {
+            warn!(
+                "   - {}: cannot write cover file: {}, skipping cover file...",
+                "warning".yellow().bold(),
+                err
+            );
+            let _ = fail_fast(&ctx.opts);
+        }
+    }
+}
+
+// Line synced lyrics get an [mm:ss.xx] timestamp in front of every line, unsynced ones are written as plain lines
+fn track_write_lyrics(lyrics: &lsm::Lyrics, audio_file: &str) -> Result<String, TrackWriteError> {
+    let sidecar = path::Path::new(audio_file).with_extension("lrc");
+
+    let lrc: String = lyrics
+        .lyrics
+        .lines
+        .iter()
+        .map(|line| match lyrics.lyrics.sync_type {
+            lsm::lyrics::SyncType::LineSynced => {
+                let start: u64 = line.start_time_ms.parse().unwrap_or(0);
+                format!(
+                    "[{:02}:{:02}.{:02}]{}\n",
+                    start / 60_000,
+                    start / 1000 % 60,
+                    start / 10 % 100,
+                    line.words
+                )
+            }
+            lsm::lyrics::SyncType::Unsynced => format!("{}\n", line.words),
+        })
+        .collect();
+
+    fs::write(&sidecar, lrc).map_err(|e| ProcessError {
+        kind: TrackWriteErrorKind::FileWrite,
+        error: e.into(),
+    })?;
+
+    Ok(sidecar.to_string_lossy().into_owned())
+}
+
+// Tags from one staging file into another, so neither the decrypted nor the tagged audio is ever fully held in memory
+fn track_write_staged(
+    mut staged: fs::File,
+    container: AudioContainer,
+    // Without sources the staged audio is moved over untagged, as for --decrypt-only
+    sources: Option<&TagSources>,
+    tags: &TagOptions,
+    temp_dir: &path::Path,
+    output_file: OutputFile,
+) -> Result<String, TrackWriteError> {
+    if let Some(path) = &output_file.dir {
+        fs::create_dir_all(path).map_err(|e| TrackWriteError {
+            kind: TrackWriteErrorKind::FolderCreate,
+            error: e.into(),
+        })?;
+    }
+
+    let mut tagged = tempfile::NamedTempFile::new_in(temp_dir).map_err(|e| ProcessError {
+        kind: TrackWriteErrorKind::FileCreate,
+        error: e.into(),
+    })?;
+
+    // Mp4 files are tagged in place once they're written, so their audio is always copied over untouched
+    let tag_result = match (container, sources) {
+        (AudioContainer::Ogg, Some(sources)) => Some(
+            staged
+                .seek(io::SeekFrom::Start(0))
+                .map_err(|e| TagsWriteError {
+                    kind: TagsWriteErrorKind::Read,
+                    error: e.into(),
+                })
+                .and_then(|_| {
+                    let mut writer = io::BufWriter::new(tagged.as_file_mut());
+
+                    track_add_metadata_tags(io::BufReader::new(&staged), &mut writer, sources, tags)?;
+
+                    writer.flush().map_err(|e| TagsWriteError {
+                        kind: TagsWriteErrorKind::Write,
+                        error: e.into(),
+                    })
+                }),
+        ),
+        _ => None,
+    };
+
+    if let Some(Err(err)) = &tag_result {
+        warn!(
+            "   - {}: {}, writing untagged audio instead...",
+            "warning".yellow().bold(),
+            err
+        );
+    }
+
+    // Untagged audio is still better than no audio, so tagging failures don't skip the track
+    if !matches!(tag_result, Some(Ok(()))) {
+        staged
+            .seek(io::SeekFrom::Start(0))
+            .and_then(|_| tagged.as_file_mut().set_len(0))
+            .and_then(|_| tagged.as_file_mut().seek(io::SeekFrom::Start(0)))
+            .and_then(|_| io::copy(&mut staged, tagged.as_file_mut()))
+            .map_err(|e| ProcessError {
+                kind: TrackWriteErrorKind::FileWrite,
+                error: e.into(),
+            })?;
+    }
+
+    // Renaming only works within the same filesystem, fall back to copying otherwise
+    if let Err(err) = tagged.persist(&output_file.file) {
+        let part = PartFile::new(&output_file.file);
+
+        fs::copy(err.file.path(), &part.path).map_err(|e| ProcessError {
+            kind: TrackWriteErrorKind::FileWrite,
+            error: e.into(),
+        })?;
+
+        part.finish(&output_file.file)?;
+    }
+
+    if let (AudioContainer::Mp4, Some(sources)) = (container, sources) {
+        track_tag_mp4_output(&output_file.file, sources, tags);
+    }
+
+    Ok(output_file.file)
+}
+
+async fn run_post_hook(hook: &str, output: &str, item: TaggedItem<'_>) {
+    // Episodes are described by their show in place of the artist and album
+    let (id, title, artist, album) = match item {
+        TaggedItem::Track(track) => (
+            track.id,
+            &track.name,
+            track.artists.first().map_or("", |artist| artist.name.as_str()),
+            &track.album.name,
+        ),
+        TaggedItem::Episode(episode) => (
+            episode.id,
+            &episode.name,
+            episode.show_name.as_str(),
+            &episode.show_name,
+        ),
+    };
+
+    // The command goes through the shell so users can pass arguments and pipes, "$1" then expands to the output path
+    let status = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", hook))
+        .arg("rippify")
+        .arg(output)
+        .env("RIPPIFY_PATH", output)
+        .env("RIPPIFY_TRACK_ID", id.to_base62().unwrap())
+        .env("RIPPIFY_TITLE", title)
+        .env("RIPPIFY_ARTIST", artist)
+        .env("RIPPIFY_ALBUM", album)
+        .status()
+        .await;
+
+    match status {
+        Ok(status) if status.success() => info!("   - ran post hook"),
+        Ok(status) => warn!(
+            "   - {}: post hook exited with {}, continuing...",
+            "warning".yellow().bold(),
+            status
+        ),
+        Err(err) => warn!(
+            "   - {}: cannot run post hook: {}, continuing...",
+            "warning".yellow().bold(),
+            err
+        ),
+    }
+}
+
+fn track_write_stdout(track_buffer: Vec<u8>) -> Result<String, TrackWriteError> {
+    let mut stdout = io::stdout().lock();
+
+    io::copy(&mut track_buffer.as_slice(), &mut stdout)
+        .and_then(|_| stdout.flush())
+        .map_err(|e| ProcessError {
+            kind: TrackWriteErrorKind::FileWrite,
+            error: e.into(),
+        })?;
+
+    Ok(String::from("<stdout>"))
+}
+
+fn find_ogg_files(dir: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+    let mut files = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            files.extend(find_ogg_files(&path)?);
+        } else if path.extension().map_or(false, |ext| ext == "ogg") {
+            files.push(path);
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+fn read_track_id(ogg_buffer: &[u8]) -> Result<Option<lsc::SpotifyId>, TagsWriteError> {
+    Ok(read_header_comment(ogg_buffer)?.and_then(|header| {
+        header
+            .comment_list
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(TAG_TRACK_ID))
+            .and_then(|(_, value)| lsc::SpotifyId::from_base62(value).ok())
+    }))
+}
+
+// Only the identification and comment headers are read, so indexing a library doesn't read every file in full
+fn read_embedded_track_id(input: impl io::Read + io::Seek) -> Option<String> {
+    let mut reader = ogg::PacketReader::new(input);
+
+    panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        reader.read_packet().ok()??;
+        let header = lhr::read_header_comment(&reader.read_packet().ok()??.data).ok()?;
+
+        header
+            .comment_list
+            .into_iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(TAG_TRACK_ID))
+            .map(|(_, value)| value)
+    }))
+    .ok()
+    .flatten()
+}
+
+// Base62 ids of everything downloaded with --download-archive, one per line. Ids are appended as soon as their file
+// is written, so an interrupted run still remembers what it finished
+struct DownloadArchive {
+    ids: sync::Mutex<coll::HashSet<String>>,
+    file: sync::Mutex<fs::File>,
+}
+
+impl DownloadArchive {
+    fn open(path: &path::Path) -> io::Result<Self> {
+        let ids = match fs::read_to_string(path) {
+            Ok(contents) => contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => coll::HashSet::new(),
+            Err(err) => return Err(err),
+        };
+
+        let file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(DownloadArchive {
+            ids: sync::Mutex::new(ids),
+            file: sync::Mutex::new(file),
+        })
+    }
+
+    fn contains(&self, ids: &[&lsc::SpotifyId]) -> bool {
+        let archived = self.ids.lock().unwrap();
+        ids.iter()
+            .any(|id| archived.contains(&id.to_base62().unwrap_or_default()))
+    }
+
+    fn record(&self, id: &lsc::SpotifyId) -> io::Result<()> {
+        let id = id.to_base62().unwrap_or_default();
+
+        if !self.ids.lock().unwrap().insert(id.clone()) {
+            return Ok(());
+        }
+
+        writeln!(self.file.lock().unwrap(), "{}", id)
+    }
+}
+
+// Recording failures only mean the item is downloaded again next time, so they don't fail it
+fn archive_item(ctx: &TrackContext, id: &lsc::SpotifyId) {
+    if let Some(Err(err)) = ctx.archive.as_ref().map(|archive| archive.record(id)) {
+        warn!(
+            "   - {}: cannot write to download archive: {}, continuing...",
+            "warning".yellow().bold(),
+            err.to_string().to_lowercase()
+        );
+    }
+}
+
+// Every track written with --db, keyed by its base62 id, so the files of a library can be looked up and checked later
+// without walking it. Downloading a track again replaces its row
+struct LibraryDb {
+    conn: sync::Mutex<rusqlite::Connection>,
+}
+
+impl LibraryDb {
+    fn open(path: &path::Path) -> rusqlite::Result<Self> {
+        let conn = rusqlite::Connection::open(path)?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS tracks (
+                id TEXT PRIMARY KEY,
+                isrc TEXT,
+                path TEXT NOT NULL,
+                bitrate INTEGER NOT NULL,
+                sha256 TEXT NOT NULL,
+                downloaded_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS tracks_isrc ON tracks (isrc);",
+        )?;
+
+        Ok(LibraryDb {
+            conn: sync::Mutex::new(conn),
+        })
+    }
+
+    fn record(&self, id: &str, isrc: Option<&str>, path: &str, bitrate: u32, sha256: &str) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO tracks (id, isrc, path, bitrate, sha256, downloaded_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            rusqlite::params![
+                id,
+                isrc,
+                path,
+                bitrate,
+                sha256,
+                ::time::OffsetDateTime::now_utc().unix_timestamp()
+            ],
+        )?;
+
+        Ok(())
+    }
+}
+
+fn file_sha256(path: &str) -> io::Result<String> {
+    let mut hasher = sha2::Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Like the download archive, a track missing from the database only means less is known about it later
+async fn db_record_track(db: &sync::Arc<LibraryDb>, track: &lsm::Track, output: &str, bitrate: u32) {
+    let db = db.clone();
+    let track_id = track.id.to_base62().unwrap_or_default();
+    let isrc = track_isrc(track);
+    let output = output.to_owned();
+
+    let recorded = run_blocking(move || {
+        let sha256 = file_sha256(&output).map_err(|err| err.to_string())?;
+        let path = fs::canonicalize(&output).map_or_else(|_| output.clone(), |path| path.display().to_string());
+
+        db.record(&track_id, isrc.as_deref(), &path, bitrate, &sha256)
+            .map_err(|err| err.to_string())
+    })
+    .await;
+
+    if let Err(err) = recorded {
+        warn!(
+            "   - {}: cannot write to library database: {}, continuing...",
+            "warning".yellow().bold(),
+            err.to_lowercase()
+        );
+    }
+}
+
+fn index_library(dir: &path::Path) -> io::Result<coll::HashMap<String, path::PathBuf>> {
+    let mut library = coll::HashMap::new();
+
+    for file in find_ogg_files(dir)? {
+        let track_id = fs::File::open(&file)
+            .ok()
+            .and_then(|input| read_embedded_track_id(io::BufReader::new(input)));
+
+        if let Some(track_id) = track_id {
+            library.entry(track_id).or_insert(file);
+        }
+    }
+
+    Ok(library)
+}
+
+// Files can be a little shorter than spotify's duration without anything missing, the ogg granule position only
+// counts complete audio frames
+static VERIFY_DURATION_TOLERANCE: stdtime::Duration = stdtime::Duration::from_secs(2);
+
+// Reads through the whole file, a truncated or otherwise damaged one either fails to parse or ends too early
+fn verify_audio_file(path: &str, container: AudioContainer, duration_ms: i32) -> Result<(), String> {
+    let expected = stdtime::Duration::from_millis(u64::try_from(duration_ms).unwrap_or(0));
+
+    let duration = match container {
+        AudioContainer::Ogg => {
+            let mut reader = ogg::PacketReader::new(io::BufReader::new(
+                fs::File::open(path).map_err(|e| format!("cannot be read: {}", e))?,
+            ));
+
+            // The ogg reader can panic on malformed streams, which is just another way of being damaged
+            panic::catch_unwind(panic::AssertUnwindSafe(|| -> Result<stdtime::Duration, String> {
+                let ident = reader
+                    .read_packet()
+                    .map_err(|e| e.to_string())?
+                    .ok_or("is empty")
+                    .and_then(|packet| lhr::read_header_ident(&packet.data).map_err(|_| "has no vorbis header"))?;
+
+                let mut granule: u64 = 0;
+
+                while let Some(packet) = reader.read_packet().map_err(|e| e.to_string())? {
+                    granule = packet.absgp_page();
+                }
+
+                Ok(stdtime::Duration::from_secs_f64(
+                    granule as f64 / f64::from(ident.audio_sample_rate.max(1)),
+                ))
+            }))
+            .map_err(|_| String::from("is not a valid ogg stream"))?
+            .map_err(|e| format!("is damaged: {}", e))?
+        }
+        AudioContainer::Mp4 => mp4ameta::Tag::read_from_path(path)
+            .map_err(|e| format!("is damaged: {}", e))?
+            .duration()
+            .ok_or("has no audio track")?,
+    };
+
+    if duration + VERIFY_DURATION_TOLERANCE < expected {
+        Err(format!(
+            "is only {}s long instead of {}s",
+            duration.as_secs(),
+            expected.as_secs()
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+// Files written before the bitrate tag existed still carry the nominal bitrate in their vorbis identification header
+fn read_bitrate(input: impl io::Read + io::Seek) -> Option<u32> {
+    let mut reader = ogg::PacketReader::new(input);
+
+    // The ogg reader can panic on malformed streams, which is no different from not knowing the bitrate here
+    panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        let ident = lhr::read_header_ident(&reader.read_packet().ok()??.data).ok()?;
+        let comment = lhr::read_header_comment(&reader.read_packet().ok()??.data).ok();
+
+        comment
+            .and_then(|header| {
+                header
+                    .comment_list
+                    .into_iter()
+                    .find(|(key, _)| key.eq_ignore_ascii_case(TAG_BITRATE))
+                    .and_then(|(_, value)| value.parse().ok())
+            })
+            .or(u32::try_from(ident.bitrate_nominal)
+                .ok()
+                .filter(|bitrate| *bitrate > 0)
+                .map(|bitrate| bitrate / 1000))
+    }))
+    .ok()
+    .flatten()
+}
+
+// Tags a complete file is expected to have, as written by rippify
+static AUDIT_TAGS: [&str; 3] = ["TRACKNUMBER", "DATE", "METADATA_BLOCK_PICTURE"];
+
+pub fn audit_tags(dir: &path::Path) -> Result<(), Error> {
+    let files = find_ogg_files(dir).map_err(|err| {
+        fatal(format!(
+            "cannot read directory \"{}\": {}",
+            dir.display(),
+            err.to_string().to_lowercase()
+        ))
+    })?;
+
+    summary!(
+        "{} Auditing {} files:",
+        "=>".green().bold(),
+        files.len().to_string().bold()
+    );
+
+    let mut num_missing: usize = 0;
+    let mut num_error: usize = 0;
+
+    for file in &files {
+        let header = fs::read(file)
+            .map_err(|e| e.to_string())
+            .and_then(|buffer| read_header_comment(&buffer).map_err(|e| e.to_string()));
+
+        let missing: Vec<_> = match header {
+            Ok(Some(header)) => AUDIT_TAGS
+                .iter()
+                .filter(|tag| !header.comment_list.iter().any(|(key, _)| key.eq_ignore_ascii_case(tag)))
+                .map(|tag| tag.to_lowercase())
+                .collect(),
+            Ok(None) => AUDIT_TAGS.iter().map(|tag| tag.to_lowercase()).collect(),
+            Err(err) => {
+                warn!(
+                    " {} {}: {}: {}",
+                    "->".yellow().bold(),
+                    file.display(),
+                    "warning".yellow().bold(),
+                    err
+                );
+                num_error += 1;
+                continue;
+            }
+        };
+
+        if !missing.is_empty() {
+            info!(
+                " {} {}: missing {}",
+                "->".yellow().bold(),
+                file.display(),
+                missing.join(", ")
+            );
+            num_missing += 1;
+        }
+    }
+
+    summary!("\n{} Audited files: ", "=>".green().bold(),);
+
+    summary!(" {} {} error", "->".yellow().bold(), num_error);
+
+    summary!(" {} {} missing tags", "->".yellow().bold(), num_missing);
+
+    summary!(
+        " {} {} complete",
+        "->".yellow().bold(),
+        files.len() - num_missing - num_error
+    );
+
+    summary!(" {} {} total audited", "->".yellow().bold(), files.len());
+
+    Ok(())
+}
+
+async fn retag_dir(session: &lsc::Session, dir: &path::Path, tags: &TagOptions) -> Result<(), Error> {
+    let files = find_ogg_files(dir).map_err(|err| {
+        fatal(format!(
+            "cannot read directory \"{}\": {}",
+            dir.display(),
+            err.to_string().to_lowercase()
+        ))
+    })?;
+
+    summary!(
+        "\n{} Retagging {} files:",
+        "=>".green().bold(),
+        files.len().to_string().bold()
+    );
+
+    let mut num_retagged: usize = 0;
+
+    let albums = AlbumCache::default();
+    let features = FeaturesCache::default();
+    let mut num_untracked: usize = 0;
+
+    for file in &files {
+        info!(" {} {}", "->".yellow().bold(), file.display());
+
+        let buffer = match fs::read(file) {
+            Ok(buffer) => buffer,
+            Err(err) => {
+                warn!(
+                    "   - {}: cannot read file: {}, skipping...",
+                    "warning".yellow().bold(),
+                    err
+                );
+                continue;
+            }
+        };
+
+        let track_id = match read_track_id(&buffer) {
+            Ok(Some(track_id)) => track_id,
+            Ok(None) => {
+                warn!(
+                    "   - {}: no embedded spotify track id, skipping...",
+                    "note".bright_blue().bold()
+                );
+                num_untracked += 1;
+                continue;
+            }
+            Err(err) => {
+                warn!("   - {}: {}, skipping...", "warning".yellow().bold(), err);
+                continue;
+            }
+        };
+
+        let track = match lsm::Track::get(session, &track_id).await {
+            Ok(track) => track,
+            Err(err) => {
+                warn!(
+                    "   - {}: cannot get metadata for track {}: {}, skipping...",
+                    "warning".yellow().bold(),
+                    track_id.to_base62().unwrap(),
+                    err
+                );
+                continue;
+            }
+        };
+
+        let album = match albums.get(session, &track.album.id).await {
+            Ok(album) => Some(album),
+            Err(err) => {
+                warn!(
+                    "   - {}: cannot get album metadata: {}, tagging without it...",
+                    "warning".yellow().bold(),
+                    err
+                );
+                None
+            }
+        };
+
+        let cover = match &album {
+            Some(album) => match albums.get_cover(session, album, &tags.cover_size).await {
+                Ok(cover) => Some(cover),
+                Err(err) => {
+                    warn!(
+                        "   - {}: cannot get cover art: {}, tagging without it...",
+                        "warning".yellow().bold(),
+                        err
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let features = if tags.audio_features {
+            features.get(session, &track.id).await.unwrap_or_else(|err| {
+                warn!(
+                    "   - {}: cannot get audio features: {}, tagging without them...",
+                    "warning".yellow().bold(),
+                    err
+                );
+                None
+            })
+        } else {
+            None
+        };
+
+        // Neither the bitrate nor the gain can be looked up again, so the ones of the existing file are kept
+        let sources = TagSources {
+            item: TaggedItem::Track(&track),
+            album: album.as_deref(),
+            cover: cover.as_deref(),
+            features: features.as_deref(),
+            lyrics: None,
+            bitrate: read_bitrate(io::Cursor::new(&buffer)),
+            normalization: read_normalization(&buffer),
+        };
+
+        let mut buffer_tags = Vec::<u8>::new();
+
+        if let Err(err) = track_add_metadata_tags(io::Cursor::new(&buffer), &mut buffer_tags, &sources, tags) {
+            warn!("   - {}: {}, skipping...", "warning".yellow().bold(), err);
+            continue;
+        }
+
+        let output_file = OutputFile {
+            dir: None,
+            file: file.to_string_lossy().into_owned(),
+        };
+
+        match track_write(buffer_tags, output_file) {
+            Ok(output) => {
+                info!("   - retagged \"{}\"", output);
+                num_retagged += 1;
+            }
+            Err(err) => {
+                warn!("   - {}: {}, skipping...", "warning".yellow().bold(), err);
+                continue;
+            }
+        };
+    }
+
+    summary!("\n{} Processed files: ", "=>".green().bold(),);
+
+    summary!(
+        " {} {} error",
+        "->".yellow().bold(),
+        files.len() - num_retagged - num_untracked
+    );
+
+    summary!(" {} {} without track id", "->".yellow().bold(), num_untracked);
+
+    summary!(" {} {} retagged", "->".yellow().bold(), num_retagged);
+
+    summary!(" {} {} total processed", "->".yellow().bold(), files.len());
+
+    Ok(())
+}
+
+// What a file is tagged after, episodes only get the few tags that make sense for them
+#[derive(Clone, Copy)]
+enum TaggedItem<'a> {
+    Track(&'a lsm::Track),
+    Episode(&'a lsm::Episode),
+}
+
+// Everything a track is tagged with besides the user's tag options
+struct TagSources<'a> {
+    item: TaggedItem<'a>,
+    album: Option<&'a lsm::Album>,
+    cover: Option<&'a Cover>,
+    features: Option<&'a AudioFeatures>,
+    lyrics: Option<&'a lsm::Lyrics>,
+    bitrate: Option<u32>,
+    // Only known once the audio is downloaded, or read back from an existing file
+    normalization: Option<Normalization>,
+}
+
+// Copy of the tag sources for tagging on the blocking pool, which can't borrow from the track task
+struct OwnedTagSources {
+    item: OwnedTaggedItem,
+    album: Option<lsm::Album>,
+    cover: Option<Cover>,
+    features: Option<AudioFeatures>,
+    lyrics: Option<lsm::Lyrics>,
+    bitrate: Option<u32>,
+    normalization: Option<Normalization>,
+}
+
+enum OwnedTaggedItem {
+    Track(lsm::Track),
+    Episode(lsm::Episode),
+}
+
+impl TagSources<'_> {
+    fn owned(&self) -> OwnedTagSources {
+        OwnedTagSources {
+            item: match self.item {
+                TaggedItem::Track(track) => OwnedTaggedItem::Track(track.clone()),
+                TaggedItem::Episode(episode) => OwnedTaggedItem::Episode(episode.clone()),
+            },
+            album: self.album.cloned(),
+            cover: self.cover.cloned(),
+            features: self.features.cloned(),
+            lyrics: self.lyrics.cloned(),
+            bitrate: self.bitrate,
+            normalization: self.normalization,
+        }
+    }
+}
+
+impl OwnedTagSources {
+    fn borrow(&self) -> TagSources<'_> {
+        TagSources {
+            item: match &self.item {
+                OwnedTaggedItem::Track(track) => TaggedItem::Track(track),
+                OwnedTaggedItem::Episode(episode) => TaggedItem::Episode(episode),
+            },
+            album: self.album.as_ref(),
+            cover: self.cover.as_ref(),
+            features: self.features.as_ref(),
+            lyrics: self.lyrics.as_ref(),
+            bitrate: self.bitrate,
+            normalization: self.normalization,
+        }
+    }
+}
+
+// Where the replaygain tags come from. Spotify ships the gain of every track and of its album in the header of the
+// audio, measuring the loudness locally is slower but doesn't depend on how spotify mastered its figures
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Normalize {
+    Spotify,
+    Compute,
+    Off,
+}
+
+// Gain in dB that brings a track or album to the reference loudness, and its highest sample with 1.0 as full scale
+#[derive(Clone, Copy)]
+struct Gain {
+    db: f32,
+    peak: f32,
+}
+
+#[derive(Clone, Copy)]
+struct Normalization {
+    track: Gain,
+    album: Option<Gain>,
+}
+
+// The header holds the track gain and peak followed by the album gain and peak, as little endian floats. Spotify's
+// gains bring tracks to -14 LUFS while replaygain 2 players expect them for -18 LUFS, so they're lowered by 4dB
+static SPOTIFY_NORMALIZATION_OFFSET: usize = 144;
+static SPOTIFY_REFERENCE_OFFSET: f32 = -4.0;
+static REPLAYGAIN_REFERENCE_LUFS: f64 = -18.0;
+
+fn read_spotify_normalization(header: &[u8]) -> Option<Normalization> {
+    let values: Vec<f32> = header
+        .get(SPOTIFY_NORMALIZATION_OFFSET..SPOTIFY_NORMALIZATION_OFFSET + 16)?
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect();
+
+    if !values.iter().all(|value| value.is_finite()) {
+        return None;
+    }
+
+    Some(Normalization {
+        track: Gain {
+            db: values[0] + SPOTIFY_REFERENCE_OFFSET,
+            peak: values[1],
+        },
+        album: Some(Gain {
+            db: values[2] + SPOTIFY_REFERENCE_OFFSET,
+            peak: values[3],
+        }),
+    })
+}
+
+// Integrated EBU R128 loudness of the whole track. Lewton can panic on malformed streams, like the ogg reader
+fn measure_loudness(mut input: impl io::Read + io::Seek) -> Result<Gain, String> {
+    input.seek(io::SeekFrom::Start(0)).map_err(|err| err.to_string())?;
+
+    panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        let mut reader = lewton::inside_ogg::OggStreamReader::new(input).map_err(|err| err.to_string())?;
+        let channels = u32::from(reader.ident_hdr.audio_channels);
+
+        let mut meter = ebur128::EbuR128::new(
+            channels,
+            reader.ident_hdr.audio_sample_rate,
+            ebur128::Mode::I | ebur128::Mode::SAMPLE_PEAK,
+        )
+        .map_err(|err| err.to_string())?;
+
+        while let Some(samples) = reader.read_dec_packet_itl().map_err(|err| err.to_string())? {
+            meter.add_frames_i16(&samples).map_err(|err| err.to_string())?;
+        }
+
+        let loudness = meter.loudness_global().map_err(|err| err.to_string())?;
+
+        if !loudness.is_finite() {
+            return Err(String::from("track is silent"));
+        }
+
+        let mut peak: f64 = 0.0;
+
+        for channel in 0..channels {
+            peak = peak.max(meter.sample_peak(channel).map_err(|err| err.to_string())?);
+        }
+
+        Ok(Gain {
+            db: (REPLAYGAIN_REFERENCE_LUFS - loudness) as f32,
+            peak: peak as f32,
+        })
+    }))
+    .unwrap_or_else(|_| Err(String::from("cannot decode audio")))
+}
+
+// A single track can't tell the loudness of its album, so computed gains keep the album gain of spotify
+fn normalized_sources<'a>(
+    sources: &TagSources<'a>,
+    spotify: Option<Normalization>,
+    audio: impl io::Read + io::Seek,
+    container: AudioContainer,
+    tags: &TagOptions,
+) -> TagSources<'a> {
+    let normalization = match tags.normalize {
+        Normalize::Off => None,
+        Normalize::Spotify => spotify,
+        // Aac files have neither a header nor a decoder here
+        Normalize::Compute if container != AudioContainer::Ogg => spotify,
+        Normalize::Compute => match measure_loudness(audio) {
+            Ok(track) => Some(Normalization {
+                track,
+                album: spotify.and_then(|spotify| spotify.album),
+            }),
+            Err(err) => {
+                warn!(
+                    "   - {}: cannot measure loudness: {}, using the gain of spotify instead...",
+                    "warning".yellow().bold(),
+                    err
+                );
+                spotify
+            }
+        },
+    };
+
+    TagSources {
+        normalization,
+        ..*sources
+    }
+}
+
+fn replay_gain_tags(normalization: &Normalization) -> Vec<(String, String)> {
+    let mut tags = Vec::new();
+
+    for (kind, gain) in [("track", Some(normalization.track)), ("album", normalization.album)] {
+        if let Some(gain) = gain {
+            tags.push((format!("replaygain_{}_gain", kind), format!("{:.2} dB", gain.db)));
+            tags.push((format!("replaygain_{}_peak", kind), format!("{:.6}", gain.peak)));
+        }
+    }
+
+    tags
+}
+
+// Files without a track gain have no normalization to keep, the peaks are optional
+fn read_normalization(ogg_buffer: &[u8]) -> Option<Normalization> {
+    let header = read_header_comment(ogg_buffer).ok()??;
+
+    let value = |key: String| {
+        header
+            .comment_list
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&key))
+            .and_then(|(_, value)| value.trim_end_matches("dB").trim().parse::<f32>().ok())
+    };
+
+    let gain = |kind: &str| {
+        Some(Gain {
+            db: value(format!("replaygain_{}_gain", kind))?,
+            peak: value(format!("replaygain_{}_peak", kind)).unwrap_or(1.0),
+        })
+    };
+
+    Some(Normalization {
+        track: gain("track")?,
+        album: gain("album"),
+    })
+}
+
+fn track_add_metadata_tags(
+    input: impl io::Read + io::Seek,
+    output: impl io::Write,
+    sources: &TagSources,
+    tags: &TagOptions,
+) -> Result<(), TagsWriteError> {
+    let track = match sources.item {
+        TaggedItem::Track(track) => track,
+        TaggedItem::Episode(episode) => return episode_add_metadata_tags(input, output, episode, sources),
+    };
+
+    let mut metadata = lhr::CommentHeader {
+        vendor: String::from("Ogg"),
+        comment_list: Vec::new(),
+    };
+
+    metadata
+        .comment_list
+        .push((String::from("title"), track_title(track, tags.strip_featured)));
+    metadata
+        .comment_list
+        .push((String::from("album"), track.album.name.clone()));
+
+    metadata.comment_list.extend(
+        track
+            .artists
+            .iter()
+            .map(|artist| (String::from("artist"), artist.name.clone()))
+            .collect::<Vec<_>>(),
+    );
+
+    metadata
+        .comment_list
+        .push((String::from("tracknumber"), track.number.to_string()));
+    metadata
+        .comment_list
+        .push((String::from("discnumber"), track.disc_number.to_string()));
+    metadata
+        .comment_list
+        .push((String::from("date"), track_release_date(track)));
+
+    if track.is_explicit {
+        metadata
+            .comment_list
+            .push((String::from("explicit"), String::from("1")));
+    }
+
+    if let Ok(track_id) = track.id.to_base62() {
+        metadata.comment_list.push((String::from(TAG_TRACK_ID), track_id));
+    }
+
+    if let Ok(album_id) = track.album.id.to_base62() {
+        metadata.comment_list.push((String::from(TAG_ALBUM_ID), album_id));
+    }
+
+    // Picard and beets look these up to match a file to its musicbrainz release without relying on names
+    if let Some(isrc) = track_isrc(track) {
+        metadata.comment_list.push((String::from("isrc"), isrc));
+    }
+
+    if let Some(upc) = sources.album.and_then(album_upc) {
+        metadata.comment_list.push((String::from("barcode"), upc));
+    }
+
+    if let Some(bitrate) = sources.bitrate {
+        metadata
+            .comment_list
+            .push((String::from(TAG_BITRATE), bitrate.to_string()));
+    }
+
+    if let Some(normalization) = &sources.normalization {
+        metadata.comment_list.extend(replay_gain_tags(normalization));
+    }
+
+    if let Some(album) = sources.album {
+        let total = album.tracks().count().to_string();
+
+        // Players disagree on which of the two names to read, so both are written
+        metadata.comment_list.push((String::from("tracktotal"), total.clone()));
+        metadata.comment_list.push((String::from("totaltracks"), total));
+
+        let discs = album.discs.len().to_string();
+        metadata.comment_list.push((String::from("disctotal"), discs.clone()));
+        metadata.comment_list.push((String::from("totaldiscs"), discs));
+
+        if !album.label.is_empty() {
+            metadata.comment_list.push((String::from("label"), album.label.clone()));
+        }
+    }
+
+    if tags.compilation.is_compilation(track) {
+        metadata
+            .comment_list
+            .push((String::from("compilation"), String::from("1")));
+        metadata
+            .comment_list
+            .push((String::from("albumartist"), String::from(VARIOUS_ARTISTS)));
+    } else if tags.album_artist {
+        if let Some(artist) = track.album.artists.first() {
+            metadata
+                .comment_list
+                .push((String::from("albumartist"), artist.name.clone()));
+        }
+    }
+
+    if let Some(lyrics) = sources.lyrics {
+        let text = lyrics_text(lyrics);
+
+        // Same as for the track total, players look for the lyrics under either name
+        metadata.comment_list.push((String::from("lyrics"), text.clone()));
+        metadata.comment_list.push((String::from("unsyncedlyrics"), text));
+    }
+
+    if let Some(features) = sources.features {
+        metadata
+            .comment_list
+            .push((String::from("bpm"), features.bpm().to_string()));
+
+        if let Some(key) = features.initial_key() {
+            metadata.comment_list.push((String::from("initialkey"), key));
+        }
+    }
+
+    if let Some(cover) = sources.cover {
+        metadata.comment_list.push((
+            String::from("metadata_block_picture"),
+            base64::engine::general_purpose::STANDARD.encode(make_picture_block(cover)),
+        ));
+    }
+
+    write_comment_header(input, output, &metadata)
+}
+
+fn episode_add_metadata_tags(
+    input: impl io::Read + io::Seek,
+    output: impl io::Write,
+    episode: &lsm::Episode,
+    sources: &TagSources,
+) -> Result<(), TagsWriteError> {
+    let mut metadata = lhr::CommentHeader {
+        vendor: String::from("Ogg"),
+        comment_list: Vec::new(),
+    };
+
+    metadata
+        .comment_list
+        .push((String::from("title"), episode.name.clone()));
+    metadata
+        .comment_list
+        .push((String::from("album"), episode.show_name.clone()));
+    metadata
+        .comment_list
+        .push((String::from("artist"), episode.show_name.clone()));
+    metadata
+        .comment_list
+        .push((String::from("date"), episode_release_date(episode)));
+    metadata
+        .comment_list
+        .push((String::from("genre"), String::from("Podcast")));
+
+    // Spotify numbers episodes only for some shows
+    if episode.number > 0 {
+        metadata
+            .comment_list
+            .push((String::from("tracknumber"), episode.number.to_string()));
+    }
+
+    if !episode.description.is_empty() {
+        metadata
+            .comment_list
+            .push((String::from("description"), episode.description.clone()));
+    }
+
+    if let Ok(episode_id) = episode.id.to_base62() {
+        metadata.comment_list.push((String::from(TAG_EPISODE_ID), episode_id));
+    }
+
+    if let Some(bitrate) = sources.bitrate {
+        metadata
+            .comment_list
+            .push((String::from(TAG_BITRATE), bitrate.to_string()));
+    }
+
+    if let Some(cover) = sources.cover {
+        metadata.comment_list.push((
+            String::from("metadata_block_picture"),
+            base64::engine::general_purpose::STANDARD.encode(make_picture_block(cover)),
+        ));
+    }
+
+    write_comment_header(input, output, &metadata)
+}
+
+fn write_comment_header(
+    input: impl io::Read + io::Seek,
+    output: impl io::Write,
+    metadata: &lhr::CommentHeader,
+) -> Result<(), TagsWriteError> {
+    // The ogg and lewton readers can panic on malformed streams, which shouldn't take the whole run down
+    // Whatever was written to the output is discarded by the callers on failure, so asserting unwind safety is fine
+    panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        replace_header_comment(input, output, metadata)
+    }))
+    .unwrap_or_else(|_| {
+        Err(TagsWriteError {
+            kind: TagsWriteErrorKind::Read,
+            error: "malformed ogg stream".into(),
+        })
+    })
+}
+
+// Unlike vorbis comments, mp4 tags live in their own atoms that can be rewritten in place, so they're added to the
+// finished output file instead of while copying the audio
+fn track_add_mp4_tags(path: &str, sources: &TagSources, tags: &TagOptions) -> Result<(), TagsWriteError> {
+    let track = match sources.item {
+        TaggedItem::Track(track) => track,
+        TaggedItem::Episode(episode) => return episode_add_mp4_tags(path, episode, sources),
+    };
+
+    let mut metadata = mp4ameta::Tag::read_from_path(path).map_err(|e| TagsWriteError {
+        kind: TagsWriteErrorKind::Mp4Read,
+        error: e.into(),
+    })?;
+
+    metadata.set_title(track_title(track, tags.strip_featured));
+    metadata.set_album(track.album.name.clone());
+
+    for artist in &track.artists {
+        metadata.add_artist(artist.name.clone());
+    }
+
+    metadata.set_track_number(track.number as u16);
+    metadata.set_disc_number(track.disc_number as u16);
+    metadata.set_year(track_release_date(track));
+
+    if track.is_explicit {
+        metadata.set_advisory_rating(mp4ameta::AdvisoryRating::Explicit);
+    }
+
+    if let Some(album) = sources.album {
+        metadata.set_total_tracks(album.tracks().count() as u16);
+        metadata.set_total_discs(album.discs.len() as u16);
+
+        if !album.label.is_empty() {
+            metadata.set_data(
+                mp4ameta::FreeformIdent::new("com.apple.iTunes", "LABEL"),
+                mp4ameta::Data::Utf8(album.label.clone()),
+            );
+        }
+    }
+
+    if tags.compilation.is_compilation(track) {
+        metadata.set_compilation();
+        metadata.set_album_artist(VARIOUS_ARTISTS);
+    } else if tags.album_artist {
+        if let Some(artist) = track.album.artists.first() {
+            metadata.set_album_artist(artist.name.clone());
+        }
+    }
+
+    if let Ok(track_id) = track.id.to_base62() {
+        metadata.set_data(
+            mp4ameta::FreeformIdent::new("com.apple.iTunes", TAG_TRACK_ID),
+            mp4ameta::Data::Utf8(track_id),
+        );
+    }
+
+    if let Ok(album_id) = track.album.id.to_base62() {
+        metadata.set_data(
+            mp4ameta::FreeformIdent::new("com.apple.iTunes", TAG_ALBUM_ID),
+            mp4ameta::Data::Utf8(album_id),
+        );
+    }
+
+    if let Some(isrc) = track_isrc(track) {
+        metadata.set_data(
+            mp4ameta::FreeformIdent::new("com.apple.iTunes", "ISRC"),
+            mp4ameta::Data::Utf8(isrc),
+        );
+    }
+
+    if let Some(upc) = sources.album.and_then(album_upc) {
+        metadata.set_data(
+            mp4ameta::FreeformIdent::new("com.apple.iTunes", "BARCODE"),
+            mp4ameta::Data::Utf8(upc),
+        );
+    }
+
+    if let Some(bitrate) = sources.bitrate {
+        metadata.set_data(
+            mp4ameta::FreeformIdent::new("com.apple.iTunes", TAG_BITRATE),
+            mp4ameta::Data::Utf8(bitrate.to_string()),
+        );
+    }
+
+    if let Some(lyrics) = sources.lyrics {
+        metadata.set_lyrics(lyrics_text(lyrics));
+    }
+
+    if let Some(features) = sources.features {
+        metadata.set_bpm(features.bpm());
+
+        if let Some(key) = features.initial_key() {
+            metadata.set_data(
+                mp4ameta::FreeformIdent::new("com.apple.iTunes", "initialkey"),
+                mp4ameta::Data::Utf8(key),
+            );
+        }
+    }
+
+    if let Some(cover) = sources.cover {
+        metadata.set_artwork(if cover.data.starts_with(b"\x89PNG") {
+            mp4ameta::Img::png(cover.data.clone())
+        } else {
+            mp4ameta::Img::jpeg(cover.data.clone())
+        });
+    }
+
+    metadata.write_to_path(path).map_err(|e| TagsWriteError {
+        kind: TagsWriteErrorKind::Mp4Write,
+        error: e.into(),
+    })
+}
+
+fn episode_add_mp4_tags(path: &str, episode: &lsm::Episode, sources: &TagSources) -> Result<(), TagsWriteError> {
+    let mut metadata = mp4ameta::Tag::read_from_path(path).map_err(|e| TagsWriteError {
+        kind: TagsWriteErrorKind::Mp4Read,
+        error: e.into(),
+    })?;
+
+    metadata.set_title(episode.name.clone());
+    metadata.set_album(episode.show_name.clone());
+    metadata.add_artist(episode.show_name.clone());
+    metadata.set_year(episode_release_date(episode));
+    metadata.set_genre("Podcast");
+
+    if episode.number > 0 {
+        metadata.set_track_number(episode.number as u16);
+    }
+
+    if !episode.description.is_empty() {
+        metadata.set_description(episode.description.clone());
+    }
+
+    if let Ok(episode_id) = episode.id.to_base62() {
+        metadata.set_data(
+            mp4ameta::FreeformIdent::new("com.apple.iTunes", TAG_EPISODE_ID),
+            mp4ameta::Data::Utf8(episode_id),
+        );
+    }
+
+    if let Some(bitrate) = sources.bitrate {
+        metadata.set_data(
+            mp4ameta::FreeformIdent::new("com.apple.iTunes", TAG_BITRATE),
+            mp4ameta::Data::Utf8(bitrate.to_string()),
+        );
+    }
+
+    if let Some(cover) = sources.cover {
+        metadata.set_artwork(if cover.data.starts_with(b"\x89PNG") {
+            mp4ameta::Img::png(cover.data.clone())
+        } else {
+            mp4ameta::Img::jpeg(cover.data.clone())
+        });
+    }
+
+    metadata.write_to_path(path).map_err(|e| TagsWriteError {
+        kind: TagsWriteErrorKind::Mp4Write,
+        error: e.into(),
+    })
+}
+
+// Only the tags are written again, the bitrate and gain stay the ones the audio was downloaded with
+fn track_refresh_tags(file: &str, sources: &TagSources, tags: &TagOptions) -> Result<(), String> {
+    let extension = path::Path::new(file)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase());
+
+    match extension.as_deref() {
+        Some("m4a") => return track_add_mp4_tags(file, sources, tags).map_err(|err| err.to_string()),
+        Some("ogg") => {}
+        _ => return Err(String::from("only .ogg and .m4a files can be retagged")),
+    }
+
+    let buffer = fs::read(file).map_err(|err| format!("cannot read existing file: {}", err))?;
+
+    let sources = TagSources {
+        bitrate: read_bitrate(io::Cursor::new(&buffer)),
+        normalization: read_normalization(&buffer),
+        ..*sources
+    };
+
+    let mut buffer_tags = Vec::<u8>::new();
+    track_add_metadata_tags(io::Cursor::new(&buffer), &mut buffer_tags, &sources, tags)
+        .map_err(|err| err.to_string())?;
+
+    let output_file = OutputFile {
+        dir: None,
+        file: file.to_owned(),
+    };
+
+    track_write(buffer_tags, output_file)
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+fn track_tag_mp4_output(path: &str, sources: &TagSources, tags: &TagOptions) {
+    // The audio is already written at this point, so a failure only leaves it untagged
+    if let Err(err) = track_add_mp4_tags(path, sources, tags) {
+        warn!(
+            "   - {}: {}, leaving the audio untagged...",
+            "warning".yellow().bold(),
+            err
+        );
+    }
+}
+
+// Reverse implementation of https://github.com/RustAudio/lewton/blob/bb2955b717094b40260902cf2f8dd9c5ea62a84a/src/header.rs#L309
+fn make_header_comment(header: &lhr::CommentHeader) -> Option<Vec<u8>> {
+    let mut packet: Vec<u8> = vec![];
+
+    // 'V' 'O' 'R' 'B' 'I' 'S'
+    packet.extend([0x03, 0x76, 0x6F, 0x72, 0x62, 0x69, 0x73] as [u8; 7]);
+
+    let vendor_buf = header.vendor.as_bytes();
+    let vendor_len = TryInto::<u32>::try_into(vendor_buf.len()).ok()?.to_le_bytes();
+
+    packet.extend(vendor_len);
+    packet.extend(vendor_buf);
+
+    let comments_len = TryInto::<u32>::try_into(header.comment_list.len()).ok()?.to_le_bytes();
+
+    packet.extend(comments_len);
+
+    for comment in &header.comment_list {
+        let comment_buf = format!("{}={}", comment.0, comment.1);
+        let comment_buf = comment_buf.as_bytes();
+        let comment_len = TryInto::<u32>::try_into(comment_buf.len()).ok()?.to_le_bytes();
+
+        packet.extend(comment_len);
+        packet.extend(comment_buf);
+    }
+
+    packet.extend([0x01] as [u8; 1]);
+    Some(packet)
+}
+
+// FLAC picture block as embedded in vorbis comments, see https://xiph.org/flac/format.html#metadata_block_picture
+fn make_picture_block(cover: &Cover) -> Vec<u8> {
+    let mime = if cover.data.starts_with(b"\x89PNG") {
+        "image/png"
+    } else {
+        "image/jpeg"
+    };
+
+    let mut block: Vec<u8> = vec![];
+
+    // Picture type 3 is the front cover
+    block.extend(3u32.to_be_bytes());
+    block.extend((mime.len() as u32).to_be_bytes());
+    block.extend(mime.as_bytes());
+
+    // Empty description
+    block.extend(0u32.to_be_bytes());
+
+    block.extend(cover.width.to_be_bytes());
+    block.extend(cover.height.to_be_bytes());
+
+    // Color depth and number of indexed colors, the latter is 0 for non indexed images
+    block.extend(24u32.to_be_bytes());
+    block.extend(0u32.to_be_bytes());
+
+    block.extend((cover.data.len() as u32).to_be_bytes());
+    block.extend(&cover.data);
+
+    block
+}
+
+enum TagsWriteErrorKind {
+    Read,
+    Write,
+    Header,
+    Mp4Read,
+    Mp4Write,
+}
+
+impl fmt::Display for TagsWriteErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TagsWriteErrorKind::Read => write!(f, "cannot read ogg packet"),
+            TagsWriteErrorKind::Write => write!(f, "cannot write ogg packet"),
+            TagsWriteErrorKind::Header => write!(f, "cannot create comment header packet"),
+            TagsWriteErrorKind::Mp4Read => write!(f, "cannot read mp4 tags"),
+            TagsWriteErrorKind::Mp4Write => write!(f, "cannot write mp4 tags"),
+        }
+    }
+}
+
+impl ProcessErrorKind for TagsWriteErrorKind {}
+type TagsWriteError = ProcessError<TagsWriteErrorKind>;
+
+fn read_header_comment(ogg_buffer: &[u8]) -> Result<Option<lhr::CommentHeader>, TagsWriteError> {
+    let mut reader = ogg::PacketReader::new(io::Cursor::new(ogg_buffer));
+
+    while let Some(packet) = reader.read_packet().map_err(|e| TagsWriteError {
+        kind: TagsWriteErrorKind::Read,
+        error: e.into(),
+    })? {
+        if let Ok(header) = lhr::read_header_comment(&packet.data) {
+            return Ok(Some(header));
+        }
+    }
+
+    Ok(None)
+}
+
+// Based on https://github.com/RustAudio/ogg/blob/0910d8d57645eccc1a1400731fefef376859c661/examples/repack.rs#L52
+fn replace_header_comment(
+    input: impl io::Read + io::Seek,
+    output: impl io::Write,
+    comment_header: &lhr::CommentHeader,
+) -> Result<(), TagsWriteError> {
+    let mut reader = ogg::PacketReader::new(input);
+    let mut writer = ogg::PacketWriter::new(output);
+
+    let mut overwrote_header = false;
+
+    loop {
+        if let Some(mut packet) = reader.read_packet().map_err(|e| TagsWriteError {
+            kind: TagsWriteErrorKind::Read,
+            error: e.into(),
+        })? {
+            if !overwrote_header {
+                if let Ok(_) = lhr::read_header_comment(&packet.data) {
+                    packet.data = make_header_comment(comment_header).ok_or(TagsWriteError {
+                        kind: TagsWriteErrorKind::Header,
+                        error: "invalid header comment data".into(),
+                    })?;
+                    overwrote_header = true;
+                }
+            }
+
+            let packet_inf = if packet.last_in_stream() {
+                ogg::PacketWriteEndInfo::EndStream
+            } else if packet.last_in_page() {
+                ogg::PacketWriteEndInfo::EndPage
+            } else {
+                ogg::PacketWriteEndInfo::NormalPacket
+            };
+
+            let packet_serial = packet.stream_serial();
+            let packet_absgp = packet.absgp_page();
+
+            writer
+                .write_packet(packet.data, packet_serial, packet_inf, packet_absgp)
+                .map_err(|e| TagsWriteError {
+                    kind: TagsWriteErrorKind::Write,
+                    error: e.into(),
+                })?;
+        } else {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output_format(sanitize: Sanitize) -> OutputFormat {
+        OutputFormat {
+            format_string: String::from("{artist}/{album}/{name}"),
+            number_width: 2,
+            replace_spaces: None,
+            strip_featured: false,
+            sanitize,
+            sanitize_replacement: '_',
+            disc_folders: false,
+        }
+    }
+
+    #[test]
+    fn component_composes_decomposed_strings() {
+        let format = output_format(Sanitize::Posix);
+
+        assert_eq!(format.component("Cafe\u{301}"), format.component("Caf\u{e9}"));
+        assert_eq!(format.component("Cafe\u{301}"), "Caf\u{e9}");
+    }
+
+    #[test]
+    fn component_composes_before_substituting() {
+        let format = output_format(Sanitize::Strict);
+
+        // Substituting first would keep the e and only replace the combining accent, as in Cafe_
+        assert_eq!(format.component("Cafe\u{301}"), "Caf_");
+        assert_eq!(format.component("Caf\u{e9}"), "Caf_");
+    }
+
+    fn audio_files(formats: &[lsm_audio::AudioFileFormat]) -> lsm_audio::AudioFiles {
+        lsm_audio::AudioFiles(
+            formats
+                .iter()
+                .enumerate()
+                .map(|(i, format)| (*format, lsc::FileId([i as u8; 20])))
+                .collect(),
+        )
+    }
+
+    fn free_selection(strict: bool) -> AudioSelection {
+        AudioSelection {
+            bitrate: 320,
+            limit: FREE_MAX_BITRATE,
+            strict,
+            formats: AUDIO_FORMATS.to_vec(),
+        }
+    }
+
+    #[test]
+    fn strict_quality_is_not_lowered_to_the_account_limit() {
+        let files = audio_files(&[
+            lsm_audio::AudioFileFormat::OGG_VORBIS_320,
+            lsm_audio::AudioFileFormat::OGG_VORBIS_160,
+        ]);
+
+        assert!(select_audio_file(&files, &free_selection(true)).is_none());
+        assert_eq!(
+            select_audio_file(&files, &free_selection(false)).map(|source| source.bitrate),
+            Some(160)
+        );
+    }
+
+    #[test]
+    fn same_recording_needs_an_isrc() {
+        assert!(same_recording(Some("USRC17607839"), Some("USRC17607839")));
+        assert!(!same_recording(Some("USRC17607839"), Some("GBAYE0601498")));
+        assert!(!same_recording(Some("USRC17607839"), None));
+        assert!(!same_recording(None, Some("USRC17607839")));
+        assert!(!same_recording(None, None));
+    }
+
+    // A page with a single segment, which is all the spotify header needs
+    fn ogg_page(body_len: u8) -> Vec<u8> {
+        let mut page = OGG_CAPTURE_PATTERN.to_vec();
+        page.resize(OGG_PAGE_HEADER_LEN - 1, 0);
+        page.push(1);
+        page.push(body_len);
+        page.extend(std::iter::repeat(0xaa).take(body_len as usize));
+        page
+    }
+
+    #[test]
+    fn audio_header_is_read_from_the_page() {
+        let header = ogg_page(200);
+        let mut audio = header.clone();
+        audio.extend(ogg_page(10));
+
+        let mut input = io::Cursor::new(audio);
+
+        assert_ne!(header.len(), 0xa7);
+        assert_eq!(read_audio_header(&mut input).unwrap(), header);
+        assert_eq!(input.position(), header.len() as u64);
+    }
+
+    #[test]
+    fn audio_header_needs_a_capture_pattern() {
+        let mut input = io::Cursor::new(vec![0u8; 512]);
+
+        let err = read_audio_header(&mut input).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn audio_header_needs_the_stream_after_it() {
+        let mut audio = ogg_page(200);
+        audio.extend(vec![0u8; 64]);
+
+        let err = read_audio_header(&mut io::Cursor::new(audio)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn audio_header_rejects_a_truncated_page() {
+        let mut audio = ogg_page(200);
+        audio.truncate(100);
+
+        assert!(read_audio_header(&mut io::Cursor::new(audio)).is_err());
+    }
+
+    #[test]
+    fn featured_credits_of_other_artists_are_stripped() {
+        let others = ["Pharrell Williams", "Nile Rodgers"];
+
+        assert_eq!(
+            strip_featured_credit("Get Lucky (feat. Pharrell Williams & Nile Rodgers)", &others),
+            "Get Lucky"
+        );
+        assert_eq!(
+            strip_featured_credit("Get Lucky [ft. pharrell williams]", &others),
+            "Get Lucky"
+        );
+        assert_eq!(
+            strip_featured_credit("Get Lucky (with Nile Rodgers)", &others),
+            "Get Lucky"
+        );
+    }
+
+    #[test]
+    fn other_parentheses_are_kept() {
+        let others = ["Pharrell Williams"];
+
+        assert_eq!(
+            strip_featured_credit("Get Lucky (Radio Edit)", &others),
+            "Get Lucky (Radio Edit)"
+        );
+        assert_eq!(
+            strip_featured_credit("Get Lucky (feat. Somebody Else)", &others),
+            "Get Lucky (feat. Somebody Else)"
+        );
+        assert_eq!(
+            strip_featured_credit("Get Lucky (feat. Pharrell Williams)", &[]),
+            "Get Lucky (feat. Pharrell Williams)"
+        );
+    }
+
+    // A vorbis stream with only its identification and comment headers, which is all the tag readers look at
+    fn vorbis_headers(bitrate_nominal: i32, comments: Vec<(String, String)>) -> Vec<u8> {
+        let mut ident = vec![0x01];
+        ident.extend(b"vorbis");
+        ident.extend(0u32.to_le_bytes());
+        ident.push(2);
+        ident.extend(44100u32.to_le_bytes());
+        ident.extend(0i32.to_le_bytes());
+        ident.extend(bitrate_nominal.to_le_bytes());
+        ident.extend(0i32.to_le_bytes());
+        // Block sizes of 256 and 2048 samples, followed by the framing bit
+        ident.push(0xb8);
+        ident.push(0x01);
+
+        let comment = make_header_comment(&lhr::CommentHeader {
+            vendor: String::from("Ogg"),
+            comment_list: comments,
+        })
+        .unwrap();
+
+        let mut buffer = Vec::new();
+        let mut writer = ogg::PacketWriter::new(&mut buffer);
+
+        writer
+            .write_packet(ident, 1, ogg::PacketWriteEndInfo::EndPage, 0)
+            .unwrap();
+        writer
+            .write_packet(comment, 1, ogg::PacketWriteEndInfo::EndStream, 0)
+            .unwrap();
+
+        drop(writer);
+        buffer
+    }
+
+    #[test]
+    fn bitrate_is_read_from_the_tag() {
+        let file = vorbis_headers(320_000, vec![(String::from(TAG_BITRATE), String::from("160"))]);
+
+        assert_eq!(read_bitrate(io::Cursor::new(file)), Some(160));
+    }
+
+    #[test]
+    fn bitrate_falls_back_to_the_nominal_bitrate() {
+        assert_eq!(
+            read_bitrate(io::Cursor::new(vorbis_headers(320_000, Vec::new()))),
+            Some(320)
+        );
+        assert_eq!(read_bitrate(io::Cursor::new(vorbis_headers(0, Vec::new()))), None);
+    }
+
+    #[test]
+    fn replay_gain_tags_are_read_back() {
+        let normalization = Normalization {
+            track: Gain { db: -6.5, peak: 0.98 },
+            album: Some(Gain { db: -7.25, peak: 1.0 }),
+        };
+
+        let file = vorbis_headers(320_000, replay_gain_tags(&normalization));
+        let read = read_normalization(&file).unwrap();
+
+        assert_eq!((read.track.db, read.track.peak), (-6.5, 0.98));
+        assert_eq!(read.album.map(|album| (album.db, album.peak)), Some((-7.25, 1.0)));
+    }
+
+    #[test]
+    fn normalization_needs_a_track_gain() {
+        let file = vorbis_headers(
+            320_000,
+            vec![(String::from("replaygain_album_gain"), String::from("-7.25 dB"))],
+        );
+
+        assert!(read_normalization(&file).is_none());
+    }
+
+    #[test]
+    fn spotify_gains_are_lowered_to_the_replaygain_reference() {
+        let mut header = vec![0u8; SPOTIFY_NORMALIZATION_OFFSET];
+
+        for value in [-2.5f32, 0.9, -3.0, 0.95] {
+            header.extend(value.to_le_bytes());
+        }
+
+        let normalization = read_spotify_normalization(&header).unwrap();
+
+        assert_eq!((normalization.track.db, normalization.track.peak), (-6.5, 0.9));
+        assert_eq!(
+            normalization.album.map(|album| (album.db, album.peak)),
+            Some((-7.0, 0.95))
+        );
+        assert!(read_spotify_normalization(&header[..SPOTIFY_NORMALIZATION_OFFSET + 8]).is_none());
+    }
+
+    #[test]
+    fn part_file_is_resumed_at_its_end() {
+        let audio: Vec<u8> = (0..=255).collect();
+
+        let mut source = io::Cursor::new(audio.clone());
+        let mut part = io::Cursor::new(audio[..100].to_vec());
+        let mut buffer = Vec::new();
+
+        resume_part(&mut source, &mut part, &mut buffer).unwrap();
+
+        assert_eq!(buffer, audio);
+        assert_eq!(part.into_inner(), audio);
+    }
+
+    #[test]
+    fn empty_part_file_fetches_everything() {
+        let audio: Vec<u8> = (0..=255).collect();
+
+        let mut part = io::Cursor::new(Vec::new());
+        let mut buffer = Vec::new();
+
+        resume_part(&mut io::Cursor::new(audio.clone()), &mut part, &mut buffer).unwrap();
+
+        assert_eq!(buffer, audio);
+    }
+
+    #[test]
+    fn space_is_only_estimated_for_new_tracks() {
+        let archived = lsc::SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap();
+        let in_library = lsc::SpotifyId::from_base62("6rqhFgbbKwnb9MLmUQDhG6").unwrap();
+        let new = lsc::SpotifyId::from_base62("2takcwOaAZWiXQijPHIx7B").unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.txt");
+        fs::write(&path, "4uLU6hMCjMI75M1A2tKUQC\n").unwrap();
+        let archive = DownloadArchive::open(&path).unwrap();
+
+        let library = coll::HashMap::from([(
+            in_library.to_base62().unwrap(),
+            path::PathBuf::from("Artist/Album/Track.ogg"),
+        )]);
+
+        assert!(already_downloaded(Some(&archive), &library, &archived));
+        assert!(already_downloaded(Some(&archive), &library, &in_library));
+        assert!(!already_downloaded(Some(&archive), &library, &new));
+        assert!(!already_downloaded(None, &coll::HashMap::new(), &archived));
+    }
+
+    // block_in_place panics on this runtime flavor, which embedding programs are free to use
+    #[tokio::test(flavor = "current_thread")]
+    async fn run_blocking_works_on_current_thread() {
+        assert_eq!(run_blocking(|| 40 + 2).await, 42);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn join_tasks_finishes_without_a_stop() {
+        let (job_tx, mut job_rx) = tokio::sync::mpsc::unbounded_channel::<usize>();
+
+        for job in 0..4 {
+            job_tx.send(job).unwrap();
+        }
+
+        drop(job_tx);
+
+        let stop = std::future::pending::<Stop>();
+        tokio::pin!(stop);
+
+        let mut results = Vec::new();
+
+        let (spawned, stopped) = time::timeout(
+            stdtime::Duration::from_secs(5),
+            join_tasks(
+                &mut job_rx,
+                stop.as_mut(),
+                |job| async move { job * 2 },
+                |result, _| results.push(result.unwrap()),
+            ),
+        )
+        .await
+        .expect("join_tasks kept waiting after the last task finished");
+
+        results.sort();
+
+        assert_eq!(spawned, 4);
+        assert!(stopped.is_none());
+        assert_eq!(results, vec![0, 2, 4, 6]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn track_logs_are_collected_whole() {
+        let (job_tx, mut job_rx) = tokio::sync::mpsc::unbounded_channel::<usize>();
+
+        for job in 0..2 {
+            job_tx.send(job).unwrap();
+        }
+
+        drop(job_tx);
+
+        let stop = std::future::pending::<Stop>();
+        tokio::pin!(stop);
+
+        let mut logs = Vec::new();
+
+        // Yielding after every line gives the other track a chance to log in between
+        join_tasks(
+            &mut job_rx,
+            stop.as_mut(),
+            |job| {
+                collect_track_log(async move {
+                    for line in 0..3 {
+                        info!("track {} line {}", job, line);
+                        task::yield_now().await;
+                    }
+
+                    job
+                })
+            },
+            |joined, _| logs.push(joined.unwrap()),
+        )
+        .await;
+
+        logs.sort_by_key(|(job, _)| *job);
+
+        assert_eq!(logs.len(), 2);
+
+        for (job, log) in logs {
+            let expected: Vec<_> = (0..3)
+                .map(|line| (false, format!("track {} line {}\n", job, line)))
+                .collect();
+
+            assert_eq!(log, expected);
+        }
+    }
+}
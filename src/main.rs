@@ -1,717 +1,1192 @@
-use async_recursion::async_recursion;
+use clap::Args;
+use clap::CommandFactory;
+use clap::FromArgMatches;
+use clap::Parser;
 use colored::Colorize;
-use lewton::header as lhr;
-use librespot_audio as lsa;
-use librespot_core as lsc;
 use librespot_core::authentication as lsc_auth;
-use librespot_metadata as lsm;
-use librespot_metadata::audio as lsm_audio;
-use lsm::Metadata;
-use std::collections as coll;
+use rippify::AltMetadata;
+use rippify::ArtistGroup;
+use rippify::AudioFormat;
+use rippify::Codec;
+use rippify::CompilationTag;
+use rippify::CoverSize;
+use rippify::Dedupe;
+use rippify::Downloader;
+use rippify::MetadataFormat;
+use rippify::Normalize;
+use rippify::OutputFormat;
+use rippify::Overwrite;
+use rippify::Quality;
+use rippify::ResolveOptions;
+use rippify::RetryPolicy;
+use rippify::Sanitize;
+use rippify::TagOptions;
+use rippify::TrackOutcome;
+use rippify::UserParams;
 use std::env;
-use std::fmt;
 use std::fs;
 use std::io;
-use std::io::Read;
+use std::io::IsTerminal;
 use std::path;
 use std::process as proc;
+use std::time as stdtime;
 
-static VERSION: &str = "0.2.0";
+static VERSION: &str = env!("CARGO_PKG_VERSION");
+static GIT_COMMIT: &str = env!("RIPPIFY_GIT_COMMIT");
+static LIBRESPOT_VERSION: &str = env!("RIPPIFY_LIBRESPOT_VERSION");
+static WATCH_STATE_FILE: &str = "watch-state.json";
 
 #[tokio::main]
 async fn main() {
+    // Help and version requests exit with 0 from within parse_opts, anything reaching here is a usage error
     let opts = match parse_opts() {
         Ok(opts) => opts,
         Err(err) => {
-            println!("{}: {}", "error".red().bold(), err.to_string().bold());
-            proc::exit(1);
+            eprintln!("{}: {}", "error".red().bold(), err.to_string().bold());
+            eprintln!("\nFor more information, try '--help'.");
+            proc::exit(2);
         }
     };
 
-    let credentials = lsc_auth::Credentials::with_password(&opts.user, &opts.pass);
-    let session_config = lsc::SessionConfig::default();
+    let stdout = opts.stdout;
 
-    let session = lsc::Session::new(session_config, None);
-
-    match session.connect(credentials, false).await {
-        Ok(_) => {
-            println!("{} Logged in as: {}", "=>".green().bold(), &opts.user.bright_blue());
-        }
-        Err(err) => {
-            println!(
-                "{}: cannot log in: {}",
-                "error".red().bold(),
-                err.to_string().to_lowercase()
-            );
-            proc::exit(1);
-        }
+    // Errors were already printed by the run
+    let reports = match Downloader::new(opts).run().await {
+        Ok(reports) => reports,
+        // Same exit status a shell reports for a program stopped by Ctrl-C
+        Err(rippify::Error::Interrupted) => proc::exit(130),
+        Err(rippify::Error::Failed(_)) => proc::exit(1),
     };
 
-    println!("\n{} Input resources:", "=>".green().bold());
+    if stdout && !reports.iter().any(|report| report.status == TrackOutcome::Written) {
+        proc::exit(1);
+    }
+}
+
+#[derive(Clone, clap::ValueEnum)]
+enum LibraryLayout {
+    Plex,
+}
 
-    let input_resources: Vec<_> = opts
-        .input
-        .iter()
-        .map(|line| get_resource_from_line(line))
-        .filter(|x| {
-            if let Err(line) = x {
-                println!(
-                    "{}: unrecognized input: {}, skipping...",
-                    " -> warning".yellow().bold(),
-                    line.bold()
-                );
-                false
-            } else {
-                let res = x.as_ref().unwrap();
-                println!(
-                    " {} {}: {}",
-                    "->".yellow().bold(),
-                    res.kind,
-                    &res.id.to_base62().unwrap()
-                );
-                true
-            }
-        })
-        .map(|x| x.unwrap())
-        .collect();
-
-    let mut input_tracks = coll::HashSet::<lsc::SpotifyId>::new();
-
-    for res in &input_resources {
-        match res.get_tracks(&session).await {
-            Ok(tracks) => input_tracks.extend(tracks),
-            Err(err) => {
-                println!(
-                    "{}: cannot get metadata for {} {}: {}, skipping...",
-                    "warning".yellow().bold(),
-                    res.kind,
-                    res.id.to_base62().unwrap(),
-                    err
-                );
-            }
+impl LibraryLayout {
+    fn format(&self) -> &'static str {
+        match self {
+            LibraryLayout::Plex => "{author}/{album}/{track_number} - {name}.{ext}",
         }
     }
+}
 
-    if input_tracks.is_empty() {
-        println!("\n{}: didn't get any tracks, aborting...", "error".red().bold());
-        proc::exit(0);
-    }
+#[derive(clap::Subcommand)]
+enum Command {
+    /// download the given tracks, albums, playlists, artists, podcast episodes or shows. Same as giving the URIs without a subcommand.
+    Get {
+        #[command(flatten)]
+        download: DownloadArgs,
+
+        #[command(flatten)]
+        inputs: Inputs,
+    },
+
+    /// mirror PLAYLIST to DIR, downloading the tracks missing there. The output format is placed under DIR, and a manifest in it remembers which files belong to the playlist, even once they were renamed.
+    Sync {
+        #[command(flatten)]
+        download: DownloadArgs,
+
+        /// spotify URI or URL of the playlist
+        playlist: String,
+
+        /// directory to mirror the playlist to
+        dir: path::PathBuf,
+
+        /// delete the files of tracks removed from the playlist since the last sync
+        #[arg(long)]
+        prune: bool,
+    },
+
+    /// check the playlists given with --playlist every --every interval and download the tracks added to them since the last check, until stopped with Ctrl-C. The tracks seen so far are remembered in the --sync-state file, watch-state.json in the --cache-dir directory by default, so a restarted watch picks up where it left off.
+    Watch {
+        #[command(flatten)]
+        download: DownloadArgs,
+
+        /// spotify URI or URL of a playlist to watch, can be given several times. A list of them can be kept in the config file as playlist = [...]
+        #[arg(long, value_name = "PLAYLIST", required = true)]
+        playlist: Vec<String>,
+
+        /// time between two checks, as a number followed by s, m, h or d, as in 90s, 30m or 6h. 30m is used by default.
+        #[arg(long, value_name = "INTERVAL", default_value = "30m", hide_default_value = true, value_parser = parse_interval)]
+        every: stdtime::Duration,
+    },
+
+    /// log in through spotify's website in the browser and cache the credentials in the --cache-dir directory, so later runs need neither a password nor a token
+    Login,
+
+    /// resolve all inputs and print how many tracks each of them contains, followed by the number of unique tracks across all of them
+    List {
+        #[command(flatten)]
+        resolve: ResolveArgs,
+
+        #[command(flatten)]
+        inputs: Inputs,
+    },
+
+    /// print the metadata of every resolved track (names, artists, album, duration, isrc, disc and track numbers, release date, popularity, availability at the --quality bitrate and the url of its cover art) to stdout without downloading anything, or read and write the metadata of downloaded files with one of the subcommands
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Meta {
+        #[command(subcommand)]
+        command: Option<MetaCommand>,
+
+        #[command(flatten)]
+        resolve: ResolveArgs,
+
+        /// format to print the metadata in, either json (an array of objects) or csv (one row per track). json is used by default.
+        #[arg(long = "format", id = "metadata_format", value_name = "FORMAT", value_enum, default_value_t = MetadataFormat::Json, hide_default_value = true)]
+        format: MetadataFormat,
+
+        /// audio quality to check the availability of tracks at, see rippify get --help
+        #[arg(long, value_name = "QUALITY", value_enum, default_value_t = Quality::High, hide_default_value = true)]
+        quality: Quality,
+
+        #[command(flatten)]
+        inputs: Inputs,
+    },
+}
 
-    println!(
-        "\n{} Parsed {} tracks:",
-        "=>".green().bold(),
-        input_tracks.len().to_string().bold()
-    );
+#[derive(clap::Subcommand)]
+enum MetaCommand {
+    /// walk DIR for previously downloaded .ogg files and rewrite their tags with the current track metadata. Only files with an embedded spotify track id can be retagged.
+    Retag {
+        /// directory to walk
+        dir: path::PathBuf,
+    },
+
+    /// list the .ogg files under DIR that are missing any of the tracknumber, date or cover art tags. Files are only read, never modified, and no login is needed.
+    Audit {
+        /// directory to walk
+        dir: path::PathBuf,
+    },
+
+    /// write the metadata of every resolved track to PATH as a json array, using beets' field names and the path the track would be downloaded to. Meant for tagging files that are already in a library with beets or other taggers.
+    Beets {
+        #[command(flatten)]
+        download: DownloadArgs,
+
+        /// json file to write
+        #[arg(long, value_name = "PATH")]
+        output: path::PathBuf,
+
+        #[command(flatten)]
+        inputs: Inputs,
+    },
+}
 
-    let mut num_completed: usize = 0;
-    let mut num_existing: usize = 0;
-
-    for track_id in &input_tracks {
-        print!(" {} ", "->".yellow().bold());
-
-        let (track, file_id) = match get_track_from_id(&session, track_id).await {
-            Ok((track, file_id)) => {
-                if track.id.to_base62().unwrap() != track_id.to_base62().unwrap() {
-                    println!(
-                        "{} ({} alt. {})",
-                        track.name.bold(),
-                        track.id.to_base62().unwrap(),
-                        track_id.to_base62().unwrap()
-                    );
-                } else {
-                    println!("{} ({})", track.name.bold(), track.id.to_base62().unwrap());
-                }
+// Options every subcommand takes, wherever they're given on the command line
+#[derive(clap::Args)]
+struct GlobalArgs {
+    /// read default options from the TOML file PATH instead of $XDG_CONFIG_HOME/rippify/config.toml or ~/.config/rippify/config.toml. Options given on the command line take precedence over the file.
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<path::PathBuf>,
+
+    /// user login name. Not needed with --token, or once credentials were cached by an earlier run
+    #[arg(
+        short,
+        long,
+        global = true,
+        value_name = "USER",
+        requires = "pass",
+        conflicts_with = "token"
+    )]
+    user: Option<String>,
+
+    /// user password, see --user. Spotify is phasing out password logins, rippify login is the more future proof way
+    #[arg(
+        short,
+        long,
+        global = true,
+        value_name = "PASS",
+        requires = "user",
+        conflicts_with = "token"
+    )]
+    pass: Option<String>,
+
+    /// log in with a spotify access token instead of a user name and password
+    #[arg(long, global = true, value_name = "TOKEN")]
+    token: Option<String>,
+
+    /// directory the credentials of the last successful login are cached in and read from when no other credentials are given. $XDG_CACHE_HOME/rippify or ~/.cache/rippify is used by default.
+    #[arg(long, global = true, value_name = "DIR")]
+    cache_dir: Option<path::PathBuf>,
+
+    /// neither read cached credentials nor cache the ones of this login, for shared machines. --user and --pass or --token are required then.
+    #[arg(long, global = true)]
+    no_cache_credentials: bool,
+
+    /// connect to spotify through the http proxy at URL, as in http://host:port. The HTTPS_PROXY environment variable is used when not given.
+    #[arg(long, global = true, value_name = "URL", value_parser = parse_proxy)]
+    proxy: Option<url::Url>,
+
+    /// seconds to wait for spotify to answer the initial login before giving up, 30 is used by default
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECS",
+        default_value_t = 30,
+        hide_default_value = true
+    )]
+    connect_timeout: u64,
+
+    /// retry the initial login up to N times when spotify can't be reached, waiting longer before every retry. Rejected credentials are never retried.
+    #[arg(
+        long,
+        global = true,
+        value_name = "N",
+        default_value_t = 0,
+        hide_default_value = true
+    )]
+    connect_retries: u32,
+
+    /// directory for temporary files, used by librespot while fetching audio, by --stage-to-disk and by --resume-partial. The system temporary directory is used by default.
+    #[arg(long, global = true, value_name = "DIR")]
+    temp_dir: Option<path::PathBuf>,
+
+    /// only print a progress bar and the final summary instead of the output for every track. Warnings are still shown.
+    #[arg(long, global = true)]
+    summary_only: bool,
+
+    /// print more details, like the audio file picked for every track. Given twice, as in -vv, the log of librespot itself is printed to stderr as well
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with_all = ["quiet", "summary_only"])]
+    verbose: u8,
+
+    /// only print warnings and errors, to stderr
+    #[arg(short, long, global = true, conflicts_with = "summary_only")]
+    quiet: bool,
+
+    /// append everything that is printed to PATH as well, without colors and with a timestamp on every line. The details of -v and librespot's own warnings are always written to it
+    #[arg(long, global = true, value_name = "PATH")]
+    log_file: Option<path::PathBuf>,
+
+    /// draw a progress bar for the whole queue and one with the downloaded bytes of every track. Enabled by default when stdout is a terminal.
+    #[arg(long, global = true, overrides_with = "no_progress")]
+    progress: bool,
+
+    /// don't draw progress bars, see --progress
+    #[arg(long, global = true, overrides_with = "progress")]
+    no_progress: bool,
+
+    /// stop the whole run with a non-zero exit code on the first input, metadata or download failure instead of skipping it
+    #[arg(long, global = true)]
+    fail_fast: bool,
+}
 
-                (track, file_id)
-            }
-            Err(err) => {
-                println!("{} ({})", "??".bold(), track_id.to_base62().unwrap());
-                println!(
-                    "   - {}: cannot get track from id: {}, skipping...",
-                    "warning".yellow().bold(),
-                    err,
-                );
-                continue;
-            }
-        };
+// Options for picking the tracks of the inputs, shared by the subcommands that resolve them
+#[derive(clap::Args)]
+struct ResolveArgs {
+    /// only download tracks on disc number N of the given albums, including albums of the given artists
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(i32).range(1..))]
+    disc: Option<i32>,
 
-        let output_file = opts.format.parse_output_format(&track);
+    /// only download the N most recent releases of every --artist-include group of the given artists
+    #[arg(long, value_name = "N")]
+    artist_albums_limit: Option<usize>,
 
-        if path::Path::new(&output_file.file).exists() {
-            println!(
-                "   - {}: output file \"{}\" already exists, skipping...",
-                "note".bright_blue().bold(),
-                output_file.file
-            );
+    /// comma separated list of the release groups to download of the given artists, out of albums, singles, compilations and appears_on (releases of others the artist is featured on). albums,singles is used by default.
+    #[arg(long, value_name = "GROUPS", value_enum, value_delimiter = ',', default_values_t = [ArtistGroup::Albums, ArtistGroup::Singles], hide_default_value = true)]
+    artist_include: Vec<ArtistGroup>,
 
-            num_existing += 1;
-            continue;
-        }
+    /// only download the top tracks spotify lists for the given artists in the account's country, instead of their releases
+    #[arg(long, conflicts_with_all = ["artist_include", "artist_albums_limit"])]
+    artist_top_tracks_only: bool,
 
-        let buffer = match track_download(&track, &file_id, &session).await {
-            Ok(buffer) => buffer,
-            Err(err) => {
-                match err.kind {
-                    TrackDownloadErrorKind::AudioKey => {
-                        println!(
-                            "   - {}: cannot get audio key: {}, skipping...",
-                            "warning".yellow().bold(),
-                            err.error
-                        );
-                    }
-                    TrackDownloadErrorKind::AudioFile => {
-                        println!(
-                            "   - {}: cannot get audio file: {}, skipping...",
-                            "warning".yellow().bold(),
-                            err.error
-                        );
-                    }
-                    TrackDownloadErrorKind::TrackFile => {
-                        println!(
-                            "   - {}: cannot get track file audio: {}, skipping...",
-                            "warning".yellow().bold(),
-                            err.error
-                        );
-                    }
-                    TrackDownloadErrorKind::Decrypt => {
-                        println!(
-                            "   - {}: cannot decrypt audio file: {}, skipping...",
-                            "warning".yellow().bold(),
-                            err.error
-                        );
-                    }
-                };
-                continue;
-            }
-        };
+    /// also download the radio spotify generates from the track or artist URI or URL SEED. Can be given multiple times.
+    #[arg(long, value_name = "SEED")]
+    radio: Vec<String>,
 
-        let buffer_tags = match track_add_metadata_tags(buffer, &track) {
-            Ok(buf) => buf,
-            Err(err) => {
-                match err.kind {
-                    TagsWriteErrorKind::Read => {
-                        print!(
-                            "   - {}: cannot read ogg packet: {}, skipping...",
-                            "warning".yellow().bold(),
-                            err.error
-                        );
-                    }
-                    TagsWriteErrorKind::Write => {
-                        print!(
-                            "   - {}: cannot write ogg packet: {}, skipping...",
-                            "warning".yellow().bold(),
-                            err.error
-                        );
-                    }
-                    TagsWriteErrorKind::Header => {
-                        print!(
-                            "   - {}: cannot create comment header packet: {}, skipping...",
-                            "warning".yellow().bold(),
-                            err.error
-                        );
-                    }
-                }
-                continue;
-            }
-        };
+    /// also download the liked songs and saved albums of the logged in account, same as giving spotify:collection
+    #[arg(long)]
+    liked: bool,
 
-        match track_write(buffer_tags, output_file) {
-            Ok(output) => {
-                println!("   - wrote \"{}\"", output);
-                num_completed += 1;
-            }
-            Err(err) => {
-                match err.kind {
-                    TrackWriteErrorKind::FolderCreate => {
-                        print!(
-                            "   - {}: cannot create output folders: {}, skipping...",
-                            "warning".yellow().bold(),
-                            err.error
-                        );
-                    }
-                    TrackWriteErrorKind::FileCreate => {
-                        println!(
-                            "   - {}: cannot create output file: {}, skipping...",
-                            "warning".yellow().bold(),
-                            err.error
-                        );
-                    }
-                    TrackWriteErrorKind::FileWrite => {
-                        println!(
-                            "   - {}: cannot write output file: {}, skipping...",
-                            "warning".yellow().bold(),
-                            err.error
-                        );
-                    }
-                };
-                continue;
-            }
-        };
-    }
+    /// search spotify for QUERY and download the matches picked from the top tracks and albums found, same as giving search:QUERY. Can be given multiple times.
+    #[arg(long, value_name = "QUERY")]
+    search: Vec<String>,
 
-    println!("\n{} Processed tracks: ", "=>".green().bold(),);
+    /// download the best match of every search instead of asking which ones to download. Always the case when stdin isn't a terminal.
+    #[arg(long)]
+    first: bool,
 
-    println!(
-        " {} {} error",
-        "->".yellow().bold(),
-        input_tracks.len() - num_completed - num_existing
-    );
+    /// download at most the first N tracks of each radio
+    #[arg(long, value_name = "N", default_value_t = 50, value_parser = clap::value_parser!(u32).range(1..))]
+    radio_count: u32,
 
-    println!(" {} {} already downloaded", "->".yellow().bold(), num_existing);
+    /// after resolving an artist, list its albums and singles and ask which of them to download. Everything is downloaded when stdin isn't a terminal.
+    #[arg(long)]
+    interactive: bool,
 
-    println!(" {} {} new", "->".yellow().bold(), num_completed);
+    /// only download playlist tracks that were added to the playlist after DATE, given as YYYY-MM-DD. Tracks added on DATE itself are skipped. Tracks from albums, artists and single track inputs are not affected.
+    #[arg(long, value_name = "DATE", value_parser = parse_date)]
+    added_after: Option<::time::Date>,
 
-    println!(" {} {} total processed", "->".yellow().bold(), input_tracks.len())
-}
+    /// skip episodes of the given shows that are shorter than SECS seconds, like trailers and teasers. Episodes given directly or through playlists are not affected.
+    #[arg(long, value_name = "SECS")]
+    skip_shorter_than: Option<u32>,
 
-struct UserParams {
-    user: String,
-    pass: String,
-    format: OutputFormat,
-    input: Vec<String>,
+    /// remember the tracks of every playlist in FILE and only download the tracks added to a playlist since the last run with the same FILE. Tracks that fail to download are tried again on the next run.
+    #[arg(long, value_name = "FILE")]
+    sync_state: Option<path::PathBuf>,
 }
 
-fn parse_opts() -> Result<UserParams, getopts::Fail> {
-    let args: Vec<String> = env::args().collect();
-    let program = args[0].clone();
+// Options for downloading and writing tracks, shared by the subcommands that do
+#[derive(clap::Args)]
+struct DownloadArgs {
+    #[command(flatten)]
+    resolve: ResolveArgs,
+
+    /// output format to use. {author}/{album}/{name}.{ext} is used by default. Available format specifiers are: {author}, {artists} (all artists, joined with commas), {album_artist}, {album}, {name}, {source}, {playlist}, {position} (the position of a track in the playlist, album or artist it was given through, as in {playlist}/{position} - {name}.{ext}), {track_number}, {disc_number}, {year}, {date} (the release date as YYYY-MM-DD), {id} (the spotify id) and {ext}, which is ogg, or m4a for the few tracks only available as aac. Numbers are zero padded to a width given after a colon, as in {track_number:03}. {source}, or its alias {playlist}, is the name of the playlist, album or artist a track was given through, or Tracks for tracks given directly, which keeps several inputs in their own folders when used first, as in {source}/{author}/{album}/{name}.{ext}. Note that when tracks have more that one author, {author} will evaluate only to main one (track metadata will still we written correctly). For podcast episodes, {author} and {album} evaluate to the show and {track_number} to the episode number. Use - to write to stdout instead, same as --stdout.
+    #[arg(short, long, value_name = "FMT")]
+    format: Option<String>,
+
+    /// preset for media servers, sets a fitting format and enables the tags they rely on. Given options still take precedence over the preset. Available layouts are: plex, which uses {author}/{album}/{track_number} - {name}.{ext} and writes albumartist tags.
+    #[arg(long, value_name = "LAYOUT", value_enum)]
+    library_layout: Option<LibraryLayout>,
+
+    /// put the tracks of albums with more than one disc into Disc 1, Disc 2, ... folders where they'd go otherwise. Without --format or --library-layout, {album_artist}/{album}/{track_number} - {name}.{ext} is used as the format, so tracks also sort in album order.
+    #[arg(long)]
+    album_layout: bool,
+
+    /// zero padding width used for {track_number}, {disc_number} and {position} when the specifier doesn't give its own, 2 is used by default
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 2,
+        hide_default_value = true,
+        value_parser = clap::value_parser!(u8).range(1..=9)
+    )]
+    track_number_width: u8,
+
+    /// replace spaces in the {author}, {album} and {name} specifiers with CHAR, _ is used if no CHAR is given
+    #[arg(long, value_name = "CHAR", num_args = 0..=1, default_missing_value = "_")]
+    replace_spaces: Option<char>,
+
+    /// file name rules every specifier of the output format is made to follow, either posix (no /, the default on linux and macos), windows (no <>:"/\|?* or control characters, no trailing dots or spaces and no reserved names like CON, the default on windows) or strict (the windows rules, and only ascii letters, digits, spaces and -_.,'()[]&+)
+    #[arg(long, value_name = "MODE", value_enum)]
+    sanitize: Option<Sanitize>,
+
+    /// character to replace the characters --sanitize doesn't allow with, a space is used by default
+    #[arg(long, value_name = "CHAR", default_value_t = ' ', hide_default_value = true)]
+    sanitize_replacement: char,
+
+    /// remove a trailing (feat. ...) or (with ...) from track titles in the {name} specifier and the title tag when it only repeats the track's other artists, which are still written to the artist tags
+    #[arg(long)]
+    strip_featured_artists: bool,
+
+    /// debug option, dumps the raw encrypted and decrypted audio buffers of every track into DIR as <id>.encrypted and <id>.decrypted. Note that this stores two extra full copies of every track (around 20MB for a 4 minute track at 320kbps).
+    #[arg(long, value_name = "DIR")]
+    keep_encrypted: Option<path::PathBuf>,
+
+    /// write the audio stream to stdout instead of a file, suppressing all other output. Requires exactly one track to be resolved.
+    #[arg(long, conflicts_with = "interactive")]
+    stdout: bool,
+
+    /// before downloading, walk DIR for previously downloaded .ogg files and skip tracks whose embedded spotify track id is already in one of them, even when it was saved under a different path
+    #[arg(long, value_name = "DIR", conflicts_with = "stdout")]
+    skip_duplicates_on_disk: Option<path::PathBuf>,
+
+    /// record the id of every downloaded track in FILE and skip tracks already recorded in it, even when the output format or directory changed since they were downloaded
+    #[arg(long, value_name = "FILE", conflicts_with = "stdout")]
+    download_archive: Option<path::PathBuf>,
+
+    /// record every downloaded track in the SQLite database at PATH, with its spotify id, isrc, path, bitrate, sha256 checksum and download time
+    #[arg(long, value_name = "PATH", conflicts_with = "stdout")]
+    db: Option<path::PathBuf>,
+
+    /// skip tracks with a spotify popularity score (0 to 100) lower than N
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(i32).range(0..=100))]
+    min_popularity: Option<i32>,
+
+    /// when to tag tracks as part of a various artists compilation (compilation=1, albumartist=Various Artists). auto is used by default, which tags albums marked as compilations by spotify or credited to Various Artists.
+    #[arg(long, value_name = "MODE", value_enum, default_value_t = CompilationTag::Auto, hide_default_value = true)]
+    compilation_tag: CompilationTag,
+
+    /// size of the album cover art embedded into every track, either small (64px), medium (300px), large (640px) or original. original is used by default, which picks the largest available image. Smaller covers keep files lighter on space limited devices.
+    #[arg(long, value_name = "SIZE", value_enum, default_value_t = CoverSize::Original, hide_default_value = true)]
+    cover_size: CoverSize,
+
+    /// where the replaygain tags of tracks come from: spotify (the gains spotify ships with the audio), compute (measure the EBU R128 loudness of every track locally, slower) or off. spotify is used by default. Album gains always come from spotify
+    #[arg(long, value_name = "MODE", value_enum, default_value_t = Normalize::Spotify, hide_default_value = true)]
+    normalize: Normalize,
+
+    /// look up the tempo and key spotify detected for every track and write them into the bpm and initialkey tags. Tracks spotify has no audio features for are tagged without them.
+    #[arg(long)]
+    audio_features: bool,
+
+    /// look up the lyrics of every track and write them into the lyrics tags and a .lrc file next to the audio file, timed when spotify has them synced. Tracks without lyrics are written without them.
+    #[arg(long)]
+    lyrics: bool,
+
+    /// number of tracks to fetch metadata and audio for at the same time, 1 is used by default. With more than one job the output of every track is printed once it's done.
+    #[arg(short, long, value_name = "N", default_value_t = 1, hide_default_value = true, value_parser = clap::value_parser!(u64).range(1..))]
+    jobs: u64,
+
+    /// number of tracks to tag and write to disk at the same time, independently of --jobs. The value of --jobs is used by default. Lower it on slow storage to keep downloading without thrashing the disk.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    write_jobs: Option<u64>,
+
+    /// instead of downloading, fetch the audio of the first N input tracks at 1, 2, 4 and 8 concurrent downloads and report the throughput of each, to help picking a concurrency for your connection. Nothing is written to disk.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    benchmark: Option<u64>,
+
+    /// start downloading tracks as soon as they're resolved instead of after resolving every input, so the first tracks of large artists don't wait for their whole discography. The download size isn't checked beforehand and the progress bar only counts the tracks resolved so far.
+    #[arg(long, conflicts_with_all = ["stdout", "benchmark", "interactive"])]
+    stream: bool,
+
+    /// when a track has several regional versions, download the original one instead of the requested one when possible. The original is assumed to be the most popular playable version with the same isrc.
+    #[arg(long)]
+    prefer_canonical: bool,
+
+    /// skip tracks that are the same recording as a track processed before under another id, as found on re-releases and compilations. isrc compares the recording codes spotify lists, fuzzy the main artist, title and duration. none is used by default.
+    #[arg(long, value_name = "MODE", value_enum, default_value_t = Dedupe::None, hide_default_value = true)]
+    dedupe: Dedupe,
+
+    /// when a track isn't playable and one of its alternative versions is downloaded instead, name and tag the file after either the requested track or the downloaded alternative. alternative is used by default.
+    #[arg(long, value_name = "TRACK", value_enum, default_value_t = AltMetadata::Alternative, hide_default_value = true)]
+    alt_metadata: AltMetadata,
+
+    /// audio quality to download, either high (320kbps), normal (160kbps) or low (96kbps), or the bitrate itself, as in --quality 160. high is used by default. When a track isn't available at that quality the next lower one is used, free accounts are limited to normal.
+    #[arg(long, value_name = "QUALITY", value_enum, default_value_t = Quality::High, hide_default_value = true)]
+    quality: Quality,
+
+    /// skip tracks that aren't available at exactly the --quality bitrate instead of falling back to a lower one. They are counted as unavailable at that quality in the summary. The bitrate is never lowered to what a free account allows, so with --quality high every track is skipped on one.
+    #[arg(long)]
+    strict_quality: bool,
+
+    /// comma separated list of audio formats to try in order instead of going by --quality, like ogg320,aac48,ogg160. Tracks offering none of them are skipped. The formats spotify serves are ogg320, ogg160, ogg96, aac48 and aac24.
+    #[arg(long, value_name = "FORMATS", value_delimiter = ',', value_parser = rippify::parse_audio_format, conflicts_with_all = ["quality", "strict_quality"])]
+    prefer: Vec<AudioFormat>,
 
-    let mut opts = getopts::Options::new();
+    /// encode the downloaded audio to another codec with ffmpeg, which has to be installed, and use its extension for {ext}. Tags are carried over, except for the cover art of opus files. ogg is used by default, which keeps the audio exactly as spotify serves it, vorbis in .ogg files or aac in .m4a files for the few tracks without vorbis audio. Existing transcoded files aren't checked by --overwrite and --verify-existing.
+    #[arg(long, value_name = "CODEC", value_enum, default_value_t = Codec::Ogg, hide_default_value = true, conflicts_with_all = ["stdout", "decrypt_only"])]
+    codec: Codec,
 
-    opts.optflag("h", "help", "print the help menu");
-    opts.optflag("v", "version", "show copyright and version information");
+    /// download tracks again when their output file already exists but has a lower bitrate than what is available now, for example files ripped at 160kbps that can now be downloaded at 320kbps. The bitrate is read from the SPOTIFY_BITRATE tag, or from the vorbis header for older files.
+    #[arg(long, conflicts_with_all = ["stdout", "overwrite"])]
+    replace_existing_if_better: bool,
 
-    opts.optopt("u", "user", "user login name, required", "USER");
-    opts.optopt("p", "pass", "user password, required", "PASS");
-    opts.optopt(
-        "f",
-        "format",
-        "output format to use. {author}/{album}/{name}.{ext} is used by default. Available format specifiers are: {author}, {album}, {name} and {ext}. Note that when tracks have more that one author, {author} will evaluate only to main one (track metadata will still we written correctly).",
-        "FMT",
-    );
+    /// what to do when the output file of a track already exists: never replace it, always download it again, replace it only if more bitrate is available (same as --replace-existing-if-better), or whenever the available bitrate differs from it, for example to go down to 96kbps for a portable library
+    #[arg(long, value_name = "POLICY", value_enum, default_value_t = Overwrite::Never, conflicts_with = "stdout")]
+    overwrite: Overwrite,
 
-    let matches = opts.parse(&args[1..])?;
-    let input = matches.free.clone();
+    /// write the current metadata, cover and lyrics into the tags of existing files that are kept, without downloading their audio again. Only works for .ogg and .m4a files
+    #[arg(long, conflicts_with = "stdout")]
+    refresh_tags: bool,
 
-    if matches.opt_present("v") {
-        print_version();
-        proc::exit(0);
-    }
+    /// instead of skipping tracks whose output file already exists right away, read the whole file first and download the track again when it's damaged or shorter than the track should be
+    #[arg(long, conflicts_with = "stdout")]
+    verify_existing: bool,
 
-    if matches.opt_present("h") || !matches.opt_present("u") || !matches.opt_present("p") || input.is_empty() {
-        print_usage(&program, opts);
-        proc::exit(0);
-    }
+    /// write the album cover into every folder tracks are downloaded to, as cover.jpg or the file name given with --save-cover=NAME, for players like kodi or plex that look for it there. Covers that are already there are kept. Spotify serves covers as jpeg, whatever the extension is
+    #[arg(long, value_name = "NAME", num_args = 0..=1, require_equals = true, default_missing_value = "cover.jpg", value_parser = parse_cover_name, conflicts_with = "stdout")]
+    save_cover: Option<String>,
 
-    let format = OutputFormat {
-        format_string: matches
-            .opt_str("f")
-            .unwrap_or("{author}/{album}/{name}.{ext}".to_owned()),
-    };
+    /// size of the cover written by --save-cover, like --cover-size. original is used by default
+    #[arg(long, value_name = "SIZE", value_enum, default_value_t = CoverSize::Original, hide_default_value = true)]
+    save_cover_size: CoverSize,
 
-    let user = matches.opt_str("u").unwrap();
-    let pass = matches.opt_str("p").unwrap();
+    /// write a .json file next to every downloaded track with its full metadata (ids, isrc, artists, album, duration, disc and track numbers, release date and popularity)
+    #[arg(long)]
+    save_metadata_json: bool,
 
-    Ok(UserParams {
-        user,
-        pass,
-        format,
-        input,
-    })
+    /// once the run is done, write an artist.json into every artist folder listing the albums in it and how many of their tracks were downloaded. Requires the output format to have an {author} folder.
+    #[arg(long, conflicts_with = "stdout")]
+    artist_index: bool,
+
+    /// once the run is done, write a .m3u8 file named after every input playlist, listing its downloaded tracks in playlist order with paths relative to it. They're written to the current folder, or to the one given with --m3u8=DIR
+    #[arg(long, value_name = "DIR", num_args = 0..=1, require_equals = true, default_missing_value = ".", conflicts_with = "stdout")]
+    m3u8: Option<path::PathBuf>,
+
+    /// write the outcome of every track (id, name, status, path, bitrate, bytes written and error) to PATH once the run is done, as json if PATH ends in .json or as csv otherwise
+    #[arg(long, value_name = "PATH")]
+    report: Option<path::PathBuf>,
+
+    /// request the audio key and audio file of a track up to N more times when spotify fails to hand them out, waiting longer before every retry. 4 is used by default.
+    #[arg(long, value_name = "N", default_value_t = 4, hide_default_value = true)]
+    retries: usize,
+
+    /// seconds to wait before the first retry of a track, doubled for every further one. 1 is used by default.
+    #[arg(long, value_name = "SECS", default_value_t = 1.0, hide_default_value = true, value_parser = parse_delay)]
+    retry_delay: f64,
+
+    /// seconds to pause all tracks for when spotify rate limits a metadata or audio key request, before asking again. Counts as one of the --retries of the track that ran into the limit. 30 is used by default.
+    #[arg(long, value_name = "SECS", default_value_t = 30, hide_default_value = true)]
+    rate_limit_cooldown: u64,
+
+    /// limit the download speed of all tracks together to RATE bytes per second, with a K, M or G suffix for kibibytes, mebibytes or gibibytes, as in 500K or 2M
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    limit_rate: Option<u64>,
+
+    /// give up on the download of a track after SECS seconds, retries included, and count it as failed
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    track_timeout: Option<u64>,
+
+    /// stop starting new tracks and drop the ones in progress SECS seconds after the run started, then print the summary of what was done
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    total_timeout: Option<u64>,
+
+    /// stage the decrypted and tagged audio in temporary files instead of memory before moving it to its output path, for low memory machines
+    #[arg(long, conflicts_with = "stdout")]
+    stage_to_disk: bool,
+
+    /// write the decrypted audio exactly as spotify serves it, without tags and still starting with the header spotify puts in front of ogg streams. Meant for inspecting or post-processing the raw stream, files written this way can't be retagged.
+    #[arg(long)]
+    decrypt_only: bool,
+
+    /// don't estimate the size of the download and check that it fits in the output directory before starting
+    #[arg(long)]
+    skip_space_check: bool,
+
+    /// keep the encrypted audio of every track in a .part file in the --temp-dir directory while it downloads, so a download that was interrupted or failed continues where it stopped, also on the next run. Mostly useful for very long tracks on slow connections.
+    #[arg(long)]
+    resume_partial: bool,
+
+    /// shell command to run after every successfully written track, with the output path as its last argument. The track is also described through the RIPPIFY_PATH, RIPPIFY_TRACK_ID, RIPPIFY_TITLE, RIPPIFY_ARTIST and RIPPIFY_ALBUM environment variables. A failing hook is reported but doesn't stop the run.
+    #[arg(long, value_name = "CMD")]
+    post_hook: Option<String>,
 }
 
-fn print_usage(program: &str, opts: getopts::Options) {
-    let brief = format!("Usage: {} [OPTIONS] URIs...", program);
-    print!("{}", opts.usage(&brief));
+#[derive(clap::Args)]
+struct Inputs {
+    /// spotify URIs or URLs of tracks, albums, playlists, artists, podcast episodes or shows, spotify:collection for the liked songs and saved albums, search:QUERY for the results of a search, or local .m3u/.m3u8 files listing them
+    #[arg(value_name = "URIs", required_unless_present_any = ["radio", "liked", "search"])]
+    input: Vec<String>,
 }
 
-fn print_version() {
-    println!("rippify version {}\n", VERSION);
-    println!(
-        "Copyright (C) 2023 Antonio de Haro. \n\
-        This program is distributed under the MIT license, see the attatched LICENSE.txt file for terms and conditions. \n\
-        This software is provided without any warranty of any kind. \n\
-        Copyright atributions for any third party code included are provided in the attatched COPYRIGHT.md file."
-    );
+#[derive(clap::Parser)]
+#[command(
+    name = "rippify",
+    disable_version_flag = true,
+    args_override_self = true,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    override_usage = "rippify [OPTIONS] URIs...\n       rippify <COMMAND> [OPTIONS]"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// show copyright and version information
+    #[arg(short = 'V', long)]
+    version: bool,
+
+    /// print a completion script for SHELL to stdout
+    #[arg(long, value_name = "SHELL", hide = true)]
+    generate_completions: Option<clap_complete::Shell>,
+
+    #[command(flatten)]
+    global: GlobalArgs,
+
+    #[command(flatten)]
+    download: DownloadArgs,
+
+    // Flags from before the subcommands, kept for existing scripts and mapped onto the subcommands replacing them
+    #[arg(long, value_name = "DIR", hide = true)]
+    retag_dir: Option<path::PathBuf>,
+
+    #[arg(long, value_name = "DIR", hide = true)]
+    only_missing_tags: Option<path::PathBuf>,
+
+    #[arg(long, hide = true)]
+    list_inputs: bool,
+
+    #[arg(long, value_name = "PATH", hide = true)]
+    export_beets: Option<path::PathBuf>,
+
+    /// spotify URIs or URLs of tracks, albums, playlists, artists, podcast episodes or shows, spotify:collection for the liked songs and saved albums, search:QUERY for the results of a search, or local .m3u/.m3u8 files listing them
+    #[arg(value_name = "URIs", required_unless_present_any = ["version", "generate_completions", "radio", "liked", "search", "retag_dir", "only_missing_tags"])]
+    input: Vec<String>,
 }
 
-enum ResourceKind {
-    Track,
-    Playlist,
-    Album,
-    Artist,
+fn moved_flag(flag: &str, replacement: &str) {
+    eprintln!(
+        "{}: {} moved to rippify {}, the old flag will be removed in a later version",
+        "note".bright_blue().bold(),
+        flag,
+        replacement
+    );
 }
 
-impl fmt::Display for ResourceKind {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            ResourceKind::Track => write!(f, "track"),
-            ResourceKind::Playlist => write!(f, "playlist"),
-            ResourceKind::Album => write!(f, "album"),
-            ResourceKind::Artist => write!(f, "artist"),
-        }
+// Librespot tunnels everything through CONNECT requests, which socks proxies don't speak
+fn parse_proxy(proxy: &str) -> Result<url::Url, String> {
+    let url = url::Url::parse(proxy).map_err(|err| err.to_string())?;
+
+    match url.scheme() {
+        "http" | "https" if url.host().is_some() => Ok(url),
+        "http" | "https" => Err(String::from("expected a proxy url like http://host:port")),
+        scheme => Err(format!(
+            "unsupported proxy scheme {}, only http proxies are supported",
+            scheme
+        )),
     }
 }
 
-impl ResourceKind {
-    fn to_url_regex(&self) -> regex::Regex {
-        regex::Regex::new(&format!(
-            r"^(http(s)?://)?open\.spotify\.com/{}/([[:alnum:]]{{22}})$",
-            self
-        ))
-        .unwrap()
+// The cover always goes into the track's own folder, so the name can't point anywhere else
+fn parse_cover_name(name: &str) -> Result<String, String> {
+    match name {
+        "" | "." | ".." => Err(String::from("expected a file name like folder.jpg")),
+        _ if name.contains(['/', '\\']) => Err(String::from("expected a file name without folders")),
+        _ => Ok(name.to_owned()),
     }
+}
 
-    fn to_uri_regex(&self) -> regex::Regex {
-        regex::Regex::new(&format!(r"^spotify:{}:([[:alnum:]]{{22}})$", self)).unwrap()
+fn parse_rate(rate: &str) -> Result<u64, String> {
+    let (number, unit) = match rate.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(split_pos) => rate.split_at(split_pos),
+        None => (rate, ""),
+    };
+
+    let multiplier = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1.0,
+        "K" | "KB" => 1024.0,
+        "M" | "MB" => 1024.0 * 1024.0,
+        "G" | "GB" => 1024.0 * 1024.0 * 1024.0,
+        _ => return Err(format!("unknown unit {}, expected K, M or G", unit)),
+    };
+
+    match number.parse::<f64>() {
+        Ok(number) if number * multiplier >= 1.0 => Ok((number * multiplier) as u64),
+        _ => Err(String::from("expected a positive rate like 500K or 2M")),
     }
 }
 
-struct InputResource {
-    kind: ResourceKind,
-    id: lsc::SpotifyId,
+fn parse_delay(delay: &str) -> Result<f64, String> {
+    match delay.parse::<f64>() {
+        Ok(secs) if secs.is_finite() && secs >= 0.0 => Ok(secs),
+        _ => Err(String::from("expected a positive number of seconds")),
+    }
 }
 
-impl InputResource {
-    #[async_recursion]
-    async fn get_tracks(&self, session: &lsc::Session) -> Result<Vec<lsc::SpotifyId>, librespot_core::error::Error> {
-        let mut tracks: Vec<lsc::SpotifyId> = Vec::new();
+fn parse_interval(interval: &str) -> Result<stdtime::Duration, String> {
+    let invalid = || String::from("expected an interval like 90s, 30m or 6h");
+    let split_pos = interval.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let (number, unit) = interval.split_at(split_pos);
+
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
 
-        match self.kind {
-            ResourceKind::Track => {
-                tracks.push(self.id);
-            }
-            ResourceKind::Playlist => {
-                let playlist = lsm::Playlist::get(session, &self.id).await?;
-                tracks.extend(playlist.tracks());
-            }
-            ResourceKind::Album => {
-                let album = lsm::Album::get(session, &self.id).await?;
-                tracks.extend(album.tracks());
-            }
-            ResourceKind::Artist => {
-                let artist = lsm::Artist::get(session, &self.id).await?;
-
-                for album_group in artist.albums.0 {
-                    for album in album_group.0 .0 {
-                        tracks.extend(
-                            InputResource {
-                                kind: ResourceKind::Album,
-                                id: album,
-                            }
-                            .get_tracks(session)
-                            .await?,
-                        );
-                    }
-                }
+    match number.parse::<u64>() {
+        Ok(number) if number > 0 => Ok(stdtime::Duration::from_secs(number * multiplier)),
+        _ => Err(invalid()),
+    }
+}
 
-                for album_group in artist.singles.0 {
-                    for album in album_group.0 .0 {
-                        tracks.extend(
-                            InputResource {
-                                kind: ResourceKind::Album,
-                                id: album,
-                            }
-                            .get_tracks(session)
-                            .await?,
-                        );
-                    }
-                }
-            }
-        }
+fn parse_date(date: &str) -> Result<::time::Date, String> {
+    let invalid = || String::from("expected a date formatted as YYYY-MM-DD");
+    let mut parts = date.splitn(3, '-');
+
+    let year = parts
+        .next()
+        .and_then(|part| part.parse::<i32>().ok())
+        .ok_or_else(invalid)?;
+    let month = parts
+        .next()
+        .and_then(|part| part.parse::<u8>().ok())
+        .ok_or_else(invalid)?;
+    let day = parts
+        .next()
+        .and_then(|part| part.parse::<u8>().ok())
+        .ok_or_else(invalid)?;
+
+    ::time::Month::try_from(month)
+        .and_then(|month| ::time::Date::from_calendar_date(year, month, day))
+        .map_err(|err| err.to_string())
+}
 
-        Ok(tracks)
-    }
+// What a run does, picked by its subcommand. Giving URIs without one is the same as get
+enum Task {
+    Get,
+    Sync(path::PathBuf, bool),
+    Watch(stdtime::Duration),
+    Login,
+    List,
+    Retag(path::PathBuf),
+    Beets(path::PathBuf),
+    Export(MetadataFormat),
 }
 
-fn get_resource_from_line(line: &str) -> Result<InputResource, &str> {
-    if let Some(id) = is_resource(line, ResourceKind::Track) {
-        Ok(InputResource {
-            kind: ResourceKind::Track,
-            id,
-        })
-    //
-    } else if let Some(id) = is_resource(line, ResourceKind::Album) {
-        Ok(InputResource {
-            kind: ResourceKind::Album,
-            id,
-        })
-    //
-    } else if let Some(id) = is_resource(line, ResourceKind::Playlist) {
-        Ok(InputResource {
-            kind: ResourceKind::Playlist,
-            id,
-        })
-    //
-    } else if let Some(id) = is_resource(line, ResourceKind::Artist) {
-        Ok(InputResource {
-            kind: ResourceKind::Artist,
-            id,
-        })
-    //
-    } else {
-        Err(line)
-    }
+// Subcommands that don't download still fill in every download option, with the defaults get has for them
+fn default_download_args() -> DownloadArgs {
+    let command = DownloadArgs::augment_args(clap::Command::new("rippify"));
+    DownloadArgs::from_arg_matches(&command.get_matches_from(["rippify"])).unwrap()
 }
 
-fn is_resource(line: &str, res: ResourceKind) -> Option<lsc::SpotifyId> {
-    if let Some(captures) = res.to_url_regex().captures(line).or(res.to_uri_regex().captures(line)) {
-        let id_str = captures.iter().last().unwrap().unwrap().as_str();
-        let id = lsc::SpotifyId::from_base62(id_str).unwrap();
+fn parse_opts() -> Result<UserParams, String> {
+    let args: Vec<String> = env::args().collect();
 
-        Some(id)
-    //
-    } else {
-        None
+    // -v used to print the version before it raised the verbosity, on its own it can only have meant the old one
+    if args.len() == 2 && args[1] == "-v" {
+        eprintln!(
+            "{}: -v now raises the verbosity, use -V or --version to print the version",
+            "note".bright_blue().bold()
+        );
+        print_version();
+        proc::exit(0);
     }
-}
 
-async fn get_track_from_id(
-    session: &lsc::Session,
-    id: &lsc::SpotifyId,
-) -> Result<(lsm::Track, lsc::FileId), librespot_core::error::Error> {
-    let mut track_ids = coll::VecDeque::<lsc::SpotifyId>::new();
-    track_ids.push_back(id.to_owned());
+    // Prints help to stdout and exits with 0 for -h, or prints the error to stderr and exits with 2 for misuse
+    let cli = Cli::parse_from(with_config_args(args)?);
 
-    while let Some(id) = track_ids.pop_front() {
-        let track = lsm::Track::get(session, &id).await?;
+    if cli.version {
+        print_version();
+        proc::exit(0);
+    }
 
-        match None
-            .or(track.files.get_key_value(&lsm_audio::AudioFileFormat::OGG_VORBIS_320))
-            .or(track.files.get_key_value(&lsm_audio::AudioFileFormat::OGG_VORBIS_160))
-            .or(track.files.get_key_value(&lsm_audio::AudioFileFormat::OGG_VORBIS_96))
-        {
-            Some(format) => return Ok((track.to_owned(), format.1.to_owned())),
-            None => track_ids.extend(track.alternatives.0),
-        };
+    if let Some(shell) = cli.generate_completions {
+        clap_complete::generate(shell, &mut Cli::command(), "rippify", &mut io::stdout());
+        proc::exit(0);
     }
 
-    Err(librespot_core::error::Error::not_found("cannot find a suitable track"))
-}
+    let (download, input, task) = match cli.command {
+        None if cli.retag_dir.is_some() => {
+            moved_flag("--retag-dir DIR", "meta retag DIR");
+            (default_download_args(), Vec::new(), Task::Retag(cli.retag_dir.unwrap()))
+        }
+        None if cli.only_missing_tags.is_some() => {
+            moved_flag("--only-missing-tags DIR", "meta audit DIR");
 
-struct OutputFormat {
-    format_string: String,
-}
+            match rippify::audit_tags(&cli.only_missing_tags.unwrap()) {
+                Ok(()) => proc::exit(0),
+                Err(_) => proc::exit(1),
+            }
+        }
+        None if cli.list_inputs => {
+            moved_flag("--list-inputs", "list");
+            (cli.download, cli.input, Task::List)
+        }
+        None if cli.export_beets.is_some() => {
+            moved_flag("--export-beets PATH", "meta beets --output PATH");
+            (cli.download, cli.input, Task::Beets(cli.export_beets.unwrap()))
+        }
+        None => (cli.download, cli.input, Task::Get),
+        Some(Command::Get { download, inputs }) => (download, inputs.input, Task::Get),
+        Some(Command::Sync {
+            download,
+            playlist,
+            dir,
+            prune,
+        }) => (download, vec![playlist], Task::Sync(dir, prune)),
+        Some(Command::Watch {
+            download,
+            playlist,
+            every,
+        }) => (download, playlist, Task::Watch(every)),
+        Some(Command::Login) => (default_download_args(), Vec::new(), Task::Login),
+        Some(Command::List { resolve, inputs }) => (
+            DownloadArgs {
+                resolve,
+                ..default_download_args()
+            },
+            inputs.input,
+            Task::List,
+        ),
+        Some(Command::Meta {
+            command: None,
+            resolve,
+            format,
+            quality,
+            inputs,
+        }) => (
+            DownloadArgs {
+                resolve,
+                quality,
+                ..default_download_args()
+            },
+            inputs.input,
+            Task::Export(format),
+        ),
+        Some(Command::Meta {
+            command: Some(MetaCommand::Retag { dir }),
+            ..
+        }) => (default_download_args(), Vec::new(), Task::Retag(dir)),
+        Some(Command::Meta {
+            command: Some(MetaCommand::Audit { dir }),
+            ..
+        }) => match rippify::audit_tags(&dir) {
+            Ok(()) => proc::exit(0),
+            Err(_) => proc::exit(1),
+        },
+        Some(Command::Meta {
+            command:
+                Some(MetaCommand::Beets {
+                    download,
+                    output,
+                    inputs,
+                }),
+            ..
+        }) => (download, inputs.input, Task::Beets(output)),
+    };
 
-#[derive(Debug)]
-struct OutputFile {
-    dir: Option<String>,
-    file: String,
-}
+    let global = cli.global;
+    let resolve = download.resolve;
 
-impl OutputFormat {
-    fn parse_output_format(&self, track: &lsm::Track) -> OutputFile {
-        let parsed = self
-            .format_string
-            .replace("{author}", &track.artists.first().unwrap().name) // NOTE: using the first found artist as the "main" artist
-            .replace("{album}", &track.album.name)
-            .replace("{name}", &track.name.as_str().replace('/', " "))
-            .replace("{ext}", "ogg");
-
-        OutputFile {
-            dir: parsed.rfind('/').map(|split_pos| parsed[..=split_pos].to_owned()),
-            file: parsed,
-        }
+    // Clap makes sure a user always comes with a password, and that only one kind of credentials is given
+    let credentials = match (global.user, global.pass, global.token) {
+        (Some(user), Some(pass), _) => Some(lsc_auth::Credentials::with_password(user, pass)),
+        (_, _, Some(token)) => Some(lsc_auth::Credentials::with_access_token(token)),
+        _ => None,
+    };
+
+    // Nothing to fall back on without the cache, unless the run doesn't log in at all
+    if credentials.is_none() && global.no_cache_credentials {
+        return Err(String::from(
+            "--no-cache-credentials requires --user and --pass or --token",
+        ));
     }
-}
 
-trait ProcessErrorKind {}
+    let proxy = match global.proxy {
+        Some(proxy) => Some(proxy),
+        None => match env::var("HTTPS_PROXY").or_else(|_| env::var("https_proxy")) {
+            Ok(proxy) if !proxy.is_empty() => Some(parse_proxy(&proxy).map_err(|err| format!("HTTPS_PROXY: {}", err))?),
+            _ => None,
+        },
+    };
 
-struct ProcessError<T: ProcessErrorKind> {
-    kind: T,
-    error: Box<dyn std::error::Error>,
-}
+    let cache_dir = global.cache_dir.unwrap_or_else(default_cache_dir);
+
+    // The state a watch keeps between checks goes to the cache unless it's given
+    let sync_state = match &task {
+        Task::Watch(_) => Some(
+            resolve
+                .sync_state
+                .clone()
+                .unwrap_or_else(|| cache_dir.join(WATCH_STATE_FILE)),
+        ),
+        _ => resolve.sync_state.clone(),
+    };
 
-enum TrackDownloadErrorKind {
-    AudioKey,
-    AudioFile,
-    TrackFile,
-    Decrypt,
-}
+    let (sync_dir, prune) = match &task {
+        Task::Sync(dir, prune) => (Some(dir.clone()), *prune),
+        _ => (None, false),
+    };
 
-impl ProcessErrorKind for TrackDownloadErrorKind {}
-type TrackDownloadError = ProcessError<TrackDownloadErrorKind>;
-
-async fn track_download(
-    track: &lsm::Track,
-    file_id: &lsc::FileId,
-    session: &lsc::Session,
-) -> Result<Vec<u8>, TrackDownloadError> {
-    let track_file_key = session
-        .audio_key()
-        .request(track.id, *file_id)
-        .await
-        .map_err(|e| ProcessError {
-            kind: TrackDownloadErrorKind::AudioKey,
-            error: e.into(),
-        })?;
-
-    let mut track_buffer = Vec::<u8>::new();
-    let mut track_buffer_decrypted = Vec::<u8>::new();
-
-    let mut track_file_audio = lsa::AudioFile::open(session, *file_id, 40)
-        .await
-        .map_err(|e| ProcessError {
-            kind: TrackDownloadErrorKind::AudioFile,
-            error: e.into(),
-        })?;
-
-    track_file_audio
-        .read_to_end(&mut track_buffer)
-        .map_err(|e| ProcessError {
-            kind: TrackDownloadErrorKind::TrackFile,
-            error: e.into(),
-        })?;
-
-    lsa::AudioDecrypt::new(Some(track_file_key), &track_buffer[..])
-        .read_to_end(&mut track_buffer_decrypted)
-        .map_err(|e| ProcessError {
-            kind: TrackDownloadErrorKind::Decrypt,
-            error: e.into(),
-        })?;
-
-    Ok(track_buffer_decrypted[0xa7..].to_vec())
-}
+    let format_string = download
+        .format
+        .or(download
+            .library_layout
+            .as_ref()
+            .map(|layout| layout.format().to_owned()))
+        .or(download
+            .album_layout
+            .then(|| String::from("{album_artist}/{album}/{track_number} - {name}.{ext}")))
+        .unwrap_or("{author}/{album}/{name}.{ext}".to_owned());
+
+    let sanitize = download.sanitize.unwrap_or_else(Sanitize::native);
+
+    if !sanitize.allows(download.sanitize_replacement) {
+        return Err(String::from(
+            "the --sanitize-replacement character isn't allowed in file names by --sanitize itself",
+        ));
+    }
 
-enum TrackWriteErrorKind {
-    FolderCreate,
-    FileCreate,
-    FileWrite,
-}
+    let format = OutputFormat {
+        format_string: match &sync_dir {
+            Some(dir) => dir.join(format_string).to_string_lossy().into_owned(),
+            None => format_string,
+        },
+        number_width: download.track_number_width.into(),
+        replace_spaces: download.replace_spaces,
+        strip_featured: download.strip_featured_artists,
+        sanitize,
+        sanitize_replacement: download.sanitize_replacement,
+        disc_folders: download.album_layout,
+    };
 
-impl ProcessErrorKind for TrackWriteErrorKind {}
-type TrackWriteError = ProcessError<TrackWriteErrorKind>;
+    let stdout = download.stdout || format.format_string == "-";
 
-fn track_write(track_buffer: Vec<u8>, output_file: OutputFile) -> Result<String, TrackWriteError> {
-    if let Some(path) = output_file.dir {
-        fs::create_dir_all(path).map_err(|e| TrackWriteError {
-            kind: TrackWriteErrorKind::FolderCreate,
-            error: e.into(),
-        })?;
+    if stdout && sync_dir.is_some() {
+        return Err(String::from("sync cannot be used when writing to stdout"));
     }
 
-    let mut file_write = fs::File::create(&output_file.file).map_err(|e| ProcessError {
-        kind: TrackWriteErrorKind::FileCreate,
-        error: e.into(),
-    })?;
+    if stdout && matches!(task, Task::Beets(_)) {
+        return Err(String::from("meta beets cannot be used when writing to stdout"));
+    }
 
-    io::copy(&mut track_buffer.as_slice(), &mut file_write).map_err(|e| ProcessError {
-        kind: TrackWriteErrorKind::FileWrite,
-        error: e.into(),
-    })?;
+    if stdout && download.album_layout {
+        return Err(String::from("--album-layout cannot be used when writing to stdout"));
+    }
 
-    Ok(output_file.file)
-}
+    if stdout && download.stage_to_disk {
+        return Err(String::from("--stage-to-disk cannot be used when writing to stdout"));
+    }
 
-fn track_add_metadata_tags(track_buffer: Vec<u8>, track: &lsm::Track) -> Result<Vec<u8>, TagsWriteError> {
-    let mut metadata = lhr::CommentHeader {
-        vendor: String::from("Ogg"),
-        comment_list: Vec::new(),
-    };
+    if stdout && matches!(task, Task::Watch(_)) {
+        return Err(String::from("rippify watch cannot write to stdout"));
+    }
 
-    metadata.comment_list.push((String::from("title"), track.name.clone()));
-    metadata
-        .comment_list
-        .push((String::from("album"), track.album.name.clone()));
-
-    metadata.comment_list.extend(
-        track
-            .artists
-            .iter()
-            .map(|artist| (String::from("artist"), artist.name.clone()))
-            .collect::<Vec<_>>(),
-    );
+    if stdout && download.codec != Codec::Ogg {
+        return Err(String::from("--codec cannot be used when writing to stdout"));
+    }
 
-    replace_header_comment(&track_buffer, &metadata)
+    Ok(UserParams {
+        credentials,
+        login: matches!(task, Task::Login),
+        cache_dir,
+        cache_credentials: !global.no_cache_credentials,
+        format,
+        keep_encrypted: download.keep_encrypted,
+        stdout,
+        retag_dir: match &task {
+            Task::Retag(dir) => Some(dir.clone()),
+            _ => None,
+        },
+        skip_duplicates_on_disk: sync_dir.clone().or(download.skip_duplicates_on_disk),
+        download_archive: download.download_archive,
+        db: download.db,
+        sync_dir,
+        prune,
+        sync_state: sync_state.clone(),
+        min_popularity: download.min_popularity,
+        prefer_canonical: download.prefer_canonical,
+        dedupe: download.dedupe,
+        alt_metadata: download.alt_metadata,
+        quality: download.quality,
+        strict_quality: download.strict_quality,
+        prefer: download.prefer,
+        codec: download.codec,
+        overwrite: if download.replace_existing_if_better {
+            Overwrite::IfLarger
+        } else {
+            download.overwrite
+        },
+        refresh_tags: download.refresh_tags,
+        verify_existing: download.verify_existing,
+        fail_fast: global.fail_fast,
+        jobs: download.jobs as usize,
+        write_jobs: download.write_jobs.unwrap_or(download.jobs) as usize,
+        summary_only: global.summary_only,
+        verbose: global.verbose,
+        quiet: global.quiet,
+        log_file: global.log_file,
+        progress: !stdout && !global.no_progress && (global.progress || io::stdout().is_terminal()),
+        list_inputs: matches!(task, Task::List),
+        export_beets: match &task {
+            Task::Beets(path) => Some(path.clone()),
+            _ => None,
+        },
+        export_metadata: match task {
+            Task::Export(format) => Some(format),
+            _ => None,
+        },
+        stream: download.stream,
+        radio: resolve.radio,
+        benchmark: download.benchmark.map(|num_tracks| num_tracks as usize),
+        connect_timeout: stdtime::Duration::from_secs(global.connect_timeout),
+        proxy,
+        connect_retries: global.connect_retries,
+        track_timeout: download.track_timeout.map(stdtime::Duration::from_secs),
+        limit_rate: download.limit_rate,
+        total_timeout: download.total_timeout.map(stdtime::Duration::from_secs),
+        retry: RetryPolicy {
+            retries: download.retries,
+            delay: stdtime::Duration::from_secs_f64(download.retry_delay),
+            cool_down: stdtime::Duration::from_secs(download.rate_limit_cooldown),
+        },
+        temp_dir: global.temp_dir.unwrap_or_else(env::temp_dir),
+        stage_to_disk: download.stage_to_disk,
+        decrypt_only: download.decrypt_only,
+        skip_space_check: download.skip_space_check,
+        resume_partial: download.resume_partial,
+        resolve: ResolveOptions {
+            disc: resolve.disc,
+            artist_albums_limit: resolve.artist_albums_limit,
+            artist_include: resolve.artist_include,
+            artist_top_tracks_only: resolve.artist_top_tracks_only,
+            added_after: resolve.added_after,
+            skip_shorter_than: resolve.skip_shorter_than,
+            sync_state: sync_state.as_deref().map(rippify::read_sync_state).transpose()?,
+            interactive: resolve.interactive,
+            search_first: resolve.first,
+            radio_count: resolve.radio_count as usize,
+        },
+        tags: TagOptions {
+            normalize: download.normalize,
+            compilation: download.compilation_tag,
+            album_artist: download.library_layout.is_some() || download.album_layout,
+            cover_size: download.cover_size,
+            audio_features: download.audio_features,
+            lyrics: download.lyrics,
+            strip_featured: download.strip_featured_artists,
+        },
+        save_metadata_json: download.save_metadata_json,
+        save_cover: download.save_cover,
+        save_cover_size: download.save_cover_size,
+        artist_index: download.artist_index,
+        m3u8_dir: download.m3u8,
+        report: download.report,
+        post_hook: download.post_hook,
+        watch_every: match task {
+            Task::Watch(every) => Some(every),
+            _ => None,
+        },
+        input: input
+            .into_iter()
+            .chain(resolve.search.into_iter().map(|query| format!("search:{}", query)))
+            .chain(resolve.liked.then(|| String::from("spotify:collection")))
+            .collect(),
+    })
 }
 
-// Reverse implementation of https://github.com/RustAudio/lewton/blob/bb2955b717094b40260902cf2f8dd9c5ea62a84a/src/header.rs#L309
-fn make_header_comment(header: &lhr::CommentHeader) -> Option<Vec<u8>> {
-    let mut packet: Vec<u8> = vec![];
+// Every key of the config file is the name of a long option, with - or _ between words. Its arguments are put in
+// front of the ones given on the command line, where a later occurrence of an option overrides an earlier one. As
+// clap only takes the options of a subcommand after its name, they go right behind the subcommand that is run
+fn with_config_args(mut args: Vec<String>) -> Result<Vec<String>, String> {
+    let given = args
+        .iter()
+        .enumerate()
+        .find_map(|(pos, arg)| match arg.strip_prefix("--config") {
+            Some("") => args.get(pos + 1).map(path::PathBuf::from),
+            Some(value) => value.strip_prefix('=').map(path::PathBuf::from),
+            None => None,
+        });
+
+    // Unlike a given config file, the default one doesn't have to exist
+    let explicit = given.is_some();
+
+    let file = match given.or_else(default_config_file) {
+        Some(file) if explicit || file.is_file() => file,
+        _ => return Ok(args),
+    };
+
+    let config: toml::Table = fs::read_to_string(&file)
+        .map_err(|err| err.to_string())
+        .and_then(|contents| {
+            contents
+                .parse::<toml::Table>()
+                .map_err(|err: toml::de::Error| err.message().to_owned())
+        })
+        .map_err(|err| format!("cannot read config file {}: {}", file.display(), err.to_lowercase()))?;
 
-    // 'V' 'O' 'R' 'B' 'I' 'S'
-    packet.extend([0x03, 0x76, 0x6F, 0x72, 0x62, 0x69, 0x73] as [u8; 7]);
+    // Building the command hands the global options down to every subcommand
+    let mut command = Cli::command();
+    command.build();
 
-    let vendor_buf = header.vendor.as_bytes();
-    let vendor_len = TryInto::<u32>::try_into(vendor_buf.len()).ok()?.to_le_bytes();
+    let (insert_pos, subcommand) = find_subcommand(&command, &args);
+    let mut config_args = Vec::new();
 
-    packet.extend(vendor_len);
-    packet.extend(vendor_buf);
+    for (key, value) in config {
+        let option = key.replace('_', "-");
 
-    let comments_len = TryInto::<u32>::try_into(header.comment_list.len()).ok()?.to_le_bytes();
+        if option == "config" || !has_option(&command, &option) {
+            return Err(format!("unknown option {} in config file {}", key, file.display()));
+        }
 
-    packet.extend(comments_len);
+        // Options of the other subcommands, like download options when logging in, don't apply to this run. Options
+        // are matched by their id too, as the --format of rippify meta isn't the output format of the others
+        if !subcommand
+            .get_arguments()
+            .any(|arg| arg.get_long() == Some(option.as_str()) && arg.get_id() == option.replace('-', "_").as_str())
+        {
+            continue;
+        }
 
-    for comment in &header.comment_list {
-        let comment_buf = format!("{}={}", comment.0, comment.1);
-        let comment_buf = comment_buf.as_bytes();
-        let comment_len = TryInto::<u32>::try_into(comment_buf.len()).ok()?.to_le_bytes();
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
 
-        packet.extend(comment_len);
-        packet.extend(comment_buf);
+        for value in values {
+            match value {
+                toml::Value::Boolean(true) => config_args.push(format!("--{}", option)),
+                toml::Value::Boolean(false) => {}
+                toml::Value::String(value) => config_args.push(format!("--{}={}", option, value)),
+                toml::Value::Integer(value) => config_args.push(format!("--{}={}", option, value)),
+                toml::Value::Float(value) => config_args.push(format!("--{}={}", option, value)),
+                _ => {
+                    return Err(format!(
+                        "unsupported value for {} in config file {}",
+                        key,
+                        file.display()
+                    ))
+                }
+            }
+        }
     }
 
-    packet.extend([0x01] as [u8; 1]);
-    Some(packet)
-}
+    args.splice(insert_pos..insert_pos, config_args);
 
-enum TagsWriteErrorKind {
-    Read,
-    Write,
-    Header,
+    Ok(args)
 }
 
-impl ProcessErrorKind for TagsWriteErrorKind {}
-type TagsWriteError = ProcessError<TagsWriteErrorKind>;
-
-// Based on https://github.com/RustAudio/ogg/blob/0910d8d57645eccc1a1400731fefef376859c661/examples/repack.rs#L52
-fn replace_header_comment(
-    ogg_buffer: &Vec<u8>,
-    comment_header: &lhr::CommentHeader,
-) -> Result<Vec<u8>, TagsWriteError> {
-    let mut out_buffer = io::Cursor::new(Vec::<u8>::new());
-    let mut in_buffer = io::Cursor::new(ogg_buffer);
-
-    let mut reader = ogg::PacketReader::new(&mut in_buffer);
-    let mut writer = ogg::PacketWriter::new(&mut out_buffer);
-
-    let mut overwrote_header = false;
-
-    loop {
-        if let Some(mut packet) = reader.read_packet().map_err(|e| TagsWriteError {
-            kind: TagsWriteErrorKind::Read,
-            error: e.into(),
-        })? {
-            if !overwrote_header {
-                if let Ok(_) = lhr::read_header_comment(&packet.data) {
-                    packet.data = make_header_comment(comment_header).ok_or(TagsWriteError {
-                        kind: TagsWriteErrorKind::Header,
-                        error: "invalid header comment data".into(),
-                    })?;
-                    overwrote_header = true;
-                }
-            }
+// The innermost subcommand given, with the position right after its name. Positional arguments end the search, as
+// there are no subcommands after the inputs
+fn find_subcommand<'a>(command: &'a clap::Command, args: &[String]) -> (usize, &'a clap::Command) {
+    let mut found = (1.min(args.len()), command);
+    let mut pos = 1;
 
-            let packet_inf = if packet.last_in_stream() {
-                ogg::PacketWriteEndInfo::EndStream
-            } else if packet.last_in_page() {
-                ogg::PacketWriteEndInfo::EndPage
-            } else {
-                ogg::PacketWriteEndInfo::NormalPacket
-            };
-
-            let packet_serial = packet.stream_serial();
-            let packet_absgp = packet.absgp_page();
-
-            writer
-                .write_packet(packet.data, packet_serial, packet_inf, packet_absgp)
-                .map_err(|e| TagsWriteError {
-                    kind: TagsWriteErrorKind::Write,
-                    error: e.into(),
-                })?;
-        } else {
+    while let Some(arg) = args.get(pos) {
+        let (_, scope) = found;
+
+        if let Some(subcommand) = scope.find_subcommand(arg) {
+            found = (pos + 1, subcommand);
+        } else if arg == "--" || !arg.starts_with('-') {
             break;
+        } else if takes_value(scope, arg) {
+            pos += 1;
         }
+
+        pos += 1;
+    }
+
+    found
+}
+
+// Whether the value of an option is the next argument, which is the case unless it's attached as in --jobs=4 or -j4
+fn takes_value(command: &clap::Command, arg: &str) -> bool {
+    let takes_values = |option: &clap::Arg| option.get_action().takes_values();
+
+    match (arg.strip_prefix("--"), arg.strip_prefix('-')) {
+        (Some(long), _) => command
+            .get_arguments()
+            .any(|option| option.get_long() == Some(long) && takes_values(option)),
+        (None, Some(short)) if short.chars().count() == 1 => command
+            .get_arguments()
+            .any(|option| option.get_short() == short.chars().next() && takes_values(option)),
+        _ => false,
     }
+}
+
+fn has_option(command: &clap::Command, option: &str) -> bool {
+    command.get_arguments().any(|arg| arg.get_long() == Some(option))
+        || command
+            .get_subcommands()
+            .any(|subcommand| has_option(subcommand, option))
+}
 
-    Ok(out_buffer.into_inner())
+fn default_config_file() -> Option<path::PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(path::PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| path::Path::new(&home).join(".config")))
+        .map(|dir| dir.join("rippify").join("config.toml"))
+}
+
+// Follows the XDG base directory spec, falling back to ~/.cache like most tools do when it isn't set
+fn default_cache_dir() -> path::PathBuf {
+    env::var_os("XDG_CACHE_HOME")
+        .map(path::PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| path::Path::new(&home).join(".cache")))
+        .unwrap_or_else(env::temp_dir)
+        .join("rippify")
+}
+
+fn print_version() {
+    println!("rippify version {} (commit {})", VERSION, GIT_COMMIT);
+    println!("built against librespot {}\n", LIBRESPOT_VERSION);
+    println!(
+        "Copyright (C) 2023 Antonio de Haro. \n\
+        This program is distributed under the MIT license, see the attatched LICENSE.txt file for terms and conditions. \n\
+        This software is provided without any warranty of any kind. \n\
+        Copyright atributions for any third party code included are provided in the attatched COPYRIGHT.md file."
+    );
 }